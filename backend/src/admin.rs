@@ -0,0 +1,336 @@
+use crate::rate_limit::{RateLimit, RateLimitKey, RateLimiter, RateLimits};
+use crate::types::LogError;
+use anyhow::Context;
+use concordium_rust_sdk::smart_contracts::common::AccountAddress;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The key under which the pause is stored.
+const PAUSED_KEY: &[u8] = b"paused";
+/// The tag of the keys of blocked rate limit keys.
+const BLOCKED_TAG: u8 = b'b';
+/// The tag of the keys of the limits replacing the limit of their kind.
+const LIMIT_TAG: u8 = b'l';
+
+fn tagged(tag: u8, key: RateLimitKey) -> Vec<u8> {
+    let mut out = vec![tag];
+    out.extend(key.to_bytes());
+    out
+}
+
+/// Encode a limit as the big-endian maximum number of requests followed by the
+/// big-endian window in seconds.
+fn encode_limit(limit: RateLimit) -> Vec<u8> {
+    let mut out = limit.max_requests.to_be_bytes().to_vec();
+    out.extend(limit.window.as_secs().to_be_bytes());
+    out
+}
+
+fn decode_limit(bytes: &[u8]) -> Option<RateLimit> {
+    Some(RateLimit {
+        max_requests: u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?),
+        window: Duration::from_secs(u64::from_be_bytes(bytes.get(4..)?.try_into().ok()?)),
+    })
+}
+
+fn store_error(e: sled::Error) -> LogError {
+    log::error!("AdminStoreError {:#}.", e);
+    LogError::AdminStoreError
+}
+
+/// The settings changed at runtime through the admin API: the pause of
+/// sponsoring, the blocked accounts and coin keys, and the limits of
+/// individual accounts. They are persisted in the database, so they survive
+/// restarts.
+pub struct Admin {
+    tree: sled::Tree,
+    paused: AtomicBool,
+    blocked: Mutex<BTreeSet<RateLimitKey>>,
+}
+
+impl Admin {
+    /// Load the settings from the tree, and apply the stored limits to the
+    /// rate limiter.
+    pub fn load(tree: sled::Tree, rate_limiter: &RateLimiter) -> anyhow::Result<Self> {
+        let paused = tree.contains_key(PAUSED_KEY)?;
+        if paused {
+            log::warn!("Sponsoring is paused. It is resumed with `POST /admin/resume`.");
+        }
+        let mut blocked = BTreeSet::new();
+        for entry in tree.scan_prefix([BLOCKED_TAG]) {
+            let (key, _) = entry?;
+            blocked.insert(RateLimitKey::from_bytes(&key[1..]).context("Invalid blocked key.")?);
+        }
+        for entry in tree.scan_prefix([LIMIT_TAG]) {
+            let (key, value) = entry?;
+            let key = RateLimitKey::from_bytes(&key[1..]).context("Invalid rate limit key.")?;
+            let limit = decode_limit(&value).context("Invalid rate limit.")?;
+            rate_limiter.set_limit(key, Some(limit));
+        }
+        Ok(Self {
+            tree,
+            paused: AtomicBool::new(paused),
+            blocked: Mutex::new(blocked),
+        })
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn set_paused(&self, paused: bool) -> Result<(), LogError> {
+        if paused {
+            self.tree.insert(PAUSED_KEY, &[1]).map_err(store_error)?;
+        } else {
+            self.tree.remove(PAUSED_KEY).map_err(store_error)?;
+        }
+        self.tree.flush().map_err(store_error)?;
+        self.paused.store(paused, Ordering::Relaxed);
+        log::warn!(
+            "Sponsoring is {}.",
+            if paused { "paused" } else { "resumed" }
+        );
+        Ok(())
+    }
+
+    /// Check that sponsoring is not paused and none of the keys of a request
+    /// is blocked.
+    pub fn check(&self, keys: &[RateLimitKey]) -> Result<(), LogError> {
+        if self.is_paused() {
+            return Err(LogError::SponsoringPaused);
+        }
+        let blocked = self.blocked.lock().unwrap();
+        match keys.iter().find(|key| blocked.contains(key)) {
+            Some(key) => {
+                log::warn!("Rejected request of the blocked {:?}.", key);
+                Err(LogError::Blocked(*key))
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn set_blocked(&self, key: RateLimitKey, blocked: bool) -> Result<(), LogError> {
+        let stored = tagged(BLOCKED_TAG, key);
+        if blocked {
+            self.tree.insert(stored, &[]).map_err(store_error)?;
+        } else {
+            self.tree.remove(stored).map_err(store_error)?;
+        }
+        self.tree.flush().map_err(store_error)?;
+        let mut set = self.blocked.lock().unwrap();
+        if blocked {
+            set.insert(key);
+        } else {
+            set.remove(&key);
+        }
+        Ok(())
+    }
+
+    /// Replace the limit of the key, or restore the limit of its kind.
+    pub fn set_limit(
+        &self,
+        rate_limiter: &RateLimiter,
+        key: RateLimitKey,
+        limit: Option<RateLimit>,
+    ) -> Result<(), LogError> {
+        let stored = tagged(LIMIT_TAG, key);
+        match limit {
+            Some(limit) => self.tree.insert(stored, encode_limit(limit)),
+            None => self.tree.remove(stored),
+        }
+        .map_err(store_error)?;
+        self.tree.flush().map_err(store_error)?;
+        rate_limiter.set_limit(key, limit);
+        Ok(())
+    }
+
+    /// The settings, as returned by `GET /admin/state`.
+    pub fn report(&self, rate_limiter: &RateLimiter) -> AdminReport {
+        let blocked = self.blocked.lock().unwrap();
+        let account_rate_limits = self
+            .tree
+            .scan_prefix([LIMIT_TAG])
+            .keys()
+            .filter_map(|key| match RateLimitKey::from_bytes(&key.ok()?[1..])? {
+                RateLimitKey::Account(account) => Some(account),
+                _ => None,
+            })
+            .map(|account| {
+                let (limit, _) = rate_limiter.limit(&RateLimitKey::Account(account));
+                AccountRateLimit {
+                    account,
+                    limit: limit.into(),
+                }
+            })
+            .collect();
+        AdminReport {
+            paused: self.is_paused(),
+            blocked_accounts: blocked
+                .iter()
+                .filter_map(|key| match key {
+                    RateLimitKey::Account(account) => Some(*account),
+                    _ => None,
+                })
+                .collect(),
+            blocked_coin_keys: blocked
+                .iter()
+                .filter_map(|key| match key {
+                    RateLimitKey::CoinKey(public_key) => Some(public_key.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            rate_limits: rate_limiter.limits().into(),
+            account_rate_limits,
+        }
+    }
+}
+
+/// A rate limit, as given to and returned by the admin API.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitView {
+    /// The maximum number of requests within the window. 0 disables the limit.
+    pub max_requests: u32,
+    /// The length of the sliding window, in seconds.
+    pub window: u64,
+}
+
+impl From<RateLimit> for RateLimitView {
+    fn from(limit: RateLimit) -> Self {
+        Self {
+            max_requests: limit.max_requests,
+            window: limit.window.as_secs(),
+        }
+    }
+}
+
+impl From<RateLimitView> for RateLimit {
+    fn from(view: RateLimitView) -> Self {
+        Self {
+            max_requests: view.max_requests,
+            window: Duration::from_secs(view.window),
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitsView {
+    pub account: RateLimitView,
+    pub coin_key: RateLimitView,
+    pub ip: RateLimitView,
+}
+
+impl From<RateLimits> for RateLimitsView {
+    fn from(limits: RateLimits) -> Self {
+        Self {
+            account: limits.account.into(),
+            coin_key: limits.coin_key.into(),
+            ip: limits.ip.into(),
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountRateLimit {
+    pub account: AccountAddress,
+    #[serde(flatten)]
+    pub limit: RateLimitView,
+}
+
+/// The response of `GET /admin/state`.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminReport {
+    pub paused: bool,
+    pub blocked_accounts: Vec<AccountAddress>,
+    /// The blocked coin keys, hex encoded.
+    pub blocked_coin_keys: Vec<String>,
+    /// The limits of the kinds of keys.
+    pub rate_limits: RateLimitsView,
+    /// The accounts whose limit replaces the account limit.
+    pub account_rate_limits: Vec<AccountRateLimit>,
+}
+
+/// The response of `GET /admin/rate-limits/{account}`.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountRateLimitReport {
+    pub account: AccountAddress,
+    #[serde(flatten)]
+    pub limit: RateLimitView,
+    /// Whether the limit replaces the account limit.
+    pub custom: bool,
+    /// The number of requests within the current window.
+    pub used: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limit::MemoryRateLimitStore;
+    use concordium_rust_sdk::smart_contracts::common::PublicKeyEd25519;
+
+    fn limiter() -> RateLimiter {
+        let limit = RateLimit {
+            max_requests: 10,
+            window: Duration::from_secs(60),
+        };
+        RateLimiter::new(
+            Box::<MemoryRateLimitStore>::default(),
+            RateLimits {
+                account: limit,
+                coin_key: limit,
+                ip: limit,
+            },
+        )
+    }
+
+    #[test]
+    fn test_settings_survive_restart() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let account = RateLimitKey::Account(AccountAddress([1; 32]));
+        let coin_key = RateLimitKey::CoinKey(PublicKeyEd25519([2; 32]));
+        let limit = RateLimit {
+            max_requests: 1,
+            window: Duration::from_secs(3600),
+        };
+
+        let rate_limiter = limiter();
+        let admin = Admin::load(db.open_tree("admin").unwrap(), &rate_limiter).unwrap();
+        assert!(admin.check(&[account, coin_key]).is_ok());
+        admin.set_blocked(coin_key, true).unwrap();
+        admin
+            .set_limit(&rate_limiter, account, Some(limit))
+            .unwrap();
+        admin.set_paused(true).unwrap();
+        assert!(matches!(
+            admin.check(&[account]),
+            Err(LogError::SponsoringPaused)
+        ));
+
+        let rate_limiter = limiter();
+        let admin = Admin::load(db.open_tree("admin").unwrap(), &rate_limiter).unwrap();
+        assert!(admin.is_paused());
+        assert_eq!(rate_limiter.limit(&account), (limit, true));
+        admin.set_paused(false).unwrap();
+        assert!(admin.check(&[account]).is_ok());
+        assert!(matches!(
+            admin.check(&[account, coin_key]),
+            Err(LogError::Blocked(RateLimitKey::CoinKey(_)))
+        ));
+
+        admin.set_blocked(coin_key, false).unwrap();
+        admin.set_limit(&rate_limiter, account, None).unwrap();
+        let rate_limiter = limiter();
+        let admin = Admin::load(db.open_tree("admin").unwrap(), &rate_limiter).unwrap();
+        assert!(admin.check(&[account, coin_key]).is_ok());
+        assert_eq!(
+            rate_limiter.limit(&account),
+            (limiter().limits().account, false)
+        );
+    }
+}
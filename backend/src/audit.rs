@@ -0,0 +1,470 @@
+use crate::tx_status::{TxStatus, TxStatusUpdate};
+use crate::types::reject_reason_name;
+use anyhow::{ensure, Context};
+use concordium_rust_sdk::smart_contracts::common::{AccountAddress, Amount};
+use concordium_rust_sdk::types::hashes::TransactionHash;
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc as std_mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// The `prevHash` of the first record of the audit log.
+const GENESIS_HASH: [u8; 32] = [0; 32];
+
+/// The columns of the CSV export, which are the fields of [`AuditRecord`].
+const CSV_COLUMNS: &[&str] = &[
+    "seq",
+    "time",
+    "event",
+    "requestTime",
+    "jobId",
+    "contract",
+    "signer",
+    "coinKey",
+    "entryPoint",
+    "simulation",
+    "usedEnergy",
+    "energy",
+    "sponsor",
+    "txHash",
+    "fee",
+    "outcome",
+    "reason",
+    "prevHash",
+    "hash",
+];
+
+/// What happened to a sponsored transaction.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum AuditEvent {
+    /// The simulation succeeded and the transaction was queued to be sent.
+    Accepted,
+    /// The contract rejected the simulation, so no transaction was sent.
+    Rejected,
+    /// The transaction was sent from a sponsor account.
+    Sent,
+    /// The transaction is in a finalized block and its fee was paid.
+    Finalized,
+    /// The job was given up before its transaction reached the node.
+    JobFailed,
+}
+
+/// A record of the audit log, written as a line of JSON. Each record contains
+/// the hash of the previous one, so records cannot be changed, removed or
+/// reordered without breaking the chain.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    /// The position of the record in the log, starting at 0.
+    pub seq: u64,
+    /// When the record was written, in RFC 3339 format.
+    pub time: String,
+    pub event: AuditEvent,
+    /// When the submission was received.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contract: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signer: Option<AccountAddress>,
+    /// The public key of the redeemed coin, hex encoded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coin_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entry_point: Option<String>,
+    /// Either `success` or `rejected`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub simulation: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub used_energy: Option<u64>,
+    /// The energy given to the transaction, without its header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub energy: Option<u64>,
+    /// The sponsor account the transaction was sent from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sponsor: Option<AccountAddress>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx_hash: Option<TransactionHash>,
+    /// The fee paid by the sponsor account, in micro CCD.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee: Option<Amount>,
+    /// Either `success` or `rejected` for finalized transactions, and
+    /// `failed` for failed jobs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<String>,
+    /// Why the simulation or transaction was rejected, or the job failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub prev_hash: String,
+    /// The hex encoded SHA-256 hash of the JSON of the record without this
+    /// field.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+}
+
+impl AuditRecord {
+    /// A record of the event without details. The position, time and hashes
+    /// are set when it is written.
+    pub fn new(event: AuditEvent) -> Self {
+        Self {
+            seq: 0,
+            time: String::new(),
+            event,
+            request_time: None,
+            job_id: None,
+            contract: None,
+            signer: None,
+            coin_key: None,
+            entry_point: None,
+            simulation: None,
+            used_energy: None,
+            energy: None,
+            sponsor: None,
+            tx_hash: None,
+            fee: None,
+            outcome: None,
+            reason: None,
+            prev_hash: String::new(),
+            hash: String::new(),
+        }
+    }
+
+    fn compute_hash(&self) -> String {
+        let unhashed = AuditRecord {
+            hash: String::new(),
+            ..self.clone()
+        };
+        let json = serde_json::to_vec(&unhashed).expect("Audit records serialize to JSON.");
+        hex::encode(Sha256::digest(json))
+    }
+}
+
+struct AuditWriter {
+    file: File,
+    next_seq: u64,
+    last_hash: String,
+}
+
+impl AuditWriter {
+    fn append(&mut self, mut record: AuditRecord) -> anyhow::Result<()> {
+        record.seq = self.next_seq;
+        record.time = chrono::Utc::now().to_rfc3339();
+        record.prev_hash = self.last_hash.clone();
+        record.hash = record.compute_hash();
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        self.next_seq += 1;
+        self.last_hash = record.hash;
+        Ok(())
+    }
+}
+
+/// The thread appending the records sent to it to the log.
+struct WriterThread {
+    records: std_mpsc::Sender<AuditRecord>,
+    thread: JoinHandle<()>,
+}
+
+/// An append-only log of the sponsored transactions, as JSON lines forming a
+/// hash chain. The log is continued across restarts.
+///
+/// The records are written and synced to disk by a dedicated thread, so
+/// recording does not block the request handlers.
+pub struct AuditLog {
+    writer: Mutex<Option<WriterThread>>,
+}
+
+impl AuditLog {
+    /// A log that records nothing.
+    pub fn disabled() -> Self {
+        Self {
+            writer: Mutex::new(None),
+        }
+    }
+
+    /// Open the log at the path, continuing the chain of its last record.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let (next_seq, last_hash) = match File::open(path) {
+            Ok(file) => {
+                let mut last = None;
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if !line.trim().is_empty() {
+                        last = Some(line);
+                    }
+                }
+                match last {
+                    Some(line) => {
+                        let record: AuditRecord = serde_json::from_str(&line).context(
+                            "The last record of the audit log is invalid. Check the log with \
+                             `audit verify`.",
+                        )?;
+                        (record.seq + 1, record.hash)
+                    }
+                    None => (0, hex::encode(GENESIS_HASH)),
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (0, hex::encode(GENESIS_HASH)),
+            Err(e) => return Err(e.into()),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut writer = AuditWriter {
+            file,
+            next_seq,
+            last_hash,
+        };
+        let (records, received) = std_mpsc::channel::<AuditRecord>();
+        let thread = std::thread::Builder::new()
+            .name("audit-log".into())
+            .spawn(move || {
+                for record in received {
+                    if let Err(e) = writer.append(record) {
+                        log::error!("Could not write to the audit log: {:#}", e);
+                    }
+                }
+            })?;
+        Ok(Self {
+            writer: Mutex::new(Some(WriterThread { records, thread })),
+        })
+    }
+
+    /// Queue the record to be appended. Failures are logged, since the
+    /// transaction the record is about cannot be undone.
+    pub fn record(&self, record: AuditRecord) {
+        let writer = self.writer.lock().unwrap();
+        let Some(writer) = &*writer else {
+            return;
+        };
+        if writer.records.send(record).is_err() {
+            log::error!("Could not write to the audit log: the writer thread stopped.");
+        }
+    }
+
+    /// Write the queued records and stop the writer thread. Records made
+    /// afterwards are not written.
+    pub fn close(&self) {
+        let Some(WriterThread { records, thread }) = self.writer.lock().unwrap().take() else {
+            return;
+        };
+        drop(records);
+        if thread.join().is_err() {
+            log::error!("The audit log writer thread panicked.");
+        }
+    }
+
+    /// Record the outcomes of the sponsored transactions, as received from
+    /// [`TxTracker::outcomes`](crate::tx_status::TxTracker::outcomes).
+    pub async fn record_outcomes(
+        self: Arc<Self>,
+        mut outcomes: mpsc::UnboundedReceiver<TxStatusUpdate>,
+    ) {
+        while let Some(update) = outcomes.recv().await {
+            let (outcome, reason) = match &update.status {
+                TxStatus::Finalized { .. } => ("success", None),
+                TxStatus::Failed {
+                    reject_reason,
+                    code,
+                    ..
+                } => (
+                    "rejected",
+                    Some(reject_reason_name(reject_reason, code.as_deref())),
+                ),
+                TxStatus::Expired => ("expired", None),
+                TxStatus::Pending | TxStatus::Committed { .. } => continue,
+            };
+            self.record(AuditRecord {
+                tx_hash: Some(update.tx_hash),
+                fee: update.status.cost(),
+                outcome: Some(outcome.into()),
+                reason,
+                ..AuditRecord::new(AuditEvent::Finalized)
+            });
+        }
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Verify the hash chain of the log, calling `on_record` with each record.
+/// Returns the number of records and the hash of the last one.
+pub fn verify(
+    reader: impl BufRead,
+    mut on_record: impl FnMut(&AuditRecord) -> anyhow::Result<()>,
+) -> anyhow::Result<(u64, String)> {
+    let mut count = 0;
+    let mut last_hash = hex::encode(GENESIS_HASH);
+    for (index, line) in reader.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: AuditRecord = serde_json::from_str(&line)
+            .with_context(|| format!("Line {}: the record is invalid.", line_number))?;
+        ensure!(
+            record.seq == count,
+            "Line {}: expected record {}, but found record {}.",
+            line_number,
+            count,
+            record.seq
+        );
+        ensure!(
+            record.prev_hash == last_hash,
+            "Line {}: the record does not follow the previous record.",
+            line_number
+        );
+        ensure!(
+            record.compute_hash() == record.hash,
+            "Line {}: the hash does not match the record.",
+            line_number
+        );
+        on_record(&record)?;
+        count += 1;
+        last_hash = record.hash;
+    }
+    Ok((count, last_hash))
+}
+
+/// Quote a CSV field if necessary.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.into()
+    }
+}
+
+/// The record as a line of CSV with the [`CSV_COLUMNS`].
+fn csv_row(record: &AuditRecord) -> anyhow::Result<String> {
+    let json = serde_json::to_value(record)?;
+    let fields: Vec<String> = CSV_COLUMNS
+        .iter()
+        .map(|column| match json.get(column) {
+            None | Some(serde_json::Value::Null) => String::new(),
+            Some(serde_json::Value::String(s)) => csv_field(s),
+            Some(value) => csv_field(&value.to_string()),
+        })
+        .collect();
+    Ok(fields.join(","))
+}
+
+/// Commands working on the audit log, e.g.,
+/// `sponsored-transaction-backend audit verify audit.jsonl`.
+#[derive(clap::Subcommand, Debug)]
+pub enum AuditCommand {
+    /// Verify the hash chain of the audit log.
+    Verify {
+        #[clap(help = "Path to the audit log.")]
+        path: PathBuf,
+    },
+    /// Verify the audit log and export it as CSV.
+    Export {
+        #[clap(help = "Path to the audit log.")]
+        path: PathBuf,
+        #[clap(
+            long = "output",
+            help = "Path to the CSV file to write. If not given, the CSV is written to stdout."
+        )]
+        output: Option<PathBuf>,
+    },
+}
+
+fn open_log(path: &Path) -> anyhow::Result<BufReader<File>> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open the audit log {}.", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+/// Run the command, printing the result.
+pub fn run(command: AuditCommand) -> anyhow::Result<()> {
+    match command {
+        AuditCommand::Verify { path } => {
+            let (count, last_hash) = verify(open_log(&path)?, |_| Ok(()))?;
+            println!(
+                "The audit log {} is intact: {} records, last hash {}.",
+                path.display(),
+                count,
+                last_hash
+            );
+        }
+        AuditCommand::Export { path, output } => {
+            let mut out: Box<dyn Write> = match &output {
+                Some(output) => Box::new(File::create(output).with_context(|| {
+                    format!("Could not create the CSV file {}.", output.display())
+                })?),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            writeln!(out, "{}", CSV_COLUMNS.join(","))?;
+            let (count, _) = verify(open_log(&path)?, |record| {
+                writeln!(out, "{}", csv_row(record)?)?;
+                Ok(())
+            })?;
+            out.flush()?;
+            if output.is_some() {
+                println!("Exported {} records.", count);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_chain() {
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", Uuid::new_v4()));
+        let log = AuditLog::open(&path).unwrap();
+        log.record(AuditRecord {
+            signer: Some(AccountAddress([1; 32])),
+            entry_point: Some("redeem".into()),
+            simulation: Some("success".into()),
+            used_energy: Some(1000),
+            ..AuditRecord::new(AuditEvent::Accepted)
+        });
+        drop(log);
+        // The chain is continued after a restart.
+        let log = AuditLog::open(&path).unwrap();
+        log.record(AuditRecord {
+            fee: Some(Amount::from_micro_ccd(2500)),
+            outcome: Some("rejected".into()),
+            reason: Some("CoinAlreadyRedeemed, \"twice\"".into()),
+            ..AuditRecord::new(AuditEvent::Finalized)
+        });
+        // The queued records are written when the log is closed.
+        log.close();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut rows = Vec::new();
+        let (count, _) = verify(contents.as_bytes(), |record| {
+            rows.push(csv_row(record)?);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(count, 2);
+        assert!(rows[1].contains(",2500,rejected,\"CoinAlreadyRedeemed, \"\"twice\"\"\","));
+
+        // Changing, removing or reordering records breaks the chain.
+        let lines: Vec<&str> = contents.lines().collect();
+        let changed = contents.replace("\"usedEnergy\":1000", "\"usedEnergy\":10");
+        assert!(verify(changed.as_bytes(), |_| Ok(())).is_err());
+        assert!(verify(lines[1].as_bytes(), |_| Ok(())).is_err());
+        let reordered = format!("{}\n{}\n", lines[1], lines[0]);
+        assert!(verify(reordered.as_bytes(), |_| Ok(())).is_err());
+    }
+}
@@ -0,0 +1,624 @@
+use crate::types::LogError;
+use anyhow::Context;
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use warp::http::HeaderMap;
+use warp::{Filter, Rejection};
+
+/// The header carrying the API key of a partner frontend.
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+/// The header carrying the Unix timestamp in seconds an HMAC signed request
+/// was signed at.
+pub const TIMESTAMP_HEADER: &str = "X-Timestamp";
+/// The header carrying the hex encoded HMAC-SHA256 signature of a request.
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+/// The header carrying the CAPTCHA response or proof-of-work token.
+pub const CHALLENGE_HEADER: &str = "X-Challenge-Token";
+/// The de facto standard header carrying the addresses a request was
+/// forwarded for, the client first.
+pub const FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+/// The standard header carrying the addresses a request was forwarded for in
+/// its `for` parameters, see RFC 7239.
+pub const FORWARDED_HEADER: &str = "Forwarded";
+
+/// How long to wait for the CAPTCHA provider to respond.
+const CAPTCHA_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The challenge a client has to solve before a sponsored transaction is
+/// submitted.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChallengeKind {
+    /// No challenge.
+    None,
+    /// A CAPTCHA, verified with the provider's `siteverify` endpoint.
+    Captcha,
+    /// A proof of work over the request body.
+    ProofOfWork,
+}
+
+/// Verifies the challenge token sent with a request in the
+/// [`CHALLENGE_HEADER`].
+pub trait ChallengeVerifier: Send + Sync {
+    /// Verify the token for the request with the given body, returning why
+    /// the verification failed otherwise.
+    fn verify<'a>(
+        &'a self,
+        token: &'a str,
+        body: &'a [u8],
+        ip: Option<IpAddr>,
+    ) -> BoxFuture<'a, Result<(), String>>;
+}
+
+/// Verifies CAPTCHA responses with a `siteverify` endpoint, as provided by
+/// hCaptcha, reCAPTCHA and Cloudflare Turnstile.
+pub struct CaptchaVerifier {
+    http: reqwest::Client,
+    verify_url: reqwest::Url,
+    secret: String,
+}
+
+impl CaptchaVerifier {
+    pub fn new(verify_url: reqwest::Url, secret: String) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            verify_url,
+            secret,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+    #[serde(rename = "error-codes", default)]
+    error_codes: Vec<String>,
+}
+
+impl ChallengeVerifier for CaptchaVerifier {
+    fn verify<'a>(
+        &'a self,
+        token: &'a str,
+        _body: &'a [u8],
+        ip: Option<IpAddr>,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let mut form = vec![("secret", self.secret.clone()), ("response", token.into())];
+            if let Some(ip) = ip {
+                form.push(("remoteip", ip.to_string()));
+            }
+            let response: SiteVerifyResponse = self
+                .http
+                .post(self.verify_url.clone())
+                .form(&form)
+                .timeout(CAPTCHA_TIMEOUT)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| {
+                    log::error!("Could not verify the CAPTCHA: {}", e);
+                    "The CAPTCHA could not be verified.".to_string()
+                })?
+                .json()
+                .await
+                .map_err(|e| {
+                    log::error!("Could not parse the CAPTCHA verification: {}", e);
+                    "The CAPTCHA could not be verified.".to_string()
+                })?;
+            if response.success {
+                Ok(())
+            } else {
+                Err(format!(
+                    "The CAPTCHA was not solved: {}.",
+                    response.error_codes.join(", ")
+                ))
+            }
+        })
+    }
+}
+
+/// Verifies that the SHA-256 hash of the request body followed by the token
+/// starts with at least `difficulty` zero bits. The work is bound to the body,
+/// which contains the signature of the sponsored transaction, so a token
+/// cannot be reused for other requests.
+pub struct ProofOfWork {
+    pub difficulty: u32,
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+impl ChallengeVerifier for ProofOfWork {
+    fn verify<'a>(
+        &'a self,
+        token: &'a str,
+        body: &'a [u8],
+        _ip: Option<IpAddr>,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        let hash = Sha256::new()
+            .chain_update(body)
+            .chain_update(token.as_bytes())
+            .finalize();
+        let result = if leading_zero_bits(&hash) >= self.difficulty {
+            Ok(())
+        } else {
+            Err(format!(
+                "The proof of work must have at least {} leading zero bits.",
+                self.difficulty
+            ))
+        };
+        Box::pin(async move { result })
+    }
+}
+
+/// Check that the origin is of the form `scheme://host[:port]` with an HTTP(S)
+/// scheme, since the CORS filter panics on malformed origins. Returns the
+/// origin as serialized by browsers in the `Origin` header.
+pub fn parse_origin(origin: &str) -> anyhow::Result<String> {
+    let url =
+        reqwest::Url::parse(origin).with_context(|| format!("Invalid origin {:?}.", origin))?;
+    anyhow::ensure!(
+        matches!(url.scheme(), "http" | "https")
+            && url.has_host()
+            && url.username().is_empty()
+            && url.password().is_none()
+            && url.path() == "/"
+            && url.query().is_none()
+            && url.fragment().is_none(),
+        "Invalid origin {:?}, expected scheme://host[:port].",
+        origin
+    );
+    Ok(url.origin().ascii_serialization())
+}
+
+/// Compare two byte strings in time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Authenticates and filters the requests to the submission endpoints.
+///
+/// If API keys or HMAC secrets are configured, a request must either carry one
+/// of the API keys in the [`API_KEY_HEADER`], or be signed with one of the
+/// secrets: the [`SIGNATURE_HEADER`] is the hex encoded HMAC-SHA256 of
+/// `{timestamp}.{body}`, where the timestamp in the [`TIMESTAMP_HEADER`] is at
+/// most `max_skew` away from the current time. If a challenge verifier is
+/// configured, the request must also carry a valid token in the
+/// [`CHALLENGE_HEADER`].
+///
+/// A signed request is accepted only once: its signature is remembered until
+/// its timestamp is too old to be accepted anyway, so captured requests cannot
+/// be replayed.
+///
+/// The IP address of the client is the remote address of the connection,
+/// unless it is one of the trusted proxies, see [`client_ip`].
+pub struct RequestGuard {
+    api_keys: Vec<String>,
+    hmac_secrets: Vec<String>,
+    max_skew: Duration,
+    challenge: Option<Box<dyn ChallengeVerifier>>,
+    trusted_proxies: Vec<IpAddr>,
+    seen: Mutex<SeenSignatures>,
+}
+
+/// The signatures of the accepted signed requests, with their timestamps.
+#[derive(Default)]
+struct SeenSignatures {
+    signatures: HashMap<Vec<u8>, i64>,
+    /// When the signatures whose timestamp left the skew are dropped next.
+    next_prune: i64,
+}
+
+impl RequestGuard {
+    pub fn new(
+        api_keys: Vec<String>,
+        hmac_secrets: Vec<String>,
+        max_skew: Duration,
+        challenge: Option<Box<dyn ChallengeVerifier>>,
+        trusted_proxies: Vec<IpAddr>,
+    ) -> Self {
+        Self {
+            api_keys,
+            hmac_secrets,
+            max_skew,
+            challenge,
+            trusted_proxies,
+            seen: Mutex::default(),
+        }
+    }
+
+    fn requires_authentication(&self) -> bool {
+        !self.api_keys.is_empty() || !self.hmac_secrets.is_empty()
+    }
+
+    fn check_api_key(&self, headers: &HeaderMap) -> bool {
+        let Some(key) = headers.get(API_KEY_HEADER) else {
+            return false;
+        };
+        self.api_keys
+            .iter()
+            .any(|expected| constant_time_eq(expected.as_bytes(), key.as_bytes()))
+    }
+
+    fn check_signature(&self, headers: &HeaderMap, body: &[u8], now: i64) -> bool {
+        let (Some(timestamp), Some(signature)) =
+            (headers.get(TIMESTAMP_HEADER), headers.get(SIGNATURE_HEADER))
+        else {
+            return false;
+        };
+        let Some(signed_at) = timestamp.to_str().ok().and_then(|t| t.parse::<i64>().ok()) else {
+            return false;
+        };
+        if signed_at.abs_diff(now) > self.max_skew.as_secs() {
+            return false;
+        }
+        let Ok(signature) = hex::decode(signature.as_bytes()) else {
+            return false;
+        };
+        let verified = self.hmac_secrets.iter().any(|secret| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                .expect("HMAC accepts keys of any length.");
+            mac.update(timestamp.as_bytes());
+            mac.update(b".");
+            mac.update(body);
+            mac.verify_slice(&signature).is_ok()
+        });
+        verified && self.first_use(signature, signed_at, now)
+    }
+
+    /// Remember the signature of a request signed at `signed_at`, returning
+    /// whether it was not seen before.
+    fn first_use(&self, signature: Vec<u8>, signed_at: i64, now: i64) -> bool {
+        let max_skew = self.max_skew.as_secs() as i64;
+        let mut seen = self.seen.lock().unwrap();
+        if now >= seen.next_prune {
+            seen.signatures
+                .retain(|_, signed_at| now - *signed_at <= max_skew);
+            seen.next_prune = now + max_skew.max(1);
+        }
+        match seen.signatures.entry(signature) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(signed_at);
+                true
+            }
+        }
+    }
+
+    /// Check that the request is authenticated and passes the challenge.
+    pub async fn check(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+        ip: Option<IpAddr>,
+    ) -> Result<(), LogError> {
+        if self.requires_authentication()
+            && !self.check_api_key(headers)
+            && !self.check_signature(headers, body, chrono::Utc::now().timestamp())
+        {
+            return Err(LogError::Unauthorized);
+        }
+        if let Some(challenge) = &self.challenge {
+            let token = headers
+                .get(CHALLENGE_HEADER)
+                .and_then(|token| token.to_str().ok())
+                .ok_or_else(|| {
+                    LogError::ChallengeFailed("The challenge token is missing.".into())
+                })?;
+            challenge
+                .verify(token, body, ip)
+                .await
+                .map_err(LogError::ChallengeFailed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check that a request to the admin API carries one of the tokens in the
+/// `Authorization: Bearer` header. Without tokens, the admin API is disabled.
+pub fn admin_auth(
+    tokens: Arc<Vec<String>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let authorized = authorization
+                .as_deref()
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .is_some_and(|token| {
+                    tokens
+                        .iter()
+                        .any(|expected| constant_time_eq(expected.as_bytes(), token.as_bytes()))
+                });
+            async move {
+                if authorized {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(LogError::AdminUnauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Parse an address of a forwarding header, which may be quoted, carry a port
+/// and, for IPv6, be enclosed in brackets.
+fn parse_forwarded_addr(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            value
+                .strip_prefix('[')?
+                .strip_suffix(']')?
+                .parse::<IpAddr>()
+                .ok()
+        })
+}
+
+/// The addresses a request was forwarded for, the client first. They are
+/// taken from the [`FORWARDED_FOR_HEADER`] if present, otherwise from the
+/// `for` parameters of the [`FORWARDED_HEADER`]. Addresses that cannot be
+/// parsed, such as `unknown`, are `None`.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+    };
+    let forwarded_for: Vec<_> = values(FORWARDED_FOR_HEADER)
+        .map(parse_forwarded_addr)
+        .collect();
+    if !forwarded_for.is_empty() {
+        return forwarded_for;
+    }
+    values(FORWARDED_HEADER)
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for").then_some(value)
+            })
+        })
+        .map(|value| value.and_then(parse_forwarded_addr))
+        .collect()
+}
+
+/// The IP address of the client of a request. If the request came from one of
+/// the trusted proxies, the forwarded addresses are followed from the last
+/// one back to the first address not of a trusted proxy. Since clients can
+/// send forwarding headers themselves, only the addresses appended by trusted
+/// proxies are used. If an address cannot be parsed, the last trusted proxy is
+/// taken as the client.
+pub fn client_ip(
+    remote: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut client = remote?;
+    if !trusted_proxies.contains(&client) {
+        return Some(client);
+    }
+    for hop in forwarded_for(headers).into_iter().rev() {
+        let Some(hop) = hop else {
+            break;
+        };
+        client = hop;
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+/// Extract the JSON body of type `T` and the IP address of the client of a
+/// request, after checking the request with the guard.
+pub fn guarded_json<T: serde::de::DeserializeOwned + Send>(
+    guard: Arc<RequestGuard>,
+) -> impl Filter<Extract = (T, Option<IpAddr>), Error = Rejection> + Clone {
+    warp::header::headers_cloned()
+        .and(warp::body::bytes())
+        .and(warp::addr::remote())
+        .and_then(
+            move |headers: HeaderMap,
+                  body: warp::hyper::body::Bytes,
+                  remote: Option<SocketAddr>| {
+                let guard = guard.clone();
+                async move {
+                    let ip = client_ip(
+                        remote.map(|addr| addr.ip()),
+                        &headers,
+                        &guard.trusted_proxies,
+                    );
+                    guard.check(&headers, &body, ip).await?;
+                    let request: T =
+                        serde_json::from_slice(&body).map_err(|_| LogError::MalformedBody)?;
+                    Ok::<_, Rejection>((request, ip))
+                }
+            },
+        )
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn headers(pairs: &[(&'static str, String)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_authentication() {
+        let guard = RequestGuard::new(
+            vec!["key".into()],
+            vec!["secret".into()],
+            Duration::from_secs(300),
+            None,
+            Vec::new(),
+        );
+        let body = b"{}";
+        let now = 1_700_000_000;
+
+        assert!(guard.check_api_key(&headers(&[(API_KEY_HEADER, "key".into())])));
+        assert!(!guard.check_api_key(&headers(&[(API_KEY_HEADER, "other".into())])));
+
+        let signed = |timestamp: i64, secret: &str| {
+            headers(&[
+                (TIMESTAMP_HEADER, timestamp.to_string()),
+                (SIGNATURE_HEADER, sign(secret, timestamp, body)),
+            ])
+        };
+        assert!(guard.check_signature(&signed(now, "secret"), body, now));
+        assert!(guard.check_signature(&signed(now - 300, "secret"), body, now));
+        assert!(!guard.check_signature(&signed(now - 301, "secret"), body, now));
+        assert!(!guard.check_signature(&signed(now, "other"), body, now));
+        assert!(!guard.check_signature(&signed(now, "secret"), b"{\"a\":1}", now));
+    }
+
+    #[test]
+    fn test_signed_requests_cannot_be_replayed() {
+        let guard = RequestGuard::new(
+            Vec::new(),
+            vec!["secret".into()],
+            Duration::from_secs(300),
+            None,
+            Vec::new(),
+        );
+        let body = b"{}";
+        let now = 1_700_000_000;
+        let signed = |timestamp: i64| {
+            headers(&[
+                (TIMESTAMP_HEADER, timestamp.to_string()),
+                (SIGNATURE_HEADER, sign("secret", timestamp, body)),
+            ])
+        };
+
+        assert!(guard.check_signature(&signed(now), body, now));
+        assert!(!guard.check_signature(&signed(now), body, now + 10));
+        // A new signature is accepted.
+        assert!(guard.check_signature(&signed(now + 1), body, now + 10));
+        // Seen signatures are dropped once they are too old anyway.
+        assert!(!guard.check_signature(&signed(now), body, now + 301));
+        guard.check_signature(&signed(now + 400), body, now + 400);
+        assert_eq!(guard.seen.lock().unwrap().signatures.len(), 1);
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxy: IpAddr = [10, 0, 0, 1].into();
+        let client: IpAddr = [203, 0, 113, 7].into();
+        let trusted = [proxy, [10, 0, 0, 2].into()];
+        let forwarded_for = |value: &str| headers(&[(FORWARDED_FOR_HEADER, value.into())]);
+
+        // The headers of untrusted remotes are ignored.
+        assert_eq!(
+            client_ip(Some(client), &forwarded_for("192.0.2.1"), &trusted),
+            Some(client)
+        );
+        assert_eq!(
+            client_ip(Some(proxy), &forwarded_for("203.0.113.7"), &trusted),
+            Some(client)
+        );
+        // Addresses before the first untrusted one may be spoofed by the client.
+        assert_eq!(
+            client_ip(
+                Some(proxy),
+                &forwarded_for("192.0.2.1, 203.0.113.7:4711, 10.0.0.2"),
+                &trusted
+            ),
+            Some(client)
+        );
+        assert_eq!(
+            client_ip(Some(proxy), &forwarded_for("unknown"), &trusted),
+            Some(proxy)
+        );
+        assert_eq!(
+            client_ip(Some(proxy), &HeaderMap::new(), &trusted),
+            Some(proxy)
+        );
+        assert_eq!(
+            client_ip(
+                Some(proxy),
+                &headers(&[(
+                    FORWARDED_HEADER,
+                    "for=192.0.2.1, for=\"[2001:db8::1]:4711\";proto=https".into()
+                )]),
+                &trusted
+            ),
+            "2001:db8::1".parse().ok()
+        );
+    }
+
+    #[test]
+    fn test_parse_origin() {
+        assert_eq!(
+            parse_origin("https://redeem.example.com").unwrap(),
+            "https://redeem.example.com"
+        );
+        assert_eq!(
+            parse_origin("http://Localhost:3000/").unwrap(),
+            "http://localhost:3000"
+        );
+        assert_eq!(
+            parse_origin("https://example.com:443").unwrap(),
+            "https://example.com"
+        );
+        for origin in [
+            "redeem.example.com",
+            "https://",
+            "ftp://example.com",
+            "https://example.com/redeem",
+            "https://example.com?a=1",
+            "https://user@example.com",
+            "https://example.com:port",
+        ] {
+            assert!(parse_origin(origin).is_err(), "{}", origin);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proof_of_work() {
+        let pow = ProofOfWork { difficulty: 8 };
+        let body = b"{\"signer\":\"...\"}";
+        let token = (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|token| {
+                let hash = Sha256::new()
+                    .chain_update(body)
+                    .chain_update(token.as_bytes())
+                    .finalize();
+                leading_zero_bits(&hash) >= 8
+            })
+            .unwrap();
+        assert!(pow.verify(&token, body, None).await.is_ok());
+        assert!(pow.verify(&token, b"{}", None).await.is_err());
+        assert_eq!(leading_zero_bits(&[0, 0b0001_0000, 0]), 11);
+    }
+}
@@ -0,0 +1,321 @@
+use crate::nodes::Nodes;
+use crate::tx_status::TxStatusUpdate;
+use crate::types::LogError;
+use concordium_rust_sdk::smart_contracts::common::{AccountAddress, Amount};
+use concordium_rust_sdk::types::AccountInfo;
+use concordium_rust_sdk::v2::BlockIdentifier;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// The number of recent transactions the average transaction cost is computed
+/// from.
+const COST_SAMPLES: usize = 100;
+
+/// How long to wait for the alert webhook to respond.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The balance of the account that can be used to pay for transactions, i.e.,
+/// that is neither locked up in a release schedule nor staked.
+pub fn available_balance(info: &AccountInfo) -> Amount {
+    let staked = info
+        .account_stake
+        .as_ref()
+        .map_or(Amount::zero(), |stake| stake.staked_amount());
+    let locked = std::cmp::max(info.account_release_schedule.total, staked);
+    info.account_amount
+        .checked_sub(locked)
+        .unwrap_or(Amount::zero())
+}
+
+/// How the balance of the sponsor account compares to the thresholds, ordered
+/// by severity.
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum BalanceLevel {
+    /// The balance is above the alert threshold.
+    Ok,
+    /// The balance is below the alert threshold.
+    Alert,
+    /// The balance is below the low watermark. No new sponsored transactions
+    /// are submitted.
+    Low,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BalanceThresholds {
+    /// Below this balance, new sponsored transactions are refused.
+    pub low_watermark: Amount,
+    /// Below this balance, an alert is raised.
+    pub alert: Amount,
+}
+
+impl BalanceThresholds {
+    fn level(&self, balance: Amount) -> BalanceLevel {
+        if balance < self.low_watermark {
+            BalanceLevel::Low
+        } else if balance < self.alert {
+            BalanceLevel::Alert
+        } else {
+            BalanceLevel::Ok
+        }
+    }
+}
+
+/// The balance of the sponsor account, as returned by `GET /health/balance`.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BalanceReport {
+    pub account: AccountAddress,
+    /// The available balance, if it was queried yet.
+    pub balance: Option<Amount>,
+    pub level: Option<BalanceLevel>,
+    pub low_watermark: Amount,
+    pub alert_threshold: Amount,
+    /// The average cost of the recent sponsored transactions.
+    pub average_transaction_cost: Option<Amount>,
+    /// The number of sponsored transactions that can be paid for before the
+    /// balance drops below the low watermark.
+    pub estimated_remaining_transactions: Option<u64>,
+}
+
+/// The body of the requests sent to the alert webhook.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct BalanceAlert {
+    account: AccountAddress,
+    balance: Amount,
+    level: BalanceLevel,
+    previous_level: Option<BalanceLevel>,
+}
+
+#[derive(Default)]
+struct BalanceState {
+    balance: Option<Amount>,
+    level: Option<BalanceLevel>,
+    /// The costs of the recent sponsored transactions.
+    costs: VecDeque<Amount>,
+}
+
+/// Monitors the balance of the sponsor account. Raises alerts when it drops
+/// below the thresholds and refuses new sponsored transactions when it drops
+/// below the low watermark.
+pub struct BalanceMonitor {
+    address: AccountAddress,
+    thresholds: BalanceThresholds,
+    webhook: Option<reqwest::Url>,
+    http: reqwest::Client,
+    state: Mutex<BalanceState>,
+}
+
+impl BalanceMonitor {
+    pub fn new(
+        address: AccountAddress,
+        thresholds: BalanceThresholds,
+        webhook: Option<reqwest::Url>,
+    ) -> Self {
+        Self {
+            address,
+            thresholds,
+            webhook,
+            http: reqwest::Client::new(),
+            state: Mutex::new(BalanceState::default()),
+        }
+    }
+
+    /// Check that the sponsor account can pay for a new transaction.
+    pub fn check(&self) -> Result<(), LogError> {
+        match self.state.lock().unwrap().level {
+            Some(BalanceLevel::Low) => Err(LogError::InsufficientSponsorBalance),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn report(&self) -> BalanceReport {
+        let state = self.state.lock().unwrap();
+        let average_transaction_cost = if state.costs.is_empty() {
+            None
+        } else {
+            let total: u64 = state.costs.iter().map(|cost| cost.micro_ccd).sum();
+            Some(Amount::from_micro_ccd(total / state.costs.len() as u64))
+        };
+        let estimated_remaining_transactions = state
+            .balance
+            .zip(average_transaction_cost)
+            .filter(|(_, cost)| cost.micro_ccd > 0)
+            .map(|(balance, cost)| {
+                balance
+                    .micro_ccd
+                    .saturating_sub(self.thresholds.low_watermark.micro_ccd)
+                    / cost.micro_ccd
+            });
+        BalanceReport {
+            account: self.address,
+            balance: state.balance,
+            level: state.level,
+            low_watermark: self.thresholds.low_watermark,
+            alert_threshold: self.thresholds.alert,
+            average_transaction_cost,
+            estimated_remaining_transactions,
+        }
+    }
+
+    /// Set the balance, returning the previous level if the level changed.
+    fn set_balance(&self, balance: Amount) -> Option<Option<BalanceLevel>> {
+        let mut state = self.state.lock().unwrap();
+        let level = self.thresholds.level(balance);
+        state.balance = Some(balance);
+        let previous = state.level.replace(level);
+        (previous != Some(level)).then_some(previous)
+    }
+
+    fn record_cost(&self, cost: Amount) {
+        let mut state = self.state.lock().unwrap();
+        if state.costs.len() == COST_SAMPLES {
+            state.costs.pop_front();
+        }
+        state.costs.push_back(cost);
+    }
+
+    async fn refresh(&self, client: &mut concordium_rust_sdk::v2::Client) {
+        let info = match client
+            .get_account_info(&self.address.into(), BlockIdentifier::LastFinal)
+            .await
+        {
+            Ok(info) => info.response,
+            Err(e) => {
+                log::warn!("Could not query the balance of the sponsor account: {}", e);
+                return;
+            }
+        };
+        let balance = available_balance(&info);
+        log::debug!("Sponsor account balance is {} CCD.", balance);
+        if let Some(previous) = self.set_balance(balance) {
+            self.alert(balance, previous).await;
+        }
+    }
+
+    /// Log the change of the balance level and notify the webhook.
+    async fn alert(&self, balance: Amount, previous: Option<BalanceLevel>) {
+        let level = self.thresholds.level(balance);
+        match level {
+            BalanceLevel::Low => log::error!(
+                "Sponsor account balance {} CCD is below the low watermark of {} CCD. New \
+                 sponsored transactions are refused.",
+                balance,
+                self.thresholds.low_watermark
+            ),
+            BalanceLevel::Alert => log::warn!(
+                "Sponsor account balance {} CCD is below the alert threshold of {} CCD.",
+                balance,
+                self.thresholds.alert
+            ),
+            BalanceLevel::Ok => log::info!("Sponsor account balance is {} CCD.", balance),
+        }
+        // Only changes are reported, apart from the initial level if it is not ok.
+        if previous.is_none() && level == BalanceLevel::Ok {
+            return;
+        }
+        let Some(webhook) = &self.webhook else {
+            return;
+        };
+        let alert = BalanceAlert {
+            account: self.address,
+            balance,
+            level,
+            previous_level: previous,
+        };
+        let response = self
+            .http
+            .post(webhook.clone())
+            .json(&alert)
+            .timeout(WEBHOOK_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = response {
+            log::warn!("Could not notify the alert webhook: {}", e);
+        }
+    }
+
+    /// Periodically query the balance of the sponsor account.
+    pub async fn run(self: Arc<Self>, nodes: Nodes, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match nodes.client() {
+                Ok(mut client) => self.refresh(&mut client).await,
+                Err(e) => log::warn!("Could not query the balance of the sponsor account: {}", e),
+            }
+        }
+    }
+
+    /// Record the costs of the finalized sponsored transactions.
+    pub async fn track_costs(self: Arc<Self>, mut updates: broadcast::Receiver<TxStatusUpdate>) {
+        loop {
+            match updates.recv().await {
+                Ok(update) => {
+                    if let Some(cost) = update.status.cost() {
+                        self.record_cost(cost);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ccd(amount: u64) -> Amount {
+        Amount::from_ccd(amount)
+    }
+
+    fn monitor() -> BalanceMonitor {
+        BalanceMonitor::new(
+            AccountAddress([0; 32]),
+            BalanceThresholds {
+                low_watermark: ccd(10),
+                alert: ccd(50),
+            },
+            None,
+        )
+    }
+
+    #[test]
+    fn test_levels() {
+        let monitor = monitor();
+        assert!(monitor.check().is_ok());
+
+        assert_eq!(monitor.set_balance(ccd(100)), Some(None));
+        assert_eq!(monitor.set_balance(ccd(90)), None);
+        assert_eq!(monitor.set_balance(ccd(40)), Some(Some(BalanceLevel::Ok)));
+        assert!(monitor.check().is_ok());
+
+        assert_eq!(monitor.set_balance(ccd(5)), Some(Some(BalanceLevel::Alert)));
+        assert!(matches!(
+            monitor.check(),
+            Err(LogError::InsufficientSponsorBalance)
+        ));
+
+        assert_eq!(monitor.set_balance(ccd(60)), Some(Some(BalanceLevel::Low)));
+        assert!(monitor.check().is_ok());
+    }
+
+    #[test]
+    fn test_estimated_remaining_transactions() {
+        let monitor = monitor();
+        monitor.record_cost(ccd(1));
+        monitor.record_cost(ccd(3));
+        assert_eq!(monitor.report().estimated_remaining_transactions, None);
+
+        monitor.set_balance(ccd(30));
+        let report = monitor.report();
+        assert_eq!(report.average_transaction_cost, Some(ccd(2)));
+        assert_eq!(report.estimated_remaining_transactions, Some(10));
+    }
+}
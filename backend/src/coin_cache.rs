@@ -0,0 +1,121 @@
+use concordium_rust_sdk::smart_contracts::common::{Amount, ContractAddress, PublicKeyEd25519};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The status of a coin, as returned by `GET /api/coin/{public_key}`.
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoinStatus {
+    pub amount: Amount,
+    pub status: CoinRedemption,
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum CoinRedemption {
+    Unredeemed,
+    Redeemed,
+}
+
+type CoinId = (ContractAddress, PublicKeyEd25519);
+
+/// Caches the status of coins queried from the smart contract. A redeemed coin
+/// stays redeemed, so its status is cached indefinitely. The status of an
+/// unredeemed coin is only cached for a short time, since it can be redeemed
+/// any moment. Coins are identified by the contract instance and their public
+/// key.
+pub struct CoinCache {
+    unredeemed_ttl: Duration,
+    coins: Mutex<BTreeMap<CoinId, (CoinStatus, Instant)>>,
+}
+
+impl CoinCache {
+    pub fn new(unredeemed_ttl: Duration) -> Self {
+        Self {
+            unredeemed_ttl,
+            coins: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The cached status of the coin, if any.
+    pub fn get(&self, key: &CoinId) -> Option<CoinStatus> {
+        self.get_at(key, Instant::now())
+    }
+
+    fn get_at(&self, key: &CoinId, now: Instant) -> Option<CoinStatus> {
+        let mut coins = self.coins.lock().unwrap();
+        let (status, cached_at) = *coins.get(key)?;
+        match status.status {
+            CoinRedemption::Redeemed => Some(status),
+            CoinRedemption::Unredeemed if now.duration_since(cached_at) < self.unredeemed_ttl => {
+                Some(status)
+            }
+            CoinRedemption::Unredeemed => {
+                coins.remove(key);
+                None
+            }
+        }
+    }
+
+    /// Cache the status of the coin.
+    pub fn insert(&self, key: CoinId, status: CoinStatus) {
+        self.insert_at(key, status, Instant::now())
+    }
+
+    fn insert_at(&self, key: CoinId, status: CoinStatus, now: Instant) {
+        if status.status == CoinRedemption::Unredeemed && self.unredeemed_ttl.is_zero() {
+            return;
+        }
+        let mut coins = self.coins.lock().unwrap();
+        // Expired entries of unredeemed coins are dropped here, so coins that
+        // are looked up only once do not pile up.
+        coins.retain(|_, (status, cached_at)| {
+            status.status == CoinRedemption::Redeemed
+                || now.duration_since(*cached_at) < self.unredeemed_ttl
+        });
+        coins.insert(key, (status, now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: CoinId = (
+        ContractAddress {
+            index: 1,
+            subindex: 0,
+        },
+        PublicKeyEd25519([1; 32]),
+    );
+
+    fn coin(status: CoinRedemption) -> CoinStatus {
+        CoinStatus {
+            amount: Amount::from_micro_ccd(1_000_000),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_unredeemed_coins_expire() {
+        let cache = CoinCache::new(Duration::from_secs(10));
+        let now = Instant::now();
+
+        cache.insert_at(KEY, coin(CoinRedemption::Unredeemed), now);
+        assert!(cache.get_at(&KEY, now + Duration::from_secs(9)).is_some());
+        assert!(cache.get_at(&KEY, now + Duration::from_secs(10)).is_none());
+    }
+
+    #[test]
+    fn test_redeemed_coins_do_not_expire() {
+        let cache = CoinCache::new(Duration::from_secs(10));
+        let now = Instant::now();
+
+        cache.insert_at(KEY, coin(CoinRedemption::Unredeemed), now);
+        cache.insert_at(KEY, coin(CoinRedemption::Redeemed), now);
+        assert_eq!(
+            cache.get_at(&KEY, now + Duration::from_secs(3600)),
+            Some(coin(CoinRedemption::Redeemed))
+        );
+    }
+}
@@ -0,0 +1,251 @@
+use anyhow::{bail, Context};
+use clap::{ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches};
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// The prefix of the environment variables the settings can be given with,
+/// e.g., `SPONSOR_NODE` for `--node`.
+pub const ENV_PREFIX: &str = "SPONSOR_";
+
+/// The id of the argument giving the path of the configuration file.
+const CONFIG_ARG: &str = "config";
+
+/// The id of the flag printing the effective configuration.
+const PRINT_CONFIG_ARG: &str = "print_config";
+
+/// Settings whose values are replaced by `<redacted>` when the configuration
+/// is printed, by their long name.
+const SECRET_SETTINGS: &[&str] = &[
+    "alert-webhook",
+    "api-key",
+    "hmac-secret",
+    "captcha-secret",
+    "admin-token",
+];
+
+/// The name of the environment variable of the setting with the given long
+/// name.
+fn env_name(long: &str) -> String {
+    format!("{}{}", ENV_PREFIX, long.to_uppercase().replace('-', "_"))
+}
+
+/// Whether the argument is a setting that can be given in the configuration
+/// file and printed, as opposed to `--config`, `--print-config`, `--help`
+/// and `--version`.
+fn is_setting(arg: &clap::Arg) -> bool {
+    arg.get_long().is_some()
+        && arg.get_id() != CONFIG_ARG
+        && !matches!(
+            arg.get_action(),
+            ArgAction::Help | ArgAction::Version | ArgAction::SetTrue | ArgAction::SetFalse
+        )
+}
+
+/// Find the path of the configuration file, given either as `--config` or in
+/// the environment. The command line has not been parsed yet at this point.
+fn find_config_path(args: &[OsString]) -> Option<PathBuf> {
+    let long = "--config";
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_str()?;
+        if arg == long {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg
+            .strip_prefix(long)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(path.into());
+        }
+    }
+    std::env::var_os(env_name(long.trim_start_matches('-'))).map(PathBuf::from)
+}
+
+/// The string representation of a value in the configuration file, as it
+/// would be given on the command line.
+fn toml_to_arg(key: &str, value: toml::Value) -> anyhow::Result<Vec<String>> {
+    Ok(match value {
+        toml::Value::String(s) => vec![s],
+        toml::Value::Integer(i) => vec![i.to_string()],
+        toml::Value::Float(f) => vec![f.to_string()],
+        toml::Value::Boolean(b) => vec![b.to_string()],
+        toml::Value::Array(values) => {
+            let mut args = Vec::with_capacity(values.len());
+            for value in values {
+                match toml_to_arg(key, value)?.as_slice() {
+                    [arg] => args.push(arg.clone()),
+                    _ => bail!("The setting {:?} cannot contain nested arrays.", key),
+                }
+            }
+            args
+        }
+        _ => bail!(
+            "The setting {:?} must be a string, number, boolean or array.",
+            key
+        ),
+    })
+}
+
+/// Add the layers below the command line to the command: every setting can be
+/// given as an environment variable, and otherwise falls back to the value in
+/// the configuration file, if any, before its default.
+fn add_layers(mut command: Command, mut file: toml::value::Table) -> anyhow::Result<Command> {
+    let settings: Vec<(String, String)> = command
+        .get_arguments()
+        .filter(|arg| is_setting(arg))
+        .map(|arg| {
+            (
+                arg.get_id().to_string(),
+                arg.get_long().unwrap().to_string(),
+            )
+        })
+        .collect();
+    for (id, long) in settings {
+        let file_value = file
+            .remove(&long)
+            .map(|value| toml_to_arg(&long, value))
+            .transpose()?;
+        command = command.mut_arg(id, |arg| {
+            let arg = arg.env(env_name(&long));
+            match file_value {
+                Some(values) => arg.default_values(values).required(false),
+                None => arg,
+            }
+        });
+    }
+    command = command.mut_arg(CONFIG_ARG, |arg| arg.env(env_name("config")));
+    if let Some(key) = file.keys().next() {
+        bail!("Unknown setting {:?} in the configuration file.", key);
+    }
+    Ok(command)
+}
+
+/// Parse the configuration of type `T` from the command line, the environment
+/// and the configuration file, in this order of precedence. `T` must have the
+/// arguments `config` and `print_config`. If `--print-config` is given, the
+/// effective configuration is printed and the process exits.
+pub fn parse<T: CommandFactory + FromArgMatches>() -> anyhow::Result<T> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let file = match find_config_path(&args) {
+        Some(path) => {
+            let contents = std::fs::read_to_string(&path).with_context(|| {
+                format!("Could not read the configuration file {}.", path.display())
+            })?;
+            toml::from_str(&contents).with_context(|| {
+                format!("Could not parse the configuration file {}.", path.display())
+            })?
+        }
+        None => toml::value::Table::new(),
+    };
+    let command = add_layers(T::command(), file)?;
+    let matches = command.clone().get_matches_from(args);
+    if matches.get_flag(PRINT_CONFIG_ARG) {
+        print!("{}", effective_config(&command, &matches)?);
+        std::process::exit(0);
+    }
+    Ok(T::from_arg_matches(&matches)?)
+}
+
+/// The effective configuration in the format of the configuration file, with
+/// the values of secret settings redacted.
+fn effective_config(command: &Command, matches: &ArgMatches) -> anyhow::Result<String> {
+    let mut config = toml::value::Table::new();
+    for arg in command.get_arguments().filter(|arg| is_setting(arg)) {
+        let long = arg.get_long().unwrap();
+        let Some(raw) = matches.get_raw(arg.get_id().as_str()) else {
+            continue;
+        };
+        let values: Vec<toml::Value> = raw
+            .map(|value| {
+                let value = value.to_string_lossy();
+                if SECRET_SETTINGS.contains(&long) {
+                    toml::Value::String("<redacted>".into())
+                } else if let Ok(i) = value.parse::<i64>() {
+                    toml::Value::Integer(i)
+                } else {
+                    toml::Value::String(value.into_owned())
+                }
+            })
+            .collect();
+        let value = if matches!(arg.get_action(), ArgAction::Append) {
+            toml::Value::Array(values)
+        } else {
+            match values.into_iter().next() {
+                Some(value) => value,
+                None => continue,
+            }
+        };
+        config.insert(long.into(), value);
+    }
+    Ok(toml::to_string(&config)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(clap::Parser, Debug)]
+    struct TestConfig {
+        #[clap(long = "config")]
+        config: Option<PathBuf>,
+        #[clap(long = "print-config")]
+        print_config: bool,
+        #[clap(long = "port", default_value = "8100")]
+        port: u16,
+        #[clap(long = "account")]
+        keys_path: PathBuf,
+        #[clap(long = "alert-webhook")]
+        alert_webhook: Option<String>,
+        #[clap(long = "instance")]
+        instances: Vec<String>,
+    }
+
+    fn parse_from(file: &str, args: &[&str]) -> anyhow::Result<(TestConfig, String)> {
+        let command = add_layers(TestConfig::command(), toml::from_str(file)?)?;
+        let matches = command
+            .clone()
+            .try_get_matches_from(std::iter::once("test").chain(args.iter().copied()))?;
+        let printed = effective_config(&command, &matches)?;
+        Ok((TestConfig::from_arg_matches(&matches)?, printed))
+    }
+
+    #[test]
+    fn test_layers() {
+        let file = r#"
+            port = 9000
+            account = "keys.json"
+            alert-webhook = "https://example.com/hook?token=secret"
+            instance = ["coins=ccd_redeem@4185"]
+        "#;
+        let (config, printed) = parse_from(file, &[]).unwrap();
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.keys_path, PathBuf::from("keys.json"));
+        assert_eq!(config.instances, vec!["coins=ccd_redeem@4185"]);
+        assert!(printed.contains("port = 9000"));
+        assert!(printed.contains(r#"alert-webhook = "<redacted>""#));
+        assert!(!printed.contains("secret"));
+        assert!(printed.contains(r#"instance = ["coins=ccd_redeem@4185"]"#));
+
+        let (config, _) = parse_from(file, &["--port", "9001"]).unwrap();
+        assert_eq!(config.port, 9001);
+
+        let (config, _) = parse_from("account = \"keys.json\"", &[]).unwrap();
+        assert_eq!(config.port, 8100);
+
+        assert!(parse_from("", &[]).is_err());
+        assert!(parse_from("unknown = 1", &["--account", "keys.json"]).is_err());
+    }
+
+    #[test]
+    fn test_find_config_path() {
+        let args = |args: &[&str]| -> Vec<OsString> { args.iter().map(Into::into).collect() };
+        assert_eq!(
+            find_config_path(&args(&["backend", "--config", "a.toml"])),
+            Some("a.toml".into())
+        );
+        assert_eq!(
+            find_config_path(&args(&["backend", "--port", "1", "--config=b.toml"])),
+            Some("b.toml".into())
+        );
+    }
+}
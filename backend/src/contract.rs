@@ -0,0 +1,303 @@
+use crate::handlers::{decode_contract_error, get_contract_schema};
+use crate::nodes::Nodes;
+use crate::types::{LogError, RevertReason, SupportsPermitQueryParams};
+use anyhow::{bail, Context};
+use concordium_rust_sdk::cis0::{SupportResult, SupportsQueryResponse};
+use concordium_rust_sdk::smart_contracts::common::{
+    from_bytes, schema::VersionedModuleSchema, Amount, ContractAddress, OwnedContractName,
+    OwnedEntrypointName,
+};
+use concordium_rust_sdk::types::smart_contracts::{
+    ContractContext, InvokeContractResult, OwnedParameter, OwnedReceiveName,
+};
+use concordium_rust_sdk::types::Energy;
+use concordium_rust_sdk::v2::BlockIdentifier;
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// The entrypoint redeeming coins, which `permit` must support.
+pub const REDEEM_ENTRYPOINT: &str = "redeem";
+
+/// The entrypoint redeeming coins issued in a Merkle batch, which is only
+/// supported by newer versions of the contract.
+pub const REDEEM_MERKLE_ENTRYPOINT: &str = "redeemMerkle";
+
+/// The energy available to queries of the contract.
+const QUERY_ENERGY: Energy = Energy { energy: 10_000 };
+
+/// A smart contract instance the backend sponsors transactions for.
+#[derive(Debug, Clone)]
+pub struct ContractInstance {
+    /// The name of the contract, without the `init_` prefix.
+    pub name: String,
+    pub address: ContractAddress,
+    /// The schema embedded in the contract module, used to decode the errors
+    /// returned by the contract.
+    pub schema: Option<Arc<VersionedModuleSchema>>,
+    /// The entrypoints `permit` is known to support.
+    permit_entrypoints: Arc<Mutex<HashSet<OwnedEntrypointName>>>,
+}
+
+impl ContractInstance {
+    /// Create the instance, getting the schema of the contract from the node.
+    pub async fn load(
+        client: concordium_rust_sdk::v2::Client,
+        name: String,
+        address: ContractAddress,
+    ) -> Self {
+        let schema = match get_contract_schema(client, address).await {
+            Ok(schema) => Some(Arc::new(schema)),
+            Err(e) => {
+                log::warn!(
+                    "Could not get the schema of the smart contract {}: {:#}.",
+                    address,
+                    e
+                );
+                None
+            }
+        };
+        Self::new(name, address, schema)
+    }
+
+    pub fn new(
+        name: String,
+        address: ContractAddress,
+        schema: Option<Arc<VersionedModuleSchema>>,
+    ) -> Self {
+        Self {
+            name,
+            address,
+            schema,
+            permit_entrypoints: Arc::default(),
+        }
+    }
+
+    /// The receive name of the given entrypoint of the contract.
+    pub fn receive_name(&self, entrypoint: &str) -> Result<OwnedReceiveName, LogError> {
+        OwnedReceiveName::try_from(format!("{}.{}", self.name, entrypoint))
+            .map_err(|_| LogError::OwnedReceiveNameError)
+    }
+
+    /// Serialize the parameter of the entrypoint given in JSON, using the
+    /// schema embedded in the contract module.
+    pub fn serialize_parameter(
+        &self,
+        entrypoint: &OwnedEntrypointName,
+        parameter: &serde_json::Value,
+    ) -> Result<Vec<u8>, LogError> {
+        let schema = self.schema.as_deref().ok_or_else(|| {
+            LogError::PermitPayloadError("The contract module has no embedded schema.".into())
+        })?;
+        let parameter_type = schema
+            .get_receive_param_schema(&self.name, entrypoint.as_entrypoint_name().into())
+            .map_err(|_| {
+                LogError::PermitPayloadError(format!(
+                    "The schema does not describe the parameter of the entrypoint {}.",
+                    entrypoint
+                ))
+            })?;
+        parameter_type
+            .serial_value(parameter)
+            .map_err(|e| LogError::PermitPayloadError(e.to_string()))
+    }
+
+    /// Whether `permit` of the contract supports the entrypoint, according to
+    /// `supportsPermit`. Supported entrypoints are remembered, so they are only
+    /// queried once.
+    pub async fn supports_permit(
+        &self,
+        nodes: &Nodes,
+        entrypoint: &OwnedEntrypointName,
+    ) -> Result<bool, LogError> {
+        if self.permit_entrypoints.lock().unwrap().contains(entrypoint) {
+            return Ok(true);
+        }
+        let parameter = OwnedParameter::from_serial(&SupportsPermitQueryParams {
+            queries: vec![entrypoint.clone()],
+        })
+        .map_err(|_| LogError::ParameterError)?;
+        let context = ContractContext {
+            invoker: None,
+            contract: self.address,
+            amount: Amount::zero(),
+            method: self.receive_name("supportsPermit")?,
+            parameter,
+            energy: QUERY_ENERGY,
+        };
+        let info = nodes
+            .invoke_instance(&BlockIdentifier::LastFinal, &context)
+            .await?;
+        let InvokeContractResult::Success {
+            return_value: Some(return_value),
+            ..
+        } = info.response
+        else {
+            return Ok(false);
+        };
+        let response: SupportsQueryResponse =
+            from_bytes(&return_value.value).map_err(|_| LogError::ParameterError)?;
+        let supported = matches!(response.results.first(), Some(SupportResult::Support));
+        if supported {
+            self.permit_entrypoints
+                .lock()
+                .unwrap()
+                .insert(entrypoint.clone());
+        }
+        Ok(supported)
+    }
+
+    /// Decode the contract error of a rejected transaction simulation into the
+    /// name of the error variant, if the schema describes it.
+    pub fn decode_error(&self, reason: &RevertReason) -> Option<String> {
+        decode_contract_error(self.schema.as_deref()?, reason)
+    }
+}
+
+/// An additional contract instance served under `/api/{path}/`, given on the
+/// command line as `PATH=CONTRACT_NAME@INDEX[,SUBINDEX]`.
+#[derive(Debug, Clone)]
+pub struct InstanceArg {
+    pub path: String,
+    pub name: String,
+    pub address: ContractAddress,
+}
+
+/// Check that the name is a valid contract name, without the `init_` prefix.
+pub fn parse_contract_name(name: &str) -> anyhow::Result<String> {
+    OwnedContractName::new(format!("init_{}", name))
+        .with_context(|| format!("Invalid contract name {:?}.", name))?;
+    Ok(name.into())
+}
+
+impl FromStr for InstanceArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, contract) = s
+            .split_once('=')
+            .context("Expected PATH=CONTRACT_NAME@INDEX[,SUBINDEX].")?;
+        if path.is_empty()
+            || !path
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!(
+                "The path {:?} must be non-empty and only contain alphanumeric characters, `-` \
+                 and `_`.",
+                path
+            );
+        }
+        let (name, address) = contract
+            .split_once('@')
+            .context("Expected CONTRACT_NAME@INDEX[,SUBINDEX].")?;
+        let (index, subindex) = address.split_once(',').unwrap_or((address, "0"));
+        Ok(InstanceArg {
+            path: path.into(),
+            name: parse_contract_name(name)?,
+            address: ContractAddress {
+                index: index.parse().context("Invalid contract index.")?,
+                subindex: subindex.parse().context("Invalid contract subindex.")?,
+            },
+        })
+    }
+}
+
+/// How much energy is given to sponsored transactions.
+#[derive(Debug, Clone, Copy)]
+pub struct EnergyConfig {
+    /// The energy available to the simulation of a transaction, and the
+    /// maximum energy given to a transaction.
+    pub max_energy: Energy,
+    /// The margin added to the energy used by the simulation, in percent.
+    pub margin_percent: u64,
+}
+
+impl EnergyConfig {
+    /// The energy to give to a transaction whose simulation used
+    /// `used_energy`. The state of the contract might change between the
+    /// simulation and the execution of the transaction, so a safety margin is
+    /// added.
+    pub fn estimate(&self, used_energy: Energy) -> Energy {
+        let margin = used_energy.energy.saturating_mul(self.margin_percent) / 100;
+        Energy {
+            energy: used_energy
+                .energy
+                .saturating_add(margin)
+                .min(self.max_energy.energy),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_instance() {
+        let instance: InstanceArg = "coins=ccd_redeem@4185".parse().unwrap();
+        assert_eq!(instance.path, "coins");
+        assert_eq!(instance.name, "ccd_redeem");
+        assert_eq!(instance.address, ContractAddress::new(4185, 0));
+
+        let instance: InstanceArg = "nft=cis3_nft@10,2".parse().unwrap();
+        assert_eq!(instance.address, ContractAddress::new(10, 2));
+
+        assert!("ccd_redeem@4185".parse::<InstanceArg>().is_err());
+        assert!("a/b=ccd_redeem@4185".parse::<InstanceArg>().is_err());
+        assert!("coins=ccd_redeem".parse::<InstanceArg>().is_err());
+        assert!("coins=ccd.redeem@1".parse::<InstanceArg>().is_err());
+    }
+
+    #[test]
+    fn test_serialize_parameter() {
+        use concordium_rust_sdk::smart_contracts::common::{
+            schema::{ContractV3, FunctionV2, ModuleV3, SchemaType},
+            to_bytes, AccountAddress,
+        };
+        use std::collections::BTreeMap;
+
+        let function = FunctionV2 {
+            parameter: Some(AccountAddress::get_type()),
+            return_value: None,
+            error: None,
+        };
+        let schema = VersionedModuleSchema::V3(ModuleV3 {
+            contracts: BTreeMap::from([(
+                "ccd_redeem".into(),
+                ContractV3 {
+                    init: None,
+                    receive: BTreeMap::from([("setAdmin".into(), function)]),
+                    event: None,
+                },
+            )]),
+        });
+        let mut contract =
+            ContractInstance::new("ccd_redeem".into(), ContractAddress::new(4184, 0), None);
+        let set_admin = OwnedEntrypointName::new_unchecked("setAdmin".into());
+        let address = AccountAddress([1; 32]);
+        let json = serde_json::to_value(address).unwrap();
+        assert!(contract.serialize_parameter(&set_admin, &json).is_err());
+
+        contract.schema = Some(Arc::new(schema));
+        assert_eq!(
+            contract.serialize_parameter(&set_admin, &json).unwrap(),
+            to_bytes(&address)
+        );
+        assert!(contract
+            .serialize_parameter(&set_admin, &serde_json::json!(1))
+            .is_err());
+        let unknown = OwnedEntrypointName::new_unchecked("unknown".into());
+        assert!(contract.serialize_parameter(&unknown, &json).is_err());
+    }
+
+    #[test]
+    fn test_estimate_energy() {
+        let config = EnergyConfig {
+            max_energy: Energy { energy: 10_000 },
+            margin_percent: 20,
+        };
+        assert_eq!(config.estimate(Energy { energy: 5_000 }).energy, 6_000);
+        assert_eq!(config.estimate(Energy { energy: 9_000 }).energy, 10_000);
+    }
+}
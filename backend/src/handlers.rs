@@ -1,32 +1,118 @@
-use crate::crypto_common::types::TransactionTime;
+use crate::admin::{AccountRateLimitReport, RateLimitView};
+use crate::audit::{AuditEvent, AuditRecord};
+use crate::coin_cache::{CoinRedemption, CoinStatus};
+use crate::contract::{ContractInstance, REDEEM_ENTRYPOINT, REDEEM_MERKLE_ENTRYPOINT};
+use crate::health::{HealthReport, Readiness};
+use crate::idempotency::IdempotencyKey;
+use crate::outbox::{Job, JobStatus, JobView, Submission};
+use crate::pool::PoolReport;
+use crate::rate_limit::RateLimitKey;
+use crate::signatures;
+use crate::simulation::{self, SimulationReport};
 use crate::types::*;
 use concordium_rust_sdk::cis2::{
     AdditionalData, OperatorUpdate, Receiver, TokenAmount, Transfer, UpdateOperator,
 };
 use concordium_rust_sdk::smart_contracts::common::{
-    AccountAddress, AccountSignatures, Address, Amount, ContractAddress, CredentialSignatures,
-    OwnedEntrypointName, Signature, SignatureEd25519,
+    schema::{Type, VersionedModuleSchema},
+    AccountAddress, Address, Amount, ContractAddress, Cursor, Deserial, OwnedEntrypointName,
+    PublicKeyEd25519, SignatureEd25519,
 };
-use concordium_rust_sdk::types::smart_contracts::{ContractContext, InvokeContractResult};
-use concordium_rust_sdk::types::{smart_contracts, transactions, Energy, WalletAccount};
+use concordium_rust_sdk::smart_contracts::engine::utils::get_embedded_schema_v1;
+use concordium_rust_sdk::types::hashes::TransactionHash;
+use concordium_rust_sdk::types::smart_contracts::{
+    ContractContext, InvokeContractResult, OwnedReceiveName,
+};
+use concordium_rust_sdk::types::transactions::ExactSizeTransactionSigner;
+use concordium_rust_sdk::types::{smart_contracts, Energy, RejectReason};
 use concordium_rust_sdk::v2::BlockIdentifier;
-use std::collections::BTreeMap;
+use sha2::{Digest, Sha256};
 use std::convert::Infallible;
+use std::future::Future;
+use std::net::IpAddr;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::broadcast::error::RecvError;
 use warp::{http::StatusCode, Rejection};
 
-const CONTRACT_NAME: &str = "cis3_nft";
-const ENERGY: u64 = 6000;
-const RATE_LIMIT_PER_ACCOUNT: u8 = 30;
-
 pub async fn handle_signature_update_operator(
-    client: concordium_rust_sdk::v2::Client,
-    key_update_operator: Arc<WalletAccount>,
     request: UpdateOperatorInputParams,
-    smart_contract_index: u64,
+    ip: Option<IpAddr>,
+    idempotency_key: Option<String>,
+    contract: ContractInstance,
     state: Server,
 ) -> Result<impl warp::Reply, Rejection> {
+    state
+        .metrics
+        .submissions
+        .with_label_values(&["submitUpdateOperator"])
+        .inc();
+
+    let message = update_operator_message(&request, &contract);
+
+    let rate_limit_keys = [
+        Some(RateLimitKey::Account(request.signer)),
+        ip.map(RateLimitKey::Ip),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    submit_transaction(
+        state,
+        message,
+        request.signature,
+        request.signer,
+        rate_limit_keys,
+        None,
+        idempotency_key,
+        contract,
+    )
+    .await
+}
+
+pub async fn handle_signature_transfer(
+    request: TransferInputParams,
+    ip: Option<IpAddr>,
+    idempotency_key: Option<String>,
+    contract: ContractInstance,
+    state: Server,
+) -> Result<impl warp::Reply, Rejection> {
+    state
+        .metrics
+        .submissions
+        .with_label_values(&["submitTransfer"])
+        .inc();
+
+    let message = transfer_message(&request, &contract)?;
+
+    let rate_limit_keys = [
+        Some(RateLimitKey::Account(request.signer)),
+        ip.map(RateLimitKey::Ip),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    submit_transaction(
+        state,
+        message,
+        request.signature,
+        request.signer,
+        rate_limit_keys,
+        None,
+        idempotency_key,
+        contract,
+    )
+    .await
+}
+
+/// The message adding or removing the operator of the request.
+fn update_operator_message(
+    request: &UpdateOperatorInputParams,
+    contract: &ContractInstance,
+) -> PermitMessage {
     log::debug!("Create payload.");
 
     let operator_update = match request.add_operator {
@@ -42,241 +128,1107 @@ pub async fn handle_signature_update_operator(
 
     log::debug!("Create PermitMessage.");
 
-    let message: PermitMessage = PermitMessage {
-        contract_address: ContractAddress {
-            index: smart_contract_index,
-            subindex: 0,
-        },
-        nonce: request.nonce,
-        timestamp: request.timestamp,
-        entry_point: OwnedEntrypointName::new_unchecked("updateOperator".into()),
-        payload: concordium_rust_sdk::smart_contracts::common::to_bytes(&payload),
+    PermitMessage::new(
+        contract.address,
+        request.nonce,
+        request.timestamp,
+        OwnedEntrypointName::new_unchecked("updateOperator".into()),
+        concordium_rust_sdk::smart_contracts::common::to_bytes(&payload),
+    )
+}
+
+/// The message transferring the token of the request.
+fn transfer_message(
+    request: &TransferInputParams,
+    contract: &ContractInstance,
+) -> Result<PermitMessage, LogError> {
+    log::debug!("Create payload.");
+
+    let transfer = Transfer {
+        from: Address::Account(request.from),
+        to: Receiver::Account(request.to),
+        token_id: request.token_id.clone(),
+        amount: TokenAmount::from_str("1").map_err(|_| LogError::TokenAmountError)?,
+        data: AdditionalData::new(vec![]).map_err(|_| LogError::AdditionalDataError)?,
     };
 
+    let payload = TransferParams(vec![transfer]);
+
+    log::debug!("Create PermitMessage.");
+
+    Ok(PermitMessage::new(
+        contract.address,
+        request.nonce,
+        request.timestamp,
+        OwnedEntrypointName::new_unchecked("transfer".into()),
+        concordium_rust_sdk::smart_contracts::common::to_bytes(&payload),
+    ))
+}
+
+pub async fn handle_signature_redeem(
+    request: RedeemInputParams,
+    ip: Option<IpAddr>,
+    idempotency_key: Option<String>,
+    contract: ContractInstance,
+    state: Server,
+) -> Result<impl warp::Reply, Rejection> {
+    state
+        .metrics
+        .submissions
+        .with_label_values(&["submitRedeem"])
+        .inc();
+
+    let (message, public_key) = redeem_message(&request, &contract)?;
+
+    let rate_limit_keys = [
+        Some(RateLimitKey::Account(request.signer)),
+        Some(RateLimitKey::CoinKey(public_key)),
+        ip.map(RateLimitKey::Ip),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
     submit_transaction(
-        client,
-        key_update_operator,
         state,
         message,
         request.signature,
         request.signer,
-        smart_contract_index,
+        rate_limit_keys,
+        Some(public_key),
+        idempotency_key,
+        contract,
     )
     .await
 }
 
-pub async fn handle_signature_transfer(
-    client: concordium_rust_sdk::v2::Client,
-    key_update_operator: Arc<WalletAccount>,
-    request: TransferInputParams,
-    smart_contract_index: u64,
+/// The message redeeming the coin of the request, and the public key of the
+/// coin.
+fn redeem_message(
+    request: &RedeemInputParams,
+    contract: &ContractInstance,
+) -> Result<(PermitMessage, PublicKeyEd25519), LogError> {
+    log::debug!("Create payload.");
+
+    let public_key =
+        PublicKeyEd25519::from_str(&request.public_key).map_err(|_| LogError::PublicKeyError)?;
+
+    let mut coin_signature = [0; 64];
+    hex::decode_to_slice(&request.coin_signature, &mut coin_signature)
+        .map_err(|_| LogError::SignatureError)?;
+    let coin_signature = SignatureEd25519(coin_signature);
+
+    // The contract would reject an invalid coin signature, but checking it
+    // here spares the simulation on the node.
+    signatures::verify_coin_signature(&public_key, &coin_signature, &request.signer)?;
+
+    // Coins issued in a Merkle batch are redeemed by their leaf in the tree.
+    let (entry_point, payload) = match &request.merkle_proof {
+        None => (
+            REDEEM_ENTRYPOINT,
+            concordium_rust_sdk::smart_contracts::common::to_bytes(&RedeemParams {
+                public_key,
+                signature: coin_signature,
+                account: request.signer,
+            }),
+        ),
+        Some(merkle_proof) => (
+            REDEEM_MERKLE_ENTRYPOINT,
+            concordium_rust_sdk::smart_contracts::common::to_bytes(&RedeemMerkleParams {
+                public_key,
+                signature: coin_signature,
+                account: request.signer,
+                amount: merkle_proof.amount,
+                root: decode_hash(&merkle_proof.root)?,
+                leaf_index: merkle_proof.leaf_index,
+                proof: merkle_proof
+                    .proof
+                    .iter()
+                    .map(|hash| decode_hash(hash))
+                    .collect::<Result<_, _>>()?,
+            }),
+        ),
+    };
+
+    log::debug!("Create PermitMessage.");
+
+    let message = PermitMessage::new(
+        contract.address,
+        request.nonce,
+        request.timestamp,
+        OwnedEntrypointName::new_unchecked(entry_point.into()),
+        payload,
+    );
+
+    Ok((message, public_key))
+}
+
+/// Decode a hex encoded hash of a Merkle proof.
+fn decode_hash(hash: &str) -> Result<[u8; 32], LogError> {
+    let mut bytes = [0; 32];
+    hex::decode_to_slice(hash, &mut bytes).map_err(|_| LogError::MerkleProofError)?;
+    Ok(bytes)
+}
+
+/// Simulate a submission like `submitUpdateOperator`, `submitTransfer`,
+/// `submitRedeem` or `permit` would, without sending it. Nonces and the rate
+/// limits of the signer and the coin are neither checked nor consumed, so
+/// support can replay the request of a user to find out why it fails.
+/// Simulations invoke the node, so they count against the rate limit of the
+/// IP address like submissions.
+pub async fn handle_simulate(
+    request: SimulateInputParams,
+    ip: Option<IpAddr>,
+    contract: ContractInstance,
     state: Server,
 ) -> Result<impl warp::Reply, Rejection> {
-    log::debug!("Create payload.");
+    if let Some(ip) = ip {
+        state
+            .rate_limiter
+            .check_and_record(&[RateLimitKey::Ip(ip)])
+            .inspect_err(|e| {
+                if let LogError::RateLimitError(key) = e {
+                    state.metrics.record_rate_limit_hit(key);
+                }
+            })?;
+    }
 
-    let transfer = Transfer {
-        from: Address::Account(request.from),
-        to: Receiver::Account(request.to),
-        token_id: request.token_id,
-        amount: TokenAmount::from_str("1").map_err(|_| LogError::TokenAmountError)?,
-        data: AdditionalData::new(vec![]).map_err(|_| LogError::AdditionalDataError)?,
+    let (message, signature, signer) = match &request {
+        SimulateInputParams::UpdateOperator(request) => (
+            update_operator_message(request, &contract),
+            &request.signature,
+            request.signer,
+        ),
+        SimulateInputParams::Transfer(request) => (
+            transfer_message(request, &contract)?,
+            &request.signature,
+            request.signer,
+        ),
+        SimulateInputParams::Redeem(request) => (
+            redeem_message(request, &contract)?.0,
+            &request.signature,
+            request.signer,
+        ),
+        SimulateInputParams::Permit(request) => (
+            permit_message(request, &contract, &state).await?.0,
+            &request.signature,
+            request.signer,
+        ),
     };
 
-    let payload = TransferParams(vec![transfer]);
+    let parameter = permit_parameter(&state, message, signature, signer).await?;
 
-    log::debug!("Create PermitMessage.");
+    let receive_name = contract.receive_name("permit")?;
 
-    let message: PermitMessage = PermitMessage {
-        contract_address: ContractAddress {
-            index: smart_contract_index,
-            subindex: 0,
-        },
-        nonce: request.nonce,
-        timestamp: request.timestamp,
-        entry_point: OwnedEntrypointName::new_unchecked("transfer".into()),
-        payload: concordium_rust_sdk::smart_contracts::common::to_bytes(&payload),
+    // Simulating does not spend funds, so an account excluded from new
+    // transactions is still good enough to invoke the contract.
+    let sponsor = state
+        .sponsors
+        .check()
+        .unwrap_or_else(|_| state.sponsors.accounts()[0].clone());
+
+    let header_energy = simulation::header_energy(
+        contract.address,
+        &receive_name,
+        &parameter,
+        sponsor.key.num_keys(),
+    );
+    let with_header = |energy: Energy| Energy {
+        energy: header_energy.energy.saturating_add(energy.energy),
+    };
+
+    let report = match simulate_permit(
+        &state,
+        &contract,
+        &receive_name,
+        &parameter,
+        sponsor.address(),
+    )
+    .await?
+    {
+        InvokeContractResult::Success {
+            events,
+            used_energy,
+            ..
+        } => {
+            let mut report = SimulationReport::success(
+                used_energy,
+                with_header(state.energy.estimate(used_energy)),
+                &contract,
+                &events,
+            );
+            // The sponsor pays for the energy the transaction uses, not for
+            // the energy it is given.
+            match state.nodes.get_chain_parameters().await {
+                Ok(parameters) => {
+                    report.estimated_fee = Some(parameters.ccd_cost(with_header(used_energy)))
+                }
+                Err(e) => {
+                    log::warn!("Could not get the chain parameters: {}", e);
+                    state
+                        .metrics
+                        .node_errors
+                        .with_label_values(&["get_chain_parameters"])
+                        .inc();
+                }
+            }
+            report
+        }
+        InvokeContractResult::Failure {
+            return_value,
+            reason,
+            used_energy,
+        } => SimulationReport::failure(
+            used_energy,
+            with_header(state.energy.estimate(used_energy)),
+            revert_reason(&contract, return_value, reason),
+        ),
     };
 
+    Ok(warp::reply::json(&report))
+}
+
+/// Sponsor a call of any entrypoint `permit` supports. The payload is
+/// serialized using the schema embedded in the contract module, so new
+/// entrypoints can be sponsored without changes to the backend.
+pub async fn handle_permit(
+    request: PermitInputParams,
+    ip: Option<IpAddr>,
+    idempotency_key: Option<String>,
+    contract: ContractInstance,
+    state: Server,
+) -> Result<impl warp::Reply, Rejection> {
+    state
+        .metrics
+        .submissions
+        .with_label_values(&["permit"])
+        .inc();
+
+    let (message, coin_key) = permit_message(&request, &contract, &state).await?;
+
+    let rate_limit_keys = [
+        Some(RateLimitKey::Account(request.signer)),
+        coin_key.map(RateLimitKey::CoinKey),
+        ip.map(RateLimitKey::Ip),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
     submit_transaction(
-        client,
-        key_update_operator,
         state,
         message,
         request.signature,
         request.signer,
-        smart_contract_index,
+        rate_limit_keys,
+        coin_key,
+        idempotency_key,
+        contract,
     )
     .await
 }
 
+/// The message calling the entrypoint of the request with its payload, and
+/// the coin key if the call redeems a coin.
+async fn permit_message(
+    request: &PermitInputParams,
+    contract: &ContractInstance,
+    state: &Server,
+) -> Result<(PermitMessage, Option<PublicKeyEd25519>), LogError> {
+    log::debug!("Create payload.");
+
+    let entry_point = OwnedEntrypointName::new(request.entry_point.clone()).map_err(|_| {
+        LogError::PermitPayloadError("The entry point is not a valid entrypoint name.".into())
+    })?;
+
+    let payload = contract.serialize_parameter(&entry_point, &request.payload)?;
+
+    let supported = contract
+        .supports_permit(&state.nodes, &entry_point)
+        .await
+        .inspect_err(|e| {
+            if let LogError::NodeAccess(_) = e {
+                state
+                    .metrics
+                    .node_errors
+                    .with_label_values(&["invoke_instance"])
+                    .inc();
+            }
+        })?;
+    if !supported {
+        return Err(LogError::EntrypointNotSupported(entry_point.to_string()));
+    }
+
+    // Redemptions are checked, rate limited and deduplicated by their coin
+    // like those of `submitRedeem`.
+    let coin_key = signatures::permit_coin_key(entry_point.as_entrypoint_name(), &payload)?;
+
+    log::debug!("Create PermitMessage.");
+
+    let message = PermitMessage::new(
+        contract.address,
+        request.nonce,
+        request.timestamp,
+        entry_point,
+        payload,
+    );
+
+    Ok((message, coin_key))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn submit_transaction(
-    mut client: concordium_rust_sdk::v2::Client,
-    key: Arc<WalletAccount>,
     state: Server,
     message: PermitMessage,
-    request_signature: String,
+    request_signature: RequestSignature,
     signer: AccountAddress,
-    smart_contract_index: u64,
+    rate_limit_keys: Vec<RateLimitKey>,
+    coin_key: Option<PublicKeyEd25519>,
+    idempotency_key: Option<String>,
+    contract: ContractInstance,
 ) -> Result<impl warp::Reply, Rejection> {
+    // The submission is tracked until it returns, so a shutdown waits for it
+    // instead of interrupting it between reserving the nonce and sending.
+    let _in_flight = state.drain.enter()?;
+
+    let request_time = chrono::Utc::now();
+    let entry_point = message.entry_point.to_string();
+    // The submission as recorded in the audit log.
+    let audit_record = |event| AuditRecord {
+        request_time: Some(request_time.to_rfc3339()),
+        contract: Some(contract.address.to_string()),
+        signer: Some(signer),
+        coin_key: coin_key.map(|key| key.to_string()),
+        entry_point: Some(entry_point.clone()),
+        ..AuditRecord::new(event)
+    };
+
+    // Requests are refused while sponsoring is paused, or if their signer
+    // account, coin or IP address is blocked through the admin API.
+    state.admin.check(&rate_limit_keys)?;
+
+    let record_rate_limit_hit = |e: &LogError| {
+        if let LogError::RateLimitError(key) = e {
+            state.metrics.record_rate_limit_hit(key);
+        }
+    };
+
+    // Keys that reached their limit are refused before the keys of the signer
+    // are queried from the node. Lookups of unknown signers count against the
+    // IP address, so changing the signer on every request does not give
+    // unthrottled access to the node.
+    state
+        .rate_limiter
+        .check(&rate_limit_keys)
+        .inspect_err(record_rate_limit_hit)?;
+    let parameter = match permit_parameter(&state, message, &request_signature, signer).await {
+        Err(LogError::UnknownSigner) => {
+            let ip_keys: Vec<RateLimitKey> = rate_limit_keys
+                .iter()
+                .filter(|key| matches!(key, RateLimitKey::Ip(_)))
+                .copied()
+                .collect();
+            state.rate_limiter.record(&ip_keys)?;
+            return Err(warp::reject::custom(LogError::UnknownSigner));
+        }
+        result => result?,
+    };
+
+    let receive_name = contract.receive_name("permit")?;
+
+    // Retries and double clicks get the job of the earlier submission, instead
+    // of a second transaction that would fail on-chain and still cost fees and
+    // a rate limit slot.
+    let idempotency_keys = IdempotencyKey::for_submission(
+        contract.address,
+        signer,
+        coin_key.as_ref(),
+        idempotency_key,
+    )?;
+    let _claim = match state
+        .outbox
+        .deduplicate(
+            &idempotency_keys,
+            parameter.as_ref(),
+            &state.tx_tracker,
+            |tx_hash| {
+                let nodes = &state.nodes;
+                async move { nodes.get_block_item_status(&tx_hash).await }
+            },
+        )
+        .await?
+    {
+        Submission::New(claim) => claim,
+        Submission::Duplicate(job) => {
+            log::debug!("Return the job {} of an earlier submission.", job.id);
+            return Ok(job_reply(&job)?);
+        }
+    };
+
+    // Refuse new transactions while no sponsor account can pay for them, e.g.,
+    // because all are low on funds, so they are not drained completely and
+    // transactions do not fail on submission.
+    let sponsor = state.sponsors.check()?;
+
+    let used_energy = match simulate_permit(
+        &state,
+        &contract,
+        &receive_name,
+        &parameter,
+        sponsor.address(),
+    )
+    .await?
+    {
+        InvokeContractResult::Success { used_energy, .. } => {
+            log::debug!("TransactionSimulationSuccess");
+            used_energy
+        }
+        InvokeContractResult::Failure {
+            return_value,
+            reason,
+            used_energy,
+        } => {
+            let revert_reason = revert_reason(&contract, return_value, reason);
+            log::error!("TransactionSimulationError {}.", revert_reason);
+            state.metrics.record_simulation_failure(&revert_reason);
+            state.audit.record(AuditRecord {
+                simulation: Some("rejected".into()),
+                used_energy: Some(used_energy.energy),
+                reason: Some(revert_reason.name()),
+                ..audit_record(AuditEvent::Rejected)
+            });
+            return Err(warp::reject::custom(LogError::TransactionSimulationError(
+                Box::new(revert_reason),
+            )));
+        }
+    };
+
+    // There should be rate limiting in place to prevent the sponsor wallet from being drained.
+    // Requests are limited per signer account, per coin key and per IP address within sliding
+    // time windows. Depending on the configured store, the rate limits are either transient and
+    // reset on server restart, or persisted in an on-disk database. Checking and recording is
+    // atomic, so parallel API requests cannot exceed the limits.
+
+    // On mainnet, a user can only create around 25 accounts per identity.
+    // In production, a user registration/authentication at the frontend can be added.
+    log::debug!("Check rate limit.");
+
+    state
+        .rate_limiter
+        .check_and_record(&rate_limit_keys)
+        .inspect_err(record_rate_limit_hit)?;
+
+    // The request is persisted in the outbox before the transaction is sent,
+    // so it is not lost if the node cannot be reached. In that case, the
+    // client gets the id of the queued job and the outbox retries sending it.
+    let job = state.outbox.create(
+        contract.address,
+        receive_name,
+        parameter.as_ref().to_vec(),
+        state.energy.estimate(used_energy),
+        idempotency_keys,
+    )?;
+
+    state.audit.record(AuditRecord {
+        job_id: Some(job.id),
+        simulation: Some("success".into()),
+        used_energy: Some(used_energy.energy),
+        energy: Some(job.energy.energy),
+        ..audit_record(AuditEvent::Accepted)
+    });
+
+    let job = state.outbox.attempt(&state, &contract, &job).await;
+
+    Ok(job_reply(&job)?)
+}
+
+/// The parameter of `permit` calling the message, after verifying the
+/// signature of the signer.
+async fn permit_parameter(
+    state: &Server,
+    message: PermitMessage,
+    request_signature: &RequestSignature,
+    signer: AccountAddress,
+) -> Result<smart_contracts::OwnedParameter, LogError> {
     log::debug!("Create signature map.");
 
-    let mut signature = [0; 64];
-    hex::decode_to_slice(request_signature, &mut signature)
-        .map_err(|_| LogError::SignatureError)?;
+    let signature = signatures::account_signatures(request_signature)?;
 
-    let mut inner_signature_map = BTreeMap::new();
-    inner_signature_map.insert(0, Signature::Ed25519(SignatureEd25519(signature)));
+    // Requests with invalid signatures are rejected before they reach the
+    // simulation. The keys of the signer are cached, so repeated invalid
+    // requests do not cost requests to the node.
+    log::debug!("Verify account signature.");
 
-    let mut signature_map = BTreeMap::new();
-    signature_map.insert(
-        0,
-        CredentialSignatures {
-            sigs: inner_signature_map,
-        },
-    );
+    let keys = state
+        .account_keys
+        .get(&state.nodes, &signer)
+        .await
+        .inspect_err(|e| {
+            if let LogError::NodeAccess(_) = e {
+                state
+                    .metrics
+                    .node_errors
+                    .with_label_values(&["get_account_info"])
+                    .inc();
+            }
+        })?;
+    signatures::verify_account_signature(
+        &keys,
+        &signatures::message_hash(&signer, &message),
+        &signature,
+    )?;
 
     log::debug!("Create Parameter.");
 
     let param: PermitParam = PermitParam {
         message,
-        signature: AccountSignatures {
-            sigs: signature_map,
-        },
+        signature,
         signer,
     };
 
     let bytes = concordium_rust_sdk::smart_contracts::common::to_bytes(&param);
 
-    let parameter =
-        smart_contracts::OwnedParameter::try_from(bytes).map_err(|_| LogError::ParameterError)?;
-
-    let receive_name =
-        smart_contracts::OwnedReceiveName::try_from(format!("{}.permit", CONTRACT_NAME))
-            .map_err(|_| LogError::OwnedReceiveNameError)?;
+    smart_contracts::OwnedParameter::try_from(bytes).map_err(|_| LogError::ParameterError)
+}
 
+/// Simulate the call of `permit` with the parameter, invoked by the sponsor
+/// account.
+async fn simulate_permit(
+    state: &Server,
+    contract: &ContractInstance,
+    receive_name: &OwnedReceiveName,
+    parameter: &smart_contracts::OwnedParameter,
+    sponsor: AccountAddress,
+) -> Result<InvokeContractResult, LogError> {
     log::debug!("Simulate transaction to check its validity.");
 
     let context = ContractContext {
-        invoker: Some(concordium_rust_sdk::types::Address::Account(key.address)),
-        contract: ContractAddress {
-            index: smart_contract_index,
-            subindex: 0,
-        },
+        invoker: Some(concordium_rust_sdk::types::Address::Account(sponsor)),
+        contract: contract.address,
         amount: Amount::zero(),
         method: receive_name.clone(),
         parameter: parameter.clone(),
-        energy: Energy { energy: ENERGY },
+        energy: state.energy.max_energy,
     };
 
-    let info = client
+    let timer = state.metrics.invoke_instance_duration.start_timer();
+    let info = state
+        .nodes
         .invoke_instance(&BlockIdentifier::Best, &context)
         .await;
+    timer.observe_duration();
 
-    if info.is_err() {
-        log::error!("SimulationInvokeError {:#?}.", info);
+    match info {
+        Ok(info) => Ok(info.response),
+        Err(e) => {
+            log::error!("SimulationInvokeError {:#?}.", e);
+
+            state
+                .metrics
+                .node_errors
+                .with_label_values(&["invoke_instance"])
+                .inc();
 
-        return Err(warp::reject::custom(LogError::SimulationInvokeError));
+            Err(LogError::SimulationInvokeError)
+        }
     }
+}
 
-    match &info.as_ref().unwrap().response {
-        InvokeContractResult::Success {
-            return_value: _,
-            events: _,
-            used_energy: _,
-        } => log::debug!("TransactionSimulationSuccess"),
-        InvokeContractResult::Failure {
-            return_value: _,
-            reason,
-            used_energy: _,
-        } => {
-            log::error!("TransactionSimulationError {:#?}.", info);
+/// The reason the contract rejected a simulated transaction, with the name of
+/// the contract error if the schema describes it.
+fn revert_reason(
+    contract: &ContractInstance,
+    return_value: Option<smart_contracts::ReturnValue>,
+    reason: RejectReason,
+) -> RevertReason {
+    let mut revert_reason = RevertReason {
+        reason,
+        return_value: return_value.map(|rv| rv.value),
+        code: None,
+    };
+    revert_reason.code = contract.decode_error(&revert_reason);
+    revert_reason
+}
 
-            return Err(warp::reject::custom(LogError::TransactionSimulationError(
-                RevertReason {
-                    reason: reason.clone(),
-                },
-            )));
-        }
+/// The response to a submission: the transaction hash once the transaction
+/// was sent, or the job while it is queued.
+fn job_reply(job: &Job) -> Result<warp::reply::WithStatus<warp::reply::Json>, LogError> {
+    match &job.status {
+        JobStatus::Submitted { tx_hash } => Ok(warp::reply::with_status(
+            warp::reply::json(&TxHash {
+                tx_hash: *tx_hash,
+                job_id: job.id,
+            }),
+            StatusCode::OK,
+        )),
+        JobStatus::Queued => Ok(warp::reply::with_status(
+            warp::reply::json(&JobView::from(job)),
+            StatusCode::ACCEPTED,
+        )),
+        JobStatus::Failed { .. } => Err(LogError::SubmitSponsoredTransactionError),
     }
+}
 
-    log::debug!("Create transaction.");
+/// Get the amount and redemption status of a coin by invoking the view
+/// functions of the smart contract, see [`view_coin_status`].
+pub async fn handle_coin_status(
+    public_key: String,
+    merkle_proof: Option<MerkleProofInput>,
+    contract: ContractInstance,
+    state: Server,
+) -> Result<impl warp::Reply, Rejection> {
+    let public_key =
+        PublicKeyEd25519::from_str(&public_key).map_err(|_| LogError::PublicKeyError)?;
 
-    let payload = transactions::Payload::Update {
-        payload: transactions::UpdateContractPayload {
-            amount: Amount::from_micro_ccd(0),
-            address: ContractAddress {
-                index: smart_contract_index,
-                subindex: 0,
-            },
-            receive_name,
-            message: parameter,
-        },
-    };
+    if let Some(status) = state.coin_cache.get(&(contract.address, public_key)) {
+        return Ok(warp::reply::json(&status));
+    }
 
-    // Transaction should expiry after one hour.
-    let transaction_expiry_seconds = chrono::Utc::now().timestamp() as u64 + 3600;
+    let status = view_coin_status(
+        public_key,
+        merkle_proof.as_ref(),
+        |entrypoint, parameter| invoke_view(&state, &contract, entrypoint, parameter),
+    )
+    .await?;
+    state
+        .coin_cache
+        .insert((contract.address, public_key), status);
 
-    // Get the current nonce for the backend wallet and lock it. This is necessary since it is possible that API requests come in parallel.
-    // The nonce is increased by 1 and its lock is released after the transaction is submitted to the blockchain.
-    let mut nonce = state.nonce.lock().await;
+    Ok(warp::reply::json(&status))
+}
 
-    // There should be rate limiting in place to prevent the sponsor wallet from being drained.
-    // We only allow up to RATE_LIMIT_PER_ACCOUNT API calls to this backend.
-    // The rate_limits are transient and are reset on server restart.
+/// Look up a coin with the view functions of the smart contract. A coin issued
+/// in a Merkle batch is looked up with `viewMerkleLeaf` if its proof is given,
+/// which is verified first. Otherwise the coin is looked up with `viewCoin`,
+/// and with `viewCoinHashed` by the commitment of its public key if it was
+/// issued in the hashed mode.
+///
+/// `invoke` invokes a view function with the parameter, returning its return
+/// value or `None` if the function rejected, which the view functions only do
+/// if the coin does not exist.
+async fn view_coin_status<F, Fut>(
+    public_key: PublicKeyEd25519,
+    merkle_proof: Option<&MerkleProofInput>,
+    invoke: F,
+) -> Result<CoinStatus, LogError>
+where
+    F: Fn(&'static str, smart_contracts::OwnedParameter) -> Fut,
+    Fut: Future<Output = Result<Option<Vec<u8>>, LogError>>,
+{
+    let status = |amount, is_redeemed| CoinStatus {
+        amount,
+        status: if is_redeemed {
+            CoinRedemption::Redeemed
+        } else {
+            CoinRedemption::Unredeemed
+        },
+    };
+    fn parse<T: Deserial>(return_value: &[u8]) -> Result<T, LogError> {
+        concordium_rust_sdk::smart_contracts::common::from_bytes(return_value).map_err(|e| {
+            log::error!("CoinViewError {:#?}.", e);
+            LogError::CoinViewError
+        })
+    }
 
-    // We only check the rate_limits after acquiring the nonce lock. If we do it before we don't
-    // have guarantees due to possible parallel API requests.
+    if let Some(merkle_proof) = merkle_proof {
+        let root = decode_hash(&merkle_proof.root)?;
+        if merkle_root(&public_key, merkle_proof)? != root {
+            return Err(LogError::CoinNotFound);
+        }
+        let parameter =
+            smart_contracts::OwnedParameter::from_serial(&(root, merkle_proof.leaf_index))
+                .map_err(|_| LogError::ParameterError)?;
+        let return_value = invoke("viewMerkleLeaf", parameter)
+            .await?
+            .ok_or(LogError::CoinNotFound)?;
+        let is_redeemed: bool = parse(&return_value)?;
+        return Ok(status(merkle_proof.amount, is_redeemed));
+    }
 
-    // On mainnet, a user can only create around 25 accounts per identity.
-    // In production, a user registration/authentication at the frontend can be added or a database that permanently
-    // keeps track of the rate_limits so that the server can be restarted and reload the rate_limit values from the database.
-    log::debug!("Check rate limit.");
+    let parameter = smart_contracts::OwnedParameter::from_serial(&public_key)
+        .map_err(|_| LogError::ParameterError)?;
+    let return_value = match invoke("viewCoin", parameter).await? {
+        Some(return_value) => return_value,
+        None => {
+            let commitment: [u8; 32] = Sha256::digest(public_key.0).into();
+            let parameter = smart_contracts::OwnedParameter::from_serial(&commitment)
+                .map_err(|_| LogError::ParameterError)?;
+            invoke("viewCoinHashed", parameter)
+                .await?
+                .ok_or(LogError::CoinNotFound)?
+        }
+    };
+    let coin: ViewCoinReturnData = parse(&return_value)?;
+    Ok(status(coin.amount, coin.is_redeemed))
+}
 
-    let mut rate_limits = state.rate_limits.lock().await;
+/// Invoke a view function of the contract instance with the parameter,
+/// returning its return value or `None` if it rejected.
+async fn invoke_view(
+    state: &Server,
+    contract: &ContractInstance,
+    entrypoint: &str,
+    parameter: smart_contracts::OwnedParameter,
+) -> Result<Option<Vec<u8>>, LogError> {
+    let context = ContractContext {
+        invoker: None,
+        contract: contract.address,
+        amount: Amount::zero(),
+        method: contract.receive_name(entrypoint)?,
+        parameter,
+        energy: state.energy.max_energy,
+    };
 
-    let limit = rate_limits.entry(signer).or_insert_with(|| 0u8);
+    let timer = state.metrics.invoke_instance_duration.start_timer();
+    let info = state
+        .nodes
+        .invoke_instance(&BlockIdentifier::Best, &context)
+        .await;
+    timer.observe_duration();
+    let info = info.map_err(|e| {
+        log::error!("CoinViewError {:#?}.", e);
+        state
+            .metrics
+            .node_errors
+            .with_label_values(&["invoke_instance"])
+            .inc();
+        LogError::CoinViewError
+    })?;
 
-    if *limit >= RATE_LIMIT_PER_ACCOUNT {
-        log::error!("Rate limit for account {:#?} reached.", signer);
+    match info.response {
+        InvokeContractResult::Success {
+            return_value: Some(return_value),
+            ..
+        } => Ok(Some(return_value.value)),
+        InvokeContractResult::Success {
+            return_value: None, ..
+        } => {
+            log::error!("CoinViewError: no return value.");
+            Err(LogError::CoinViewError)
+        }
+        InvokeContractResult::Failure { .. } => Ok(None),
+    }
+}
 
-        return Err(warp::reject::custom(LogError::RateLimitError));
+/// The root of the Merkle tree computed from the leaf of the coin and the
+/// proof, hashing leaves as `SHA-256(0x00 || public_key || amount)` and inner
+/// nodes as `SHA-256(0x01 || left || right)` like the smart contract.
+fn merkle_root(
+    public_key: &PublicKeyEd25519,
+    merkle_proof: &MerkleProofInput,
+) -> Result<[u8; 32], LogError> {
+    let mut node: [u8; 32] = Sha256::new()
+        .chain_update([0])
+        .chain_update(public_key.0)
+        .chain_update(merkle_proof.amount.micro_ccd.to_le_bytes())
+        .finalize()
+        .into();
+    let mut index = merkle_proof.leaf_index;
+    for sibling in &merkle_proof.proof {
+        let sibling = decode_hash(sibling)?;
+        let (left, right) = if index & 1 == 0 {
+            (node, sibling)
+        } else {
+            (sibling, node)
+        };
+        node = Sha256::new()
+            .chain_update([1])
+            .chain_update(left)
+            .chain_update(right)
+            .finalize()
+            .into();
+        index /= 2;
     }
+    Ok(node)
+}
 
-    *limit += 1;
+/// Get the status of a transaction submitted by the backend.
+pub async fn handle_tx_status(
+    tx_hash: TransactionHash,
+    state: Server,
+) -> Result<impl warp::Reply, Rejection> {
+    let status = state
+        .tx_tracker
+        .status(&tx_hash)
+        .ok_or(LogError::UnknownTransaction)?;
+    Ok(warp::reply::json(&status))
+}
 
-    let tx = transactions::send::make_and_sign_transaction(
-        &key.keys,
-        key.address,
-        *nonce,
-        TransactionTime {
-            seconds: transaction_expiry_seconds,
-        },
-        concordium_rust_sdk::types::transactions::send::GivenEnergy::Absolute(Energy {
-            energy: ENERGY,
-        }),
-        payload,
-    );
+/// Get the status of a job created by a submission.
+pub async fn handle_job_status(
+    job_id: uuid::Uuid,
+    state: Server,
+) -> Result<impl warp::Reply, Rejection> {
+    let job = state.outbox.job(&job_id).ok_or(LogError::UnknownJob)?;
+    Ok(warp::reply::json(&JobView::from(&job)))
+}
 
-    let bi = transactions::BlockItem::AccountTransaction(tx);
+/// Report that the process is up.
+pub async fn handle_health(started: Instant) -> Result<impl warp::Reply, Rejection> {
+    Ok(warp::reply::json(&HealthReport::new(started)))
+}
 
-    log::debug!("Submit transaction.");
+/// Report whether the backend can sponsor transactions. Responds with 503 if
+/// any of the checks failed.
+pub async fn handle_ready(
+    readiness: Arc<Readiness>,
+    state: Server,
+) -> Result<impl warp::Reply, Rejection> {
+    let report = readiness.check(&state.sponsors).await;
+    let code = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&report), code))
+}
 
-    match client.send_block_item(&bi).await {
-        Ok(hash) => {
-            *nonce = nonce.next();
+/// Report the balances of the sponsor accounts. Responds with 503 if no
+/// account can pay for new transactions.
+pub async fn handle_balance_health(state: Server) -> Result<impl warp::Reply, Rejection> {
+    let report = PoolReport::new(&state.sponsors);
+    let code = if report.available > 0 {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&report), code))
+}
 
-            Ok(warp::reply::json(&TxHash { tx_hash: hash }))
+/// Serve the metrics in the Prometheus text format. The gauges are updated
+/// from the current state on each scrape.
+pub async fn handle_metrics(state: Server) -> Result<impl warp::Reply, Rejection> {
+    for account in state.sponsors.accounts() {
+        let address = account.address().to_string();
+        if let Some(balance) = account.balance.report().balance {
+            state
+                .metrics
+                .sponsor_balance
+                .with_label_values(&[&address])
+                .set(balance.micro_ccd as f64 / 1_000_000.0);
         }
-        Err(e) => {
-            log::error!("SubmitSponsoredTransactionError {:#?}.", e);
+        state
+            .metrics
+            .nonce
+            .with_label_values(&[&address])
+            .set(account.nonce.current().nonce as i64);
+    }
+    Ok(warp::reply::with_header(
+        state.metrics.encode(),
+        "Content-Type",
+        prometheus::TEXT_FORMAT,
+    ))
+}
+
+/// Stream the status changes of a transaction submitted by the backend as
+/// server-sent events. The stream starts with the current status and ends
+/// once the status is final.
+pub async fn handle_tx_events(
+    tx_hash: TransactionHash,
+    state: Server,
+) -> Result<impl warp::Reply, Rejection> {
+    // Subscribe before getting the current status so no change is missed.
+    let updates = state.tx_tracker.subscribe();
+    let status = state
+        .tx_tracker
+        .status(&tx_hash)
+        .ok_or(LogError::UnknownTransaction)?;
 
-            Err(warp::reject::custom(
-                LogError::SubmitSponsoredTransactionError,
-            ))
+    // The state of the stream is the status to send next, if known, and the
+    // receiver of the updates. It is `None` once the final status was sent.
+    let tracker = state.tx_tracker;
+    let events = futures::stream::unfold(Some((Some(status), updates)), move |stream_state| {
+        let tracker = tracker.clone();
+        async move {
+            let (next, mut updates) = stream_state?;
+            let status = match next {
+                Some(status) => status,
+                None => loop {
+                    match updates.recv().await {
+                        Ok(update) if update.tx_hash == tx_hash => break update.status,
+                        Ok(_) => continue,
+                        // Some updates were missed, so continue with the current status.
+                        Err(RecvError::Lagged(_)) => break tracker.status(&tx_hash)?,
+                        Err(RecvError::Closed) => return None,
+                    }
+                },
+            };
+            let event = warp::sse::Event::default()
+                .event("status")
+                .json_data(&status);
+            let next_state = (!status.is_final()).then_some((None, updates));
+            Some((event, next_state))
         }
+    });
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
+/// Get the schema embedded in the module of the smart contract instance.
+pub async fn get_contract_schema(
+    mut client: concordium_rust_sdk::v2::Client,
+    address: ContractAddress,
+) -> anyhow::Result<VersionedModuleSchema> {
+    let instance_info = client
+        .get_instance_info(address, &BlockIdentifier::LastFinal)
+        .await?;
+
+    let module = client
+        .get_module_source(
+            &instance_info.response.source_module(),
+            &BlockIdentifier::LastFinal,
+        )
+        .await?;
+
+    get_embedded_schema_v1(module.response.source.as_ref())
+}
+
+/// Decode the contract error of a rejected transaction simulation into the
+/// name of the error variant, using the schema of the contract.
+///
+/// This returns `None` if the transaction was not rejected by the contract
+/// itself, or if the schema does not describe the error.
+pub fn decode_contract_error(
+    schema: &VersionedModuleSchema,
+    reason: &RevertReason,
+) -> Option<String> {
+    let RejectReason::RejectedReceive { receive_name, .. } = &reason.reason else {
+        return None;
+    };
+    let receive_name = receive_name.as_receive_name();
+    let error_schema = schema
+        .get_receive_error_schema(
+            receive_name.contract_name(),
+            receive_name.entrypoint_name().into(),
+        )
+        .ok()?;
+
+    let return_value = reason.return_value.as_ref()?;
+    let error = error_schema.to_json(&mut Cursor::new(return_value)).ok()?;
+
+    // Enums are represented as an object with the name of the variant as the
+    // only key.
+    match error {
+        serde_json::Value::Object(variant) if variant.len() == 1 => variant.keys().next().cloned(),
+        _ => None,
     }
 }
 
+/// Decode the reject code of a transaction rejected by the contract into the
+/// name of the error variant, using the schema of the contract.
+///
+/// Contract errors derived with `Reject` use the reject code `-(i + 1)` for
+/// the `i`-th variant of the error enum.
+pub fn decode_reject_code(schema: &VersionedModuleSchema, reason: &RejectReason) -> Option<String> {
+    let RejectReason::RejectedReceive {
+        reject_reason,
+        receive_name,
+        ..
+    } = reason
+    else {
+        return None;
+    };
+    let receive_name = receive_name.as_receive_name();
+    let error_schema = schema
+        .get_receive_error_schema(
+            receive_name.contract_name(),
+            receive_name.entrypoint_name().into(),
+        )
+        .ok()?;
+
+    let Type::Enum(variants) = error_schema else {
+        return None;
+    };
+    let index = usize::try_from(-(i64::from(*reject_reason) + 1)).ok()?;
+    variants.get(index).map(|(name, _)| name.clone())
+}
+
+/// Serve the settings changed through the admin API.
+pub async fn handle_admin_state(state: Server) -> Result<impl warp::Reply, Rejection> {
+    Ok(warp::reply::json(&state.admin.report(&state.rate_limiter)))
+}
+
+/// Pause or resume sponsoring. While paused, submissions are refused and the
+/// queued jobs are not sent.
+pub async fn handle_admin_pause(
+    paused: bool,
+    state: Server,
+) -> Result<impl warp::Reply, Rejection> {
+    state.admin.set_paused(paused)?;
+    handle_admin_state(state).await
+}
+
+/// Block or unblock the signer account or coin key of submissions.
+pub async fn handle_admin_block(
+    key: RateLimitKey,
+    blocked: bool,
+    state: Server,
+) -> Result<impl warp::Reply, Rejection> {
+    log::warn!(
+        "{} {:?} through the admin API.",
+        if blocked { "Block" } else { "Unblock" },
+        key
+    );
+    state.admin.set_blocked(key, blocked)?;
+    handle_admin_state(state).await
+}
+
+/// Block or unblock the coin with the hex encoded public key.
+pub async fn handle_admin_block_coin(
+    public_key: String,
+    blocked: bool,
+    state: Server,
+) -> Result<impl warp::Reply, Rejection> {
+    let public_key =
+        PublicKeyEd25519::from_str(&public_key).map_err(|_| LogError::PublicKeyError)?;
+    handle_admin_block(RateLimitKey::CoinKey(public_key), blocked, state).await
+}
+
+/// Serve the limit of the account and its usage in the current window.
+pub async fn handle_admin_rate_limit(
+    account: AccountAddress,
+    state: Server,
+) -> Result<impl warp::Reply, Rejection> {
+    let key = RateLimitKey::Account(account);
+    let (limit, custom) = state.rate_limiter.limit(&key);
+    Ok(warp::reply::json(&AccountRateLimitReport {
+        account,
+        limit: limit.into(),
+        custom,
+        used: state.rate_limiter.usage(&key)?,
+    }))
+}
+
+/// Replace the limit of the account, or restore the account limit if no limit
+/// is given.
+pub async fn handle_admin_set_rate_limit(
+    account: AccountAddress,
+    limit: Option<RateLimitView>,
+    state: Server,
+) -> Result<impl warp::Reply, Rejection> {
+    log::warn!(
+        "Set the rate limit of {} to {:?} through the admin API.",
+        account,
+        limit
+    );
+    state.admin.set_limit(
+        &state.rate_limiter,
+        RateLimitKey::Account(account),
+        limit.map(Into::into),
+    )?;
+    handle_admin_rate_limit(account, state).await
+}
+
+/// Discard the requests recorded for the account, so it can make the full
+/// number of requests again.
+pub async fn handle_admin_reset_rate_limit(
+    account: AccountAddress,
+    state: Server,
+) -> Result<impl warp::Reply, Rejection> {
+    log::warn!("Reset the rate limit of {} through the admin API.", account);
+    state.rate_limiter.reset(&RateLimitKey::Account(account))?;
+    handle_admin_rate_limit(account, state).await
+}
+
 pub async fn handle_rejection(err: Rejection) -> Result<impl warp::Reply, Infallible> {
     if err.is_not_found() {
         let code = StatusCode::NOT_FOUND;
@@ -291,6 +1243,13 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl warp::Reply, Infall
         let message = "Simulation invoke error.";
         Ok(mk_reply(message.into(), code))
     } else if let Some(LogError::TransactionSimulationError(e)) = err.find() {
+        if let Some(code) = e.code.clone() {
+            let msg = ContractErrorResponse { code };
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&msg),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
         let code = StatusCode::INTERNAL_SERVER_ERROR;
         let message = format!("Transaction simulation error. Your transaction would revert with the given input parameters: {}", e);
         Ok(mk_reply(message, code))
@@ -310,9 +1269,54 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl warp::Reply, Infall
         let code = StatusCode::BAD_REQUEST;
         let message = "Signature error.";
         Ok(mk_reply(message.into(), code))
-    } else if let Some(LogError::RateLimitError) = err.find() {
+    } else if let Some(LogError::SignatureMapError(reason)) = err.find() {
+        let code = StatusCode::BAD_REQUEST;
+        let message = format!("Invalid signature map: {}", reason);
+        Ok(mk_reply(message, code))
+    } else if let Some(LogError::PermitPayloadError(reason)) = err.find() {
+        let code = StatusCode::BAD_REQUEST;
+        let message = format!("Invalid permit payload: {}", reason);
+        Ok(mk_reply(message, code))
+    } else if let Some(LogError::EntrypointNotSupported(entry_point)) = err.find() {
+        let code = StatusCode::BAD_REQUEST;
+        let message = format!(
+            "The permit function of the contract does not support the entrypoint {}.",
+            entry_point
+        );
+        Ok(mk_reply(message, code))
+    } else if let Some(LogError::InvalidCoinSignature) = err.find() {
         let code = StatusCode::BAD_REQUEST;
-        let message = "Rate limit reached for the account. Use a different account.";
+        let message = "The coin signature is not a valid signature of the signer account address by the coin key.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::InvalidAccountSignature) = err.find() {
+        let code = StatusCode::BAD_REQUEST;
+        let message = "The signature is not a valid signature of the message by the keys of the signer account.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::UnknownSigner) = err.find() {
+        let code = StatusCode::BAD_REQUEST;
+        let message = "The signer account does not exist.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::PublicKeyError) = err.find() {
+        let code = StatusCode::BAD_REQUEST;
+        let message = "Public key error.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::MerkleProofError) = err.find() {
+        let code = StatusCode::BAD_REQUEST;
+        let message = "The hashes of the Merkle proof must be hex encoded 32-byte hashes.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::RateLimitError(key)) = err.find() {
+        let code = StatusCode::TOO_MANY_REQUESTS;
+        let message = match key {
+            RateLimitKey::Account(_) => {
+                "Rate limit reached for the account. Use a different account."
+            }
+            RateLimitKey::CoinKey(_) => "Rate limit reached for the coin. Try again later.",
+            RateLimitKey::Ip(_) => "Rate limit reached for your IP address. Try again later.",
+        };
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::RateLimitStoreError) = err.find() {
+        let code = StatusCode::INTERNAL_SERVER_ERROR;
+        let message = "Rate limit store error.";
         Ok(mk_reply(message.into(), code))
     } else if let Some(LogError::ParameterError) = err.find() {
         let code = StatusCode::BAD_REQUEST;
@@ -322,6 +1326,77 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl warp::Reply, Infall
         let code = StatusCode::BAD_REQUEST;
         let message = "AdditionalData error.";
         Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::CoinNotFound) = err.find() {
+        let code = StatusCode::NOT_FOUND;
+        let message = "Coin not found.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::CoinViewError) = err.find() {
+        let code = StatusCode::INTERNAL_SERVER_ERROR;
+        let message = "Coin view error.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::UnknownTransaction) = err.find() {
+        let code = StatusCode::NOT_FOUND;
+        let message = "Transaction not submitted by this backend or no longer tracked.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::InsufficientSponsorBalance) = err.find() {
+        let code = StatusCode::SERVICE_UNAVAILABLE;
+        let message = "The sponsor account is low on funds. Try again later.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::NoSponsorAvailable) = err.find() {
+        let code = StatusCode::SERVICE_UNAVAILABLE;
+        let message = "No sponsor account is available. Try again later.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::UnknownJob) = err.find() {
+        let code = StatusCode::NOT_FOUND;
+        let message = "Job not created by this backend or no longer kept.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::IdempotencyKeyError) = err.find() {
+        let code = StatusCode::BAD_REQUEST;
+        let message = "The idempotency key must consist of 1 to 255 printable ASCII characters.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::IdempotencyConflict) = err.find() {
+        let code = StatusCode::CONFLICT;
+        let message = "A submission with the same idempotency key is in progress.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::IdempotencyKeyReused) = err.find() {
+        let code = StatusCode::UNPROCESSABLE_ENTITY;
+        let message = "The idempotency key was used for a different request.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::OutboxError) = err.find() {
+        let code = StatusCode::INTERNAL_SERVER_ERROR;
+        let message = "The request could not be persisted.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::ShuttingDown) = err.find() {
+        let code = StatusCode::SERVICE_UNAVAILABLE;
+        let message = "The backend is shutting down. Try again later.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::SponsoringPaused) = err.find() {
+        let code = StatusCode::SERVICE_UNAVAILABLE;
+        let message = "Sponsoring is paused. Try again later.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::Blocked(key)) = err.find() {
+        let code = StatusCode::FORBIDDEN;
+        let message = match key {
+            RateLimitKey::Account(_) => "The signer account is blocked.",
+            RateLimitKey::CoinKey(_) => "The coin is blocked.",
+            RateLimitKey::Ip(_) => "Your IP address is blocked.",
+        };
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::AdminUnauthorized) = err.find() {
+        let code = StatusCode::UNAUTHORIZED;
+        let message = "Missing or invalid admin token.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::AdminStoreError) = err.find() {
+        let code = StatusCode::INTERNAL_SERVER_ERROR;
+        let message = "The admin settings could not be stored.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::Unauthorized) = err.find() {
+        let code = StatusCode::UNAUTHORIZED;
+        let message = "Missing or invalid API key or request signature.";
+        Ok(mk_reply(message.into(), code))
+    } else if let Some(LogError::ChallengeFailed(reason)) = err.find() {
+        let code = StatusCode::FORBIDDEN;
+        Ok(mk_reply(reason.clone(), code))
     } else if let Some(LogError::NonceQueryError) = err.find() {
         let code = StatusCode::BAD_REQUEST;
         let message = "Account info query error.";
@@ -329,6 +1404,7 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl warp::Reply, Infall
     } else if err
         .find::<warp::filters::body::BodyDeserializeError>()
         .is_some()
+        || matches!(err.find(), Some(LogError::MalformedBody))
     {
         let code = StatusCode::BAD_REQUEST;
         let message = "Malformed body.";
@@ -341,10 +1417,137 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl warp::Reply, Infall
 }
 
 /// Helper function to make the reply.
-fn mk_reply(message: String, code: StatusCode) -> impl warp::Reply {
+fn mk_reply(message: String, code: StatusCode) -> warp::reply::WithStatus<warp::reply::Json> {
     let msg = ErrorResponse {
         message,
         code: code.as_u16(),
     };
     warp::reply::with_status(warp::reply::json(&msg), code)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use concordium_rust_sdk::smart_contracts::common::to_bytes;
+
+    fn public_key(byte: u8) -> PublicKeyEd25519 {
+        PublicKeyEd25519([byte; 32])
+    }
+
+    fn sha256(data: &[&[u8]]) -> [u8; 32] {
+        data.iter()
+            .fold(Sha256::new(), |hasher, data| hasher.chain_update(data))
+            .finalize()
+            .into()
+    }
+
+    fn leaf(public_key: &PublicKeyEd25519, amount: Amount) -> [u8; 32] {
+        sha256(&[&[0], &public_key.0, &amount.micro_ccd.to_le_bytes()])
+    }
+
+    #[test]
+    fn test_simulate_accepts_all_submissions() {
+        let common = serde_json::json!({
+            "signer": "3kBx2h5Y2veb4hZgAJWPrr8RyQESKm5TjzF3ti1QQ4VSYLwK1G",
+            "nonce": 0,
+            "signature": "00",
+            "timestamp": "2030-08-08T05:15:00Z",
+        });
+        let request = |fields: serde_json::Value| {
+            let mut body = common.clone();
+            body.as_object_mut()
+                .unwrap()
+                .extend(fields.as_object().unwrap().clone());
+            serde_json::from_value::<SimulateInputParams>(body).unwrap()
+        };
+        let account = common["signer"].clone();
+
+        assert!(matches!(
+            request(serde_json::json!({"operator": account, "add_operator": true})),
+            SimulateInputParams::UpdateOperator(_)
+        ));
+        assert!(matches!(
+            request(serde_json::json!({"token_id": "00", "from": account, "to": account})),
+            SimulateInputParams::Transfer(_)
+        ));
+        assert!(matches!(
+            request(serde_json::json!({"public_key": "00", "coin_signature": "00"})),
+            SimulateInputParams::Redeem(_)
+        ));
+        assert!(matches!(
+            request(serde_json::json!({"entry_point": "redeem", "payload": {}})),
+            SimulateInputParams::Permit(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_hashed_coin_status() {
+        let commitment = sha256(&[&public_key(1).0]);
+        let invoke = |entrypoint: &'static str, parameter: smart_contracts::OwnedParameter| {
+            let return_value = match entrypoint {
+                "viewCoin" => None,
+                "viewCoinHashed" if parameter.as_ref() == commitment => {
+                    Some(to_bytes(&(Amount::from_micro_ccd(1000), true)))
+                }
+                _ => None,
+            };
+            std::future::ready(Ok(return_value))
+        };
+
+        let status = view_coin_status(public_key(1), None, invoke).await.unwrap();
+        assert_eq!(
+            status,
+            CoinStatus {
+                amount: Amount::from_micro_ccd(1000),
+                status: CoinRedemption::Redeemed,
+            }
+        );
+        assert!(matches!(
+            view_coin_status(public_key(2), None, invoke).await,
+            Err(LogError::CoinNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_merkle_coin_status() {
+        let amount = Amount::from_micro_ccd(1000);
+        let sibling = leaf(&public_key(2), amount);
+        let root = sha256(&[&[1], &sibling, &leaf(&public_key(1), amount)]);
+        let invoke = |entrypoint: &'static str, parameter: smart_contracts::OwnedParameter| {
+            let return_value = (entrypoint == "viewMerkleLeaf"
+                && parameter.as_ref() == to_bytes(&(root, 1u32)))
+            .then(|| to_bytes(&false));
+            std::future::ready(Ok(return_value))
+        };
+        let filter = warp::query::<MerkleProofQuery>();
+        let query = |amount: u64, leaf_index: u32| {
+            let path = format!(
+                "/?amount={}&root={}&leaf_index={}&proof={}",
+                amount,
+                hex::encode(root),
+                leaf_index,
+                hex::encode(sibling),
+            );
+            let query = warp::test::request().path(&path).filter(&filter);
+            MerkleProofInput::from(futures::executor::block_on(query).unwrap())
+        };
+
+        let status = view_coin_status(public_key(1), Some(&query(1000, 1)), invoke)
+            .await
+            .unwrap();
+        assert_eq!(
+            status,
+            CoinStatus {
+                amount,
+                status: CoinRedemption::Unredeemed,
+            }
+        );
+        // The amount and the position of the leaf are covered by the proof.
+        for proof in [query(2000, 1), query(1000, 0)] {
+            assert!(matches!(
+                view_coin_status(public_key(1), Some(&proof), invoke).await,
+                Err(LogError::CoinNotFound)
+            ));
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use crate::contract::ContractInstance;
+use crate::nodes::{NodeReport, Nodes};
+use crate::pool::SponsorPool;
+use concordium_rust_sdk::v2::BlockIdentifier;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// How long each readiness check may take before it is considered failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The response of `GET /health`.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthReport {
+    pub status: &'static str,
+    pub version: &'static str,
+    pub uptime_seconds: u64,
+}
+
+impl HealthReport {
+    pub fn new(started: Instant) -> Self {
+        Self {
+            status: "up",
+            version: env!("CARGO_PKG_VERSION"),
+            uptime_seconds: started.elapsed().as_secs(),
+        }
+    }
+}
+
+/// The result of a single readiness check.
+#[derive(serde::Serialize, Debug)]
+pub struct Check {
+    pub ok: bool,
+    /// Why the check failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn ok() -> Self {
+        Self {
+            ok: true,
+            error: None,
+        }
+    }
+
+    fn failed(error: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            error: Some(error.into()),
+        }
+    }
+}
+
+/// The response of `GET /ready`.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadinessReport {
+    /// Whether all checks passed.
+    pub ready: bool,
+    /// The time since the slot time of the last finalized block, if the node
+    /// could be queried.
+    pub consensus_lag_seconds: Option<i64>,
+    /// The nonce that will be used for the next sponsored transaction of each
+    /// sponsor account, by account.
+    pub next_nonces: BTreeMap<String, u64>,
+    /// The results of the checks, by name. The contract instances are checked
+    /// as `instance:PATH`, with the default instance as `instance:default`.
+    pub checks: BTreeMap<String, Check>,
+    /// The health of each node. A single unhealthy node does not fail the
+    /// readiness check, since requests fail over to the other nodes.
+    pub nodes: Vec<NodeReport>,
+}
+
+/// Checks whether the backend can sponsor transactions: the node is reachable
+/// and caught up, the nonces of the sponsor accounts are known, at least one
+/// sponsor account can pay for transactions and the contract instances can be
+/// reached.
+pub struct Readiness {
+    nodes: Nodes,
+    /// The contract instances, by the name of their check.
+    instances: Vec<(String, ContractInstance)>,
+    /// The maximum time since the slot time of the last finalized block for the
+    /// node to be considered caught up.
+    max_consensus_lag: Duration,
+}
+
+/// Check that the last finalized block is at most `max_lag` old.
+fn check_consensus_lag(lag: chrono::Duration, max_lag: Duration) -> Check {
+    match lag.to_std() {
+        Ok(lag) if lag > max_lag => Check::failed(format!(
+            "The last finalized block is {} seconds old, at most {} seconds are allowed.",
+            lag.as_secs(),
+            max_lag.as_secs()
+        )),
+        // A negative lag means the clocks are slightly out of sync.
+        _ => Check::ok(),
+    }
+}
+
+async fn with_timeout<T, E: std::fmt::Display>(
+    check: impl Future<Output = Result<T, E>>,
+) -> Result<T, String> {
+    match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(_) => Err("The node did not respond in time.".into()),
+    }
+}
+
+impl Readiness {
+    pub fn new(
+        nodes: Nodes,
+        instances: Vec<(String, ContractInstance)>,
+        max_consensus_lag: Duration,
+    ) -> Self {
+        Self {
+            nodes,
+            instances,
+            max_consensus_lag,
+        }
+    }
+
+    pub async fn check(&self, sponsors: &SponsorPool) -> ReadinessReport {
+        let mut checks = BTreeMap::new();
+
+        let client = self.nodes.client().map_err(|e| e.to_string());
+        let block_info = match client.clone() {
+            Ok(mut client) => {
+                with_timeout(client.get_block_info(&BlockIdentifier::LastFinal)).await
+            }
+            Err(e) => Err(e.clone()),
+        };
+        let consensus_lag = match block_info {
+            Ok(info) => {
+                checks.insert("node".into(), Check::ok());
+                let lag = chrono::Utc::now() - info.response.block_slot_time;
+                checks.insert(
+                    "consensus".into(),
+                    check_consensus_lag(lag, self.max_consensus_lag),
+                );
+                Some(lag.num_seconds())
+            }
+            Err(e) => {
+                checks.insert("node".into(), Check::failed(e));
+                checks.insert(
+                    "consensus".into(),
+                    Check::failed("The node is not reachable."),
+                );
+                None
+            }
+        };
+
+        let unsynced: Vec<String> = sponsors
+            .accounts()
+            .iter()
+            .filter(|account| !account.nonce.is_synced())
+            .map(|account| account.address().to_string())
+            .collect();
+        let nonce_check = if unsynced.is_empty() {
+            Check::ok()
+        } else {
+            Check::failed(format!(
+                "The nonces of the sponsor accounts {} are being resynchronized with the node.",
+                unsynced.join(", ")
+            ))
+        };
+        checks.insert("nonce".into(), nonce_check);
+
+        let sponsors_check = match sponsors.check() {
+            Ok(_) => Check::ok(),
+            Err(e) => Check::failed(e.to_string()),
+        };
+        checks.insert("sponsors".into(), sponsors_check);
+
+        for (name, instance) in &self.instances {
+            let result = match client.clone() {
+                Ok(mut client) => {
+                    with_timeout(
+                        client.get_instance_info(instance.address, &BlockIdentifier::LastFinal),
+                    )
+                    .await
+                }
+                Err(e) => Err(e.clone()),
+            };
+            let check = match result {
+                Ok(_) => Check::ok(),
+                Err(e) => Check::failed(e),
+            };
+            checks.insert(format!("instance:{}", name), check);
+        }
+
+        ReadinessReport {
+            ready: checks.values().all(|check| check.ok),
+            consensus_lag_seconds: consensus_lag,
+            next_nonces: sponsors
+                .accounts()
+                .iter()
+                .map(|account| (account.address().to_string(), account.nonce.current().nonce))
+                .collect(),
+            checks,
+            nodes: self.nodes.reports(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_consensus_lag() {
+        let max_lag = Duration::from_secs(60);
+        assert!(check_consensus_lag(chrono::Duration::seconds(10), max_lag).ok);
+        assert!(check_consensus_lag(chrono::Duration::seconds(60), max_lag).ok);
+        assert!(!check_consensus_lag(chrono::Duration::seconds(61), max_lag).ok);
+        assert!(check_consensus_lag(chrono::Duration::seconds(-2), max_lag).ok);
+    }
+}
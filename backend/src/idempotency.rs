@@ -0,0 +1,148 @@
+use crate::types::LogError;
+use concordium_rust_sdk::smart_contracts::common::{
+    AccountAddress, ContractAddress, PublicKeyEd25519,
+};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// The header carrying the idempotency key chosen by the client.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// The maximum length of an idempotency key chosen by the client.
+const MAX_CLIENT_KEY_LENGTH: usize = 255;
+
+/// Identifies submissions that must not result in more than one transaction.
+///
+/// Redemptions of a coin by a signer are identified by the coin and the signer,
+/// so retries of the frontend and double clicks are recognized without
+/// cooperation of the client. In addition, a client can choose a key in the
+/// [`IDEMPOTENCY_KEY_HEADER`], which is scoped to the signer, so clients
+/// cannot interfere with the submissions of other accounts.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum IdempotencyKey {
+    Coin {
+        contract: ContractAddress,
+        coin_key: String,
+        signer: AccountAddress,
+    },
+    Client {
+        contract: ContractAddress,
+        signer: AccountAddress,
+        key: String,
+    },
+}
+
+impl IdempotencyKey {
+    /// The keys identifying a submission to the contract by the signer.
+    pub fn for_submission(
+        contract: ContractAddress,
+        signer: AccountAddress,
+        coin_key: Option<&PublicKeyEd25519>,
+        client_key: Option<String>,
+    ) -> Result<Vec<Self>, LogError> {
+        let mut keys = Vec::new();
+        if let Some(coin_key) = coin_key {
+            keys.push(Self::Coin {
+                contract,
+                coin_key: coin_key.to_string(),
+                signer,
+            });
+        }
+        if let Some(key) = client_key {
+            if key.is_empty()
+                || key.len() > MAX_CLIENT_KEY_LENGTH
+                || !key.bytes().all(|b| b.is_ascii_graphic())
+            {
+                return Err(LogError::IdempotencyKeyError);
+            }
+            keys.push(Self::Client {
+                contract,
+                signer,
+                key,
+            });
+        }
+        Ok(keys)
+    }
+
+    /// Whether the key was chosen by the client. A client key must not be
+    /// reused for a different request, while the request redeeming a coin may
+    /// be signed again, e.g., after the page was reloaded.
+    pub fn is_client(&self) -> bool {
+        matches!(self, Self::Client { .. })
+    }
+}
+
+/// The idempotency keys of the submissions that are being processed, but
+/// for which no job was created yet.
+#[derive(Default)]
+pub struct Claims {
+    claimed: Mutex<HashSet<IdempotencyKey>>,
+}
+
+/// Marks the keys of a submission as being processed until it is dropped.
+pub struct Claim<'a> {
+    claims: &'a Claims,
+    keys: Vec<IdempotencyKey>,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        let mut claimed = self.claims.claimed.lock().unwrap();
+        for key in &self.keys {
+            claimed.remove(key);
+        }
+    }
+}
+
+impl Claims {
+    /// Claim the keys, unless another submission with any of them is being
+    /// processed.
+    pub fn claim(&self, keys: &[IdempotencyKey]) -> Result<Claim<'_>, LogError> {
+        let mut claimed = self.claimed.lock().unwrap();
+        if keys.iter().any(|key| claimed.contains(key)) {
+            return Err(LogError::IdempotencyConflict);
+        }
+        claimed.extend(keys.iter().cloned());
+        Ok(Claim {
+            claims: self,
+            keys: keys.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_and_claims() {
+        let contract = ContractAddress::new(4184, 0);
+        let signer = AccountAddress([1; 32]);
+        let coin_key = PublicKeyEd25519([2; 32]);
+
+        let keys = IdempotencyKey::for_submission(
+            contract,
+            signer,
+            Some(&coin_key),
+            Some("order-1".into()),
+        )
+        .unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(!keys[0].is_client());
+        assert!(keys[1].is_client());
+        assert!(
+            IdempotencyKey::for_submission(contract, signer, None, Some("a b".into())).is_err()
+        );
+        assert!(IdempotencyKey::for_submission(contract, signer, None, Some("".into())).is_err());
+
+        let claims = Claims::default();
+        let claim = claims.claim(&keys).unwrap();
+        assert!(matches!(
+            claims.claim(&keys[1..]),
+            Err(LogError::IdempotencyConflict)
+        ));
+        drop(claim);
+        assert!(claims.claim(&keys[1..]).is_ok());
+    }
+}
@@ -1,30 +1,105 @@
+mod admin;
+mod audit;
+mod auth;
+mod balance;
+mod coin_cache;
+mod config;
+mod contract;
 mod handlers;
+mod health;
+mod idempotency;
+mod metrics;
+mod nodes;
+mod nonce;
+mod outbox;
+mod pool;
+mod rate_limit;
+mod shutdown;
+mod signatures;
+mod simulation;
+mod startup;
+mod tx_status;
 mod types;
+use crate::admin::{Admin, RateLimitView};
+use crate::audit::{AuditCommand, AuditLog};
+use crate::auth::*;
+use crate::balance::{BalanceMonitor, BalanceThresholds};
+use crate::coin_cache::CoinCache;
+use crate::contract::*;
 use crate::handlers::*;
+use crate::health::Readiness;
+use crate::idempotency::IDEMPOTENCY_KEY_HEADER;
+use crate::metrics::Metrics;
+use crate::nodes::Nodes;
+use crate::nonce::NonceManager;
+use crate::outbox::{Backoff, Outbox};
+use crate::pool::{ErrorPolicy, SelectionStrategy, SponsorAccount, SponsorPool};
+use crate::rate_limit::*;
+use crate::shutdown::{shutdown_signal, Drain};
+use crate::signatures::AccountKeysCache;
+use crate::tx_status::TxTracker;
 use crate::types::*;
 use anyhow::Context;
-use clap::Parser;
 use concordium_rust_sdk::common::{self as crypto_common};
-use concordium_rust_sdk::types::WalletAccount;
-use std::collections::HashMap;
+use concordium_rust_sdk::smart_contracts::common::{AccountAddress, Amount, ContractAddress};
+use concordium_rust_sdk::types::hashes::TransactionHash;
+use concordium_rust_sdk::types::{Energy, WalletAccount};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tonic::transport::ClientTlsConfig;
+use std::time::{Duration, Instant};
+use tonic::transport::{Certificate, ClientTlsConfig};
+use uuid::Uuid;
 use warp::http;
 use warp::Filter;
 
+/// Commands that are run instead of starting the server.
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Work on the audit log, e.g., `audit verify audit.jsonl`.
+    #[clap(subcommand)]
+    Audit(AuditCommand),
+}
+
 /// Structure used to receive the correct command line arguments.
 #[derive(clap::Parser, Debug)]
-#[clap(arg_required_else_help(true))]
-#[clap(version, author)]
+#[clap(version, author, subcommand_negates_reqs = true)]
 struct IdVerifierConfig {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(
+        long = "config",
+        help = "Path to a TOML configuration file. Its keys are the long names of the options, \
+                e.g., `port = 8100`. Options given on the command line or as environment \
+                variables take precedence over the file."
+    )]
+    config: Option<PathBuf>,
+    #[clap(
+        long = "print-config",
+        help = "Print the effective configuration in the format of the configuration file, with \
+                secrets redacted, and exit."
+    )]
+    print_config: bool,
     #[clap(
         long = "node",
-        help = "GRPC V2 interface of the node.",
+        help = "GRPC V2 interface of the node. Can be given multiple times to distribute the \
+                requests across several nodes and fail over if a node is unavailable.",
         default_value = "http://localhost:20000"
     )]
-    endpoint: concordium_rust_sdk::v2::Endpoint,
+    endpoints: Vec<concordium_rust_sdk::v2::Endpoint>,
+    #[clap(
+        long = "node-health-interval",
+        default_value = "10",
+        help = "Interval in seconds at which the health of the nodes is checked."
+    )]
+    node_health_interval: u64,
+    #[clap(
+        long = "node-ca-certificate",
+        help = "Path to a PEM encoded CA certificate to verify the TLS certificate of the node \
+                with, instead of the system trust roots. Only used if the node URL is `https`."
+    )]
+    node_ca_certificate: Option<PathBuf>,
     #[clap(
         long = "port",
         default_value = "8100",
@@ -37,6 +112,46 @@ struct IdVerifierConfig {
         help = "The smart contract index which the sponsored transaction is submitted to."
     )]
     smart_contract_index: u64,
+    #[clap(
+        long = "smart-contract-subindex",
+        default_value = "0",
+        help = "The subindex of the smart contract which the sponsored transaction is submitted to."
+    )]
+    smart_contract_subindex: u64,
+    #[clap(
+        long = "contract-name",
+        default_value = "ccd_redeem",
+        value_parser = parse_contract_name,
+        help = "The name of the smart contract, without the `init_` prefix."
+    )]
+    contract_name: String,
+    #[clap(
+        long = "instance",
+        help = "An additional smart contract instance to serve, given as \
+                `PATH=CONTRACT_NAME@INDEX[,SUBINDEX]`. Its endpoints are served under \
+                `/api/PATH/`, e.g., `/api/PATH/submitRedeem`. Can be given multiple times."
+    )]
+    instances: Vec<InstanceArg>,
+    #[clap(
+        long = "max-energy",
+        default_value = "20000",
+        help = "The energy available to the simulation of a sponsored transaction, and the \
+                maximum energy given to the transaction."
+    )]
+    max_energy: u64,
+    #[clap(
+        long = "energy-margin",
+        default_value = "20",
+        help = "The margin in percent added to the energy used by the simulation of a sponsored \
+                transaction to get the energy given to the transaction."
+    )]
+    energy_margin: u64,
+    #[clap(
+        long = "min-sponsor-balance",
+        default_value = "1",
+        help = "The minimum balance in CCD the sponsor account must have for the backend to start."
+    )]
+    min_sponsor_balance: Amount,
     #[structopt(
         long = "log-level",
         default_value = "debug",
@@ -49,101 +164,862 @@ struct IdVerifierConfig {
         help = "location of the folder to serve"
     )]
     public_folder: String,
-    #[structopt(long = "account", help = "Path to the account key file.")]
-    keys_path: PathBuf,
+    #[clap(
+        long = "account",
+        required = true,
+        help = "Path to the key file of a sponsor account. Can be given multiple times to \
+                distribute the sponsored transactions across several accounts."
+    )]
+    keys_paths: Vec<PathBuf>,
+    #[clap(
+        long = "sponsor-selection",
+        value_enum,
+        default_value = "least-pending",
+        help = "How the sponsor account of a transaction is chosen."
+    )]
+    sponsor_selection: SelectionStrategy,
+    #[clap(
+        long = "sponsor-error-threshold",
+        default_value = "3",
+        help = "The number of consecutive transactions of a sponsor account refused by the node \
+                after which the account is excluded."
+    )]
+    sponsor_error_threshold: u32,
+    #[clap(
+        long = "sponsor-error-cooldown",
+        default_value = "300",
+        help = "How long in seconds a sponsor account is excluded after repeated errors."
+    )]
+    sponsor_error_cooldown: u64,
+    #[clap(
+        long = "rate-limit-store",
+        value_enum,
+        default_value = "memory",
+        help = "Where to store the rate limits. With `memory` the rate limits are reset on \
+                server restart, with `sled` they are persisted in the database at `--db-path`."
+    )]
+    rate_limit_store: RateLimitStoreKind,
+    #[clap(
+        long = "db-path",
+        default_value = "sponsor-db",
+        help = "Path to the embedded database used for persistent state, such as the \
+                transactions still pending on shutdown."
+    )]
+    db_path: PathBuf,
+    #[clap(
+        long = "account-rate-limit",
+        default_value = "30",
+        help = "Maximum number of sponsored transactions per signer account within the window. \
+                Set to 0 to disable."
+    )]
+    account_rate_limit: u32,
+    #[clap(
+        long = "account-rate-limit-window",
+        default_value = "86400",
+        help = "Length of the sliding window of the account rate limit, in seconds."
+    )]
+    account_rate_limit_window: u64,
+    #[clap(
+        long = "coin-rate-limit",
+        default_value = "5",
+        help = "Maximum number of sponsored transactions per coin key within the window. Set to \
+                0 to disable."
+    )]
+    coin_rate_limit: u32,
+    #[clap(
+        long = "coin-rate-limit-window",
+        default_value = "3600",
+        help = "Length of the sliding window of the coin rate limit, in seconds."
+    )]
+    coin_rate_limit_window: u64,
+    #[clap(
+        long = "ip-rate-limit",
+        default_value = "100",
+        help = "Maximum number of sponsored transactions per IP address within the window. Set \
+                to 0 to disable."
+    )]
+    ip_rate_limit: u32,
+    #[clap(
+        long = "ip-rate-limit-window",
+        default_value = "3600",
+        help = "Length of the sliding window of the IP address rate limit, in seconds."
+    )]
+    ip_rate_limit_window: u64,
+    #[clap(
+        long = "max-in-flight-transactions",
+        default_value = "1",
+        help = "Maximum number of sponsored transactions per sponsor account that are \
+                submitted concurrently, each with the next nonce. With 1, the transactions of an \
+                account are submitted one after another."
+    )]
+    max_in_flight_transactions: usize,
+    #[clap(
+        long = "nonce-resync-interval",
+        default_value = "60",
+        help = "Interval in seconds at which the nonces of the sponsor accounts are compared \
+                with the ones known to the node. Set to 0 to disable."
+    )]
+    nonce_resync_interval: u64,
+    #[clap(
+        long = "coin-cache-ttl",
+        default_value = "10",
+        help = "How long the status of an unredeemed coin is cached, in seconds. The status of \
+                redeemed coins is cached indefinitely."
+    )]
+    coin_cache_ttl: u64,
+    #[clap(
+        long = "account-keys-cache-ttl",
+        default_value = "300",
+        help = "How long the keys of a signer account are cached to verify its signatures \
+                before the simulation, in seconds. Updated keys are used once the cached ones \
+                expire."
+    )]
+    account_keys_cache_ttl: u64,
+    #[clap(
+        long = "balance-check-interval",
+        default_value = "60",
+        help = "Interval in seconds at which the balance of the sponsor account is checked."
+    )]
+    balance_check_interval: u64,
+    #[clap(
+        long = "low-balance-watermark",
+        default_value = "10",
+        help = "Balance in CCD below which no new sponsored transactions are submitted, until \
+                the sponsor account is topped up."
+    )]
+    low_balance_watermark: Amount,
+    #[clap(
+        long = "balance-alert-threshold",
+        default_value = "100",
+        help = "Balance in CCD below which an alert is logged and sent to the alert webhook."
+    )]
+    balance_alert_threshold: Amount,
+    #[clap(
+        long = "alert-webhook",
+        help = "URL the balance alerts are posted to as JSON. If not given, alerts are only \
+                logged."
+    )]
+    alert_webhook: Option<reqwest::Url>,
+    #[clap(
+        long = "max-consensus-lag",
+        default_value = "60",
+        help = "Maximum age in seconds of the last finalized block for the node to be considered \
+                caught up by `/ready`."
+    )]
+    max_consensus_lag: u64,
+    #[clap(
+        long = "allowed-origin",
+        required = true,
+        value_parser = parse_origin,
+        help = "An origin allowed to make requests from browsers, e.g., \
+                `https://redeem.example.com`. Can be given multiple times. Requests from other \
+                origins are refused. The origin of the frontend served from `--public-folder` \
+                must be given as well."
+    )]
+    allowed_origins: Vec<String>,
+    #[clap(
+        long = "api-key",
+        help = "An API key that authenticates requests to the submission endpoints in the \
+                `X-Api-Key` header. Can be given multiple times, e.g., one per partner frontend."
+    )]
+    api_keys: Vec<String>,
+    #[clap(
+        long = "hmac-secret",
+        help = "A secret that authenticates requests to the submission endpoints signed with \
+                HMAC-SHA256 in the `X-Timestamp` and `X-Signature` headers. Can be given \
+                multiple times."
+    )]
+    hmac_secrets: Vec<String>,
+    #[clap(
+        long = "hmac-max-skew",
+        default_value = "300",
+        help = "Maximum difference in seconds between the timestamp of a signed request and the \
+                current time."
+    )]
+    hmac_max_skew: u64,
+    #[clap(
+        long = "trusted-proxy",
+        help = "The IP address of a reverse proxy in front of the server. For requests from a \
+                trusted proxy, the client IP address used for rate limiting and the challenge is \
+                taken from the `X-Forwarded-For` or `Forwarded` header. Can be given multiple \
+                times. If not given, the remote address of the connection is used."
+    )]
+    trusted_proxies: Vec<IpAddr>,
+    #[clap(
+        long = "admin-token",
+        help = "A token that authenticates requests to the admin API in the `Authorization: \
+                Bearer` header. Can be given multiple times. If not given, the admin API is \
+                disabled."
+    )]
+    admin_tokens: Vec<String>,
+    #[clap(
+        long = "audit-log",
+        help = "Path to the append-only audit log of the sponsored transactions, written as \
+                JSON lines forming a hash chain. If not given, no audit log is written."
+    )]
+    audit_log: Option<PathBuf>,
+    #[clap(
+        long = "challenge",
+        value_enum,
+        default_value = "none",
+        help = "The challenge clients have to solve before a sponsored transaction is submitted. \
+                The token is sent in the `X-Challenge-Token` header."
+    )]
+    challenge: ChallengeKind,
+    #[clap(
+        long = "captcha-verify-url",
+        default_value = "https://api.hcaptcha.com/siteverify",
+        help = "The `siteverify` endpoint of the CAPTCHA provider, used with `--challenge \
+                captcha`."
+    )]
+    captcha_verify_url: reqwest::Url,
+    #[clap(
+        long = "captcha-secret",
+        help = "The secret key of the CAPTCHA provider, required with `--challenge captcha`."
+    )]
+    captcha_secret: Option<String>,
+    #[clap(
+        long = "pow-difficulty",
+        default_value = "20",
+        help = "The number of leading zero bits of the proof of work, used with `--challenge \
+                proof-of-work`."
+    )]
+    pow_difficulty: u32,
+    #[clap(
+        long = "shutdown-timeout",
+        default_value = "30",
+        help = "How long in seconds to wait for in-flight submissions to finish on shutdown."
+    )]
+    shutdown_timeout: u64,
+    #[clap(
+        long = "max-submission-attempts",
+        default_value = "10",
+        help = "How often sending a sponsored transaction is attempted before its job fails."
+    )]
+    max_submission_attempts: u32,
+    #[clap(
+        long = "retry-initial-backoff",
+        default_value = "2",
+        help = "How long in seconds to wait before the first retry of a failed send. The delay \
+                doubles with every further attempt."
+    )]
+    retry_initial_backoff: u64,
+    #[clap(
+        long = "retry-max-backoff",
+        default_value = "300",
+        help = "The maximum delay in seconds between retries of a failed send."
+    )]
+    retry_max_backoff: u64,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let app = IdVerifierConfig::parse();
+    let started = Instant::now();
+    let app: IdVerifierConfig = config::parse()?;
+    // `audit verify` and `audit export` work on the audit log without starting
+    // the server.
+    if let Some(Command::Audit(command)) = app.command {
+        return audit::run(command);
+    }
     let mut log_builder = env_logger::Builder::new();
     // only log the current module (main).
     log_builder.filter_level(app.log_level); // filter filter_module(module_path!(), app.log_level);
     log_builder.init();
 
-    let endpoint = if app
-        .endpoint
-        .uri()
-        .scheme()
-        .map_or(false, |x| x == &http::uri::Scheme::HTTPS)
-    {
-        app.endpoint.tls_config(ClientTlsConfig::new())?
-    } else {
-        app.endpoint
-    };
+    let ca_certificate = app
+        .node_ca_certificate
+        .as_ref()
+        .map(|path| {
+            std::fs::read(path)
+                .with_context(|| format!("Could not read the CA certificate {}.", path.display()))
+        })
+        .transpose()?;
+
+    let mut endpoints = Vec::with_capacity(app.endpoints.len());
+    for endpoint in app.endpoints {
+        let endpoint = if endpoint
+            .uri()
+            .scheme()
+            .is_some_and(|x| x == &http::uri::Scheme::HTTPS)
+        {
+            let mut tls_config = ClientTlsConfig::new();
+            if let Some(pem) = &ca_certificate {
+                tls_config = tls_config.ca_certificate(Certificate::from_pem(pem));
+            }
+            endpoint.tls_config(tls_config)?
+        } else {
+            anyhow::ensure!(
+                ca_certificate.is_none(),
+                "A CA certificate is given, but the node URL {} is not `https`.",
+                endpoint.uri()
+            );
+            endpoint
+        };
+        endpoints.push(endpoint);
+    }
 
-    let mut client_update_operator = concordium_rust_sdk::v2::Client::new(endpoint).await?;
+    let nodes = Nodes::connect(endpoints).await?;
 
-    let client_transfer = client_update_operator.clone();
+    tokio::spawn(
+        nodes
+            .clone()
+            .run_health_checks(Duration::from_secs(app.node_health_interval)),
+    );
 
     let cors = warp::cors()
-        .allow_any_origin()
-        .allow_header("Content-Type")
+        .allow_headers(vec![
+            "Content-Type",
+            API_KEY_HEADER,
+            TIMESTAMP_HEADER,
+            SIGNATURE_HEADER,
+            CHALLENGE_HEADER,
+            IDEMPOTENCY_KEY_HEADER,
+        ])
         .allow_methods(vec!["POST", "GET"]);
+    let cors = cors.allow_origins(app.allowed_origins.iter().map(String::as_str));
+
+    let challenge: Option<Box<dyn ChallengeVerifier>> = match app.challenge {
+        ChallengeKind::None => None,
+        ChallengeKind::Captcha => Some(Box::new(CaptchaVerifier::new(
+            app.captcha_verify_url,
+            app.captcha_secret
+                .context("--captcha-secret is required with --challenge captcha.")?,
+        ))),
+        ChallengeKind::ProofOfWork => Some(Box::new(ProofOfWork {
+            difficulty: app.pow_difficulty,
+        })),
+    };
+
+    let guard = Arc::new(RequestGuard::new(
+        app.api_keys,
+        app.hmac_secrets,
+        Duration::from_secs(app.hmac_max_skew),
+        challenge,
+        app.trusted_proxies,
+    ));
 
     log::debug!("Acquire keys.");
 
-    // load account keys and sender address from a file
-    let keys: WalletAccount = serde_json::from_str(
-        &std::fs::read_to_string(app.keys_path).context("Could not read the keys file.")?,
+    // load account keys and sender addresses from the files, each account with its own nonce
+    let mut sponsor_keys: Vec<(WalletAccount, Arc<NonceManager>)> =
+        Vec::with_capacity(app.keys_paths.len());
+    for keys_path in &app.keys_paths {
+        let keys: WalletAccount =
+            serde_json::from_str(&std::fs::read_to_string(keys_path).with_context(|| {
+                format!("Could not read the keys file {}.", keys_path.display())
+            })?)
+            .with_context(|| format!("Could not parse the keys file {}.", keys_path.display()))?;
+
+        if sponsor_keys
+            .iter()
+            .any(|(other, _)| other.address == keys.address)
+        {
+            anyhow::bail!(
+                "The sponsor account {} is given more than once.",
+                keys.address
+            );
+        }
+
+        log::debug!("Acquire nonce of wallet account {}.", keys.address);
+
+        let nonce_manager = Arc::new(
+            NonceManager::new(nodes.clone(), keys.address, app.max_in_flight_transactions).await?,
+        );
+
+        log::debug!(
+            "Next nonce of wallet account {} is {}.",
+            keys.address,
+            nonce_manager.current()
+        );
+
+        if app.nonce_resync_interval > 0 {
+            tokio::spawn(
+                nonce_manager
+                    .clone()
+                    .run_drift_detection(Duration::from_secs(app.nonce_resync_interval)),
+            );
+        }
+
+        sponsor_keys.push((keys, nonce_manager));
+    }
+
+    log::debug!("Open the database.");
+
+    let db = sled::open(&app.db_path).context("Could not open the database.")?;
+
+    let rate_limit_store: Box<dyn RateLimitStore> = match app.rate_limit_store {
+        RateLimitStoreKind::Memory => Box::<MemoryRateLimitStore>::default(),
+        RateLimitStoreKind::Sled => Box::new(SledRateLimitStore::new(db.open_tree("rate_limits")?)),
+    };
+
+    let rate_limits = RateLimits {
+        account: RateLimit {
+            max_requests: app.account_rate_limit,
+            window: Duration::from_secs(app.account_rate_limit_window),
+        },
+        coin_key: RateLimit {
+            max_requests: app.coin_rate_limit,
+            window: Duration::from_secs(app.coin_rate_limit_window),
+        },
+        ip: RateLimit {
+            max_requests: app.ip_rate_limit,
+            window: Duration::from_secs(app.ip_rate_limit_window),
+        },
+    };
+
+    let rate_limiter = Arc::new(RateLimiter::new(rate_limit_store, rate_limits));
+
+    // The settings changed through the admin API are persisted regardless of
+    // the rate limit store.
+    let admin = Arc::new(
+        Admin::load(db.open_tree("admin")?, &rate_limiter)
+            .context("Could not load the admin settings.")?,
+    );
+    if app.admin_tokens.is_empty() {
+        log::info!("The admin API is disabled. Use --admin-token to enable it.");
+    }
+
+    log::debug!("Acquire schemas of the smart contracts.");
+
+    let default_instance = ContractInstance::load(
+        nodes.client()?,
+        app.contract_name,
+        ContractAddress {
+            index: app.smart_contract_index,
+            subindex: app.smart_contract_subindex,
+        },
     )
-    .context("Could not parse the keys file.")?;
+    .await;
 
-    let key_update_operator = Arc::new(keys);
+    let mut instances = BTreeMap::new();
+    for instance in app.instances {
+        let contract =
+            ContractInstance::load(nodes.client()?, instance.name, instance.address).await;
+        if instances.insert(instance.path.clone(), contract).is_some() {
+            anyhow::bail!(
+                "The instance path {:?} is given more than once.",
+                instance.path
+            );
+        }
+    }
 
-    let key_transfer = key_update_operator.clone();
+    log::debug!("Validate the smart contract instances and the sponsor account.");
 
-    log::debug!("Acquire nonce of wallet account.");
+    for contract in std::iter::once(&default_instance).chain(instances.values()) {
+        startup::check_contract_instance(&nodes, contract).await?;
+    }
 
-    let nonce_response = client_update_operator
-        .get_next_account_sequence_number(&key_update_operator.address)
-        .await
-        .map_err(|e| {
-            log::warn!("NonceQueryError {:#?}.", e);
-            LogError::NonceQueryError
-        })?;
+    let mut sponsor_accounts = Vec::with_capacity(sponsor_keys.len());
+    for (keys, nonce_manager) in sponsor_keys {
+        startup::check_sponsor_balance(nodes.client()?, keys.address, app.min_sponsor_balance)
+            .await?;
+
+        let balance_monitor = Arc::new(BalanceMonitor::new(
+            keys.address,
+            BalanceThresholds {
+                low_watermark: app.low_balance_watermark,
+                alert: app.balance_alert_threshold,
+            },
+            app.alert_webhook.clone(),
+        ));
+
+        tokio::spawn(balance_monitor.clone().run(
+            nodes.clone(),
+            Duration::from_secs(app.balance_check_interval),
+        ));
+
+        sponsor_accounts.push(Arc::new(SponsorAccount::new(
+            keys,
+            nonce_manager,
+            balance_monitor,
+            ErrorPolicy {
+                threshold: app.sponsor_error_threshold.max(1),
+                cooldown: Duration::from_secs(app.sponsor_error_cooldown),
+            },
+        )));
+    }
+
+    log::info!(
+        "Sponsoring transactions from {} accounts.",
+        sponsor_accounts.len()
+    );
+
+    let sponsors = Arc::new(SponsorPool::new(sponsor_accounts, app.sponsor_selection));
+
+    let readiness = Arc::new(Readiness::new(
+        nodes.clone(),
+        std::iter::once(("default".to_string(), default_instance.clone()))
+            .chain(
+                instances
+                    .iter()
+                    .map(|(path, instance)| (path.clone(), instance.clone())),
+            )
+            .collect(),
+        Duration::from_secs(app.max_consensus_lag),
+    ));
+
+    let tx_tracker = Arc::new(TxTracker::new());
+
+    // Transactions that were still pending when the backend was last shut down
+    // are tracked again, so their final status can be reported.
+    let pending_transactions = db.open_tree("pending_transactions")?;
+    let loaded = tx_tracker
+        .load_pending(
+            &pending_transactions,
+            &std::iter::once(&default_instance)
+                .chain(instances.values())
+                .collect::<Vec<_>>(),
+        )
+        .context("Could not load the pending transactions.")?;
+    if loaded > 0 {
+        log::info!(
+            "Tracking {} transactions pending since the last shutdown.",
+            loaded
+        );
+    }
+
+    // Submissions that could not be sent yet are retried by the outbox, also
+    // across restarts.
+    let outbox = Arc::new(
+        Outbox::open(
+            db.open_tree("outbox")?,
+            app.max_submission_attempts,
+            Backoff {
+                initial: Duration::from_secs(app.retry_initial_backoff),
+                max: Duration::from_secs(app.retry_max_backoff),
+            },
+        )
+        .context("Could not load the outbox.")?,
+    );
+    let all_instances: Vec<ContractInstance> = std::iter::once(&default_instance)
+        .chain(instances.values())
+        .cloned()
+        .collect();
+
+    let instances = Arc::new(instances);
+
+    // Requests to `/api/PATH/...` are served by the instance registered for
+    // `PATH`, all other requests to `/api/...` by the default instance.
+    let instance = warp::path("api").and(
+        warp::path::param::<String>()
+            .and_then(move |path: String| {
+                let instance = instances.get(&path).cloned();
+                async move { instance.ok_or_else(warp::reject::not_found) }
+            })
+            .or(warp::any().map(move || default_instance.clone()))
+            .unify(),
+    );
+
+    tokio::spawn(tx_tracker.clone().run(nodes.clone()));
+
+    // The costs are recorded for every account, since the transactions of all
+    // accounts cost about the same.
+    for account in sponsors.accounts() {
+        tokio::spawn(account.balance.clone().track_costs(tx_tracker.subscribe()));
+    }
+
+    let audit = Arc::new(match &app.audit_log {
+        Some(path) => AuditLog::open(path)
+            .with_context(|| format!("Could not open the audit log {}.", path.display()))?,
+        None => AuditLog::disabled(),
+    });
+    tokio::spawn(audit.clone().record_outcomes(tx_tracker.outcomes()));
+
+    let drain = Arc::new(Drain::default());
 
     let state_update_operator = Server {
-        nonce: Arc::new(Mutex::new(nonce_response.nonce)),
-        rate_limits: Arc::new(Mutex::new(HashMap::new())),
+        nodes,
+        sponsors,
+        rate_limiter,
+        tx_tracker: tx_tracker.clone(),
+        coin_cache: Arc::new(CoinCache::new(Duration::from_secs(app.coin_cache_ttl))),
+        account_keys: Arc::new(AccountKeysCache::new(Duration::from_secs(
+            app.account_keys_cache_ttl,
+        ))),
+        metrics: Arc::new(Metrics::new().context("Could not create the metrics.")?),
+        drain: drain.clone(),
+        outbox: outbox.clone(),
+        admin,
+        audit: audit.clone(),
+        energy: EnergyConfig {
+            max_energy: Energy {
+                energy: app.max_energy,
+            },
+            margin_percent: app.energy_margin,
+        },
     };
 
+    tokio::spawn(outbox.run(state_update_operator.clone(), all_instances));
+
     let state_transfer = state_update_operator.clone();
 
+    let state_redeem = state_update_operator.clone();
+
+    let state_permit = state_update_operator.clone();
+
+    let state_simulate = state_update_operator.clone();
+
+    let state_coin_status = state_update_operator.clone();
+
+    let state_tx_status = state_update_operator.clone();
+
+    let state_tx_events = state_update_operator.clone();
+
+    let state_job_status = state_update_operator.clone();
+
+    let state_balance_health = state_update_operator.clone();
+
+    let state_metrics = state_update_operator.clone();
+
+    let state_ready = state_update_operator.clone();
+
+    let state_admin = state_update_operator.clone();
+
     // 1. Provide submit update operator
     let provide_submit_update_operator = warp::post()
         .and(warp::filters::body::content_length_limit(50 * 1024))
-        .and(warp::path!("api" / "submitUpdateOperator"))
-        .and(warp::body::json())
-        .and_then(move |request: UpdateOperatorInputParams| {
-            log::debug!("Process update operator transaction.");
-
-            handle_signature_update_operator(
-                client_update_operator.clone(),
-                key_update_operator.clone(),
-                request,
-                app.smart_contract_index,
-                state_update_operator.clone(),
-            )
-        });
+        .and(instance.clone())
+        .and(warp::path!("submitUpdateOperator"))
+        .and(guarded_json(guard.clone()))
+        .and(warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER))
+        .and_then(
+            move |contract: ContractInstance,
+                  request: UpdateOperatorInputParams,
+                  ip: Option<IpAddr>,
+                  idempotency_key: Option<String>| {
+                log::debug!("Process update operator transaction.");
+
+                handle_signature_update_operator(
+                    request,
+                    ip,
+                    idempotency_key,
+                    contract,
+                    state_update_operator.clone(),
+                )
+            },
+        );
 
     // 2. Provide submit transfer
     let provide_submit_transfer = warp::post()
         .and(warp::filters::body::content_length_limit(50 * 1024))
-        .and(warp::path!("api" / "submitTransfer"))
+        .and(instance.clone())
+        .and(warp::path!("submitTransfer"))
+        .and(guarded_json(guard.clone()))
+        .and(warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER))
+        .and_then(
+            move |contract: ContractInstance,
+                  request: TransferInputParams,
+                  ip: Option<IpAddr>,
+                  idempotency_key: Option<String>| {
+                log::debug!("Process transfer transaction.");
+
+                handle_signature_transfer(
+                    request,
+                    ip,
+                    idempotency_key,
+                    contract,
+                    state_transfer.clone(),
+                )
+            },
+        );
+
+    // 3. Provide submit redeem
+    let provide_submit_redeem = warp::post()
+        .and(warp::filters::body::content_length_limit(50 * 1024))
+        .and(instance.clone())
+        .and(warp::path!("submitRedeem"))
+        .and(guarded_json(guard.clone()))
+        .and(warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER))
+        .and_then(
+            move |contract: ContractInstance,
+                  request: RedeemInputParams,
+                  ip: Option<IpAddr>,
+                  idempotency_key: Option<String>| {
+                log::debug!("Process redeem transaction.");
+
+                handle_signature_redeem(
+                    request,
+                    ip,
+                    idempotency_key,
+                    contract,
+                    state_redeem.clone(),
+                )
+            },
+        );
+
+    // 4. Provide generic permit
+    let provide_permit = warp::post()
+        .and(warp::filters::body::content_length_limit(50 * 1024))
+        .and(instance.clone())
+        .and(warp::path!("permit"))
+        .and(guarded_json(guard.clone()))
+        .and(warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER))
+        .and_then(
+            move |contract: ContractInstance,
+                  request: PermitInputParams,
+                  ip: Option<IpAddr>,
+                  idempotency_key: Option<String>| {
+                log::debug!("Process permit transaction.");
+
+                handle_permit(request, ip, idempotency_key, contract, state_permit.clone())
+            },
+        );
+
+    // 5. Provide simulation
+    let provide_simulate = warp::post()
+        .and(warp::filters::body::content_length_limit(50 * 1024))
+        .and(instance.clone())
+        .and(warp::path!("simulate"))
+        .and(guarded_json(guard.clone()))
+        .and_then(
+            move |contract: ContractInstance, request: SimulateInputParams, ip: Option<IpAddr>| {
+                log::debug!("Simulate transaction.");
+
+                handle_simulate(request, ip, contract, state_simulate.clone())
+            },
+        );
+
+    // 6. Provide coin status
+    let provide_coin_status = warp::get()
+        .and(instance)
+        .and(warp::path!("coin" / String))
+        .and(
+            warp::query::<MerkleProofQuery>()
+                .map(|query: MerkleProofQuery| Some(query.into()))
+                .or(warp::any().map(|| None))
+                .unify(),
+        )
+        .and_then(
+            move |contract: ContractInstance,
+                  public_key: String,
+                  merkle_proof: Option<MerkleProofInput>| {
+                handle_coin_status(
+                    public_key,
+                    merkle_proof,
+                    contract,
+                    state_coin_status.clone(),
+                )
+            },
+        );
+
+    // 7. Provide transaction status
+    let provide_tx_status = warp::get()
+        .and(warp::path!("api" / "tx" / TransactionHash))
+        .and_then(move |tx_hash: TransactionHash| {
+            handle_tx_status(tx_hash, state_tx_status.clone())
+        });
+
+    // 8. Provide transaction status events
+    let provide_tx_events = warp::get()
+        .and(warp::path!("api" / "tx" / TransactionHash / "events"))
+        .and_then(move |tx_hash: TransactionHash| {
+            handle_tx_events(tx_hash, state_tx_events.clone())
+        });
+
+    // 9. Provide job status
+    let provide_job_status = warp::get()
+        .and(warp::path!("api" / "job" / Uuid))
+        .and_then(move |job_id: Uuid| handle_job_status(job_id, state_job_status.clone()));
+
+    // 10. Provide sponsor balance health
+    let provide_balance_health = warp::get()
+        .and(warp::path!("health" / "balance"))
+        .and_then(move || handle_balance_health(state_balance_health.clone()));
+
+    // 11. Provide metrics
+    let provide_metrics = warp::get()
+        .and(warp::path!("metrics"))
+        .and_then(move || handle_metrics(state_metrics.clone()));
+
+    // 12. Provide liveness
+    let provide_health = warp::get()
+        .and(warp::path!("health"))
+        .and_then(move || handle_health(started));
+
+    // 13. Provide readiness
+    let provide_ready = warp::get()
+        .and(warp::path!("ready"))
+        .and_then(move || handle_ready(readiness.clone(), state_ready.clone()));
+
+    // 14. Provide admin API
+    let admin = warp::path("admin").and(admin_auth(Arc::new(app.admin_tokens)));
+    let with_state = warp::any().map(move || state_admin.clone());
+    // Blocking and replacing a limit is done with PUT, undoing it with DELETE.
+    let put_or_delete = warp::put()
+        .map(|| true)
+        .or(warp::delete().map(|| false))
+        .unify();
+
+    let provide_admin_state = warp::get()
+        .and(admin.clone())
+        .and(warp::path!("state"))
+        .and(with_state.clone())
+        .and_then(handle_admin_state);
+
+    let provide_admin_pause = warp::post()
+        .and(admin.clone())
+        .and(
+            warp::path!("pause")
+                .map(|| true)
+                .or(warp::path!("resume").map(|| false))
+                .unify(),
+        )
+        .and(with_state.clone())
+        .and_then(handle_admin_pause);
+
+    let provide_admin_block_account = admin
+        .clone()
+        .and(warp::path!("blocklist" / "account" / AccountAddress))
+        .and(put_or_delete)
+        .and(with_state.clone())
+        .and_then(|account, blocked, state| {
+            handle_admin_block(RateLimitKey::Account(account), blocked, state)
+        });
+
+    let provide_admin_block_coin = admin
+        .clone()
+        .and(warp::path!("blocklist" / "coin" / String))
+        .and(put_or_delete)
+        .and(with_state.clone())
+        .and_then(handle_admin_block_coin);
+
+    let provide_admin_rate_limit = warp::get()
+        .and(admin.clone())
+        .and(warp::path!("rate-limits" / AccountAddress))
+        .and(with_state.clone())
+        .and_then(handle_admin_rate_limit);
+
+    let provide_admin_set_rate_limit = warp::put()
+        .and(admin.clone())
+        .and(warp::path!("rate-limits" / AccountAddress))
+        .and(warp::filters::body::content_length_limit(1024))
         .and(warp::body::json())
-        .and_then(move |request: TransferInputParams| {
-            log::debug!("Process transfer transaction.");
-
-            handle_signature_transfer(
-                client_transfer.clone(),
-                key_transfer.clone(),
-                request,
-                app.smart_contract_index,
-                state_transfer.clone(),
-            )
+        .and(with_state.clone())
+        .and_then(|account, limit: RateLimitView, state| {
+            handle_admin_set_rate_limit(account, Some(limit), state)
         });
 
+    let provide_admin_remove_rate_limit = warp::delete()
+        .and(admin.clone())
+        .and(warp::path!("rate-limits" / AccountAddress))
+        .and(with_state.clone())
+        .and_then(|account, state| handle_admin_set_rate_limit(account, None, state));
+
+    let provide_admin_reset_rate_limit = warp::post()
+        .and(admin)
+        .and(warp::path!("rate-limits" / AccountAddress / "reset"))
+        .and(with_state)
+        .and_then(handle_admin_reset_rate_limit);
+
+    let provide_admin = provide_admin_state
+        .or(provide_admin_pause)
+        .or(provide_admin_block_account)
+        .or(provide_admin_block_coin)
+        .or(provide_admin_rate_limit)
+        .or(provide_admin_set_rate_limit)
+        .or(provide_admin_remove_rate_limit)
+        .or(provide_admin_reset_rate_limit);
+
     log::debug!("Get public files to serve.");
 
     let serve_public_files = warp::get().and(warp::fs::dir(app.public_folder));
@@ -152,10 +1028,57 @@ async fn main() -> anyhow::Result<()> {
 
     let server = provide_submit_update_operator
         .or(provide_submit_transfer)
+        .or(provide_submit_redeem)
+        .or(provide_permit)
+        .or(provide_simulate)
+        .or(provide_coin_status)
+        .or(provide_tx_status)
+        .or(provide_tx_events)
+        .or(provide_job_status)
+        .or(provide_balance_health)
+        .or(provide_metrics)
+        .or(provide_health)
+        .or(provide_ready)
+        .or(provide_admin)
         .or(serve_public_files)
         .recover(handle_rejection)
         .with(cors)
         .with(warp::trace::request());
-    warp::serve(server).run(([0, 0, 0, 0], app.port)).await;
+
+    // On shutdown, the server stops accepting connections and new submissions
+    // are refused. Submissions in flight are given time to finish, so that no
+    // nonce is reserved without the transaction being sent. Open connections,
+    // such as event streams, are closed when the process exits.
+    let (stop_server, server_stopped) = tokio::sync::oneshot::channel::<()>();
+    let (addr, server) = warp::serve(server)
+        .try_bind_with_graceful_shutdown(([0, 0, 0, 0], app.port), async {
+            server_stopped.await.ok();
+        })
+        .context("Could not bind the server.")?;
+    log::info!("Listening on {}.", addr);
+    tokio::spawn(server);
+
+    shutdown_signal().await;
+
+    log::info!("Shutting down.");
+    drain.close();
+    let _ = stop_server.send(());
+    let shutdown_timeout = Duration::from_secs(app.shutdown_timeout);
+    if tokio::time::timeout(shutdown_timeout, drain.wait())
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "{} submissions did not finish within the shutdown timeout.",
+            drain.in_flight()
+        );
+    }
+
+    let saved = tx_tracker
+        .save_pending(&pending_transactions)
+        .context("Could not save the pending transactions.")?;
+    log::info!("Saved {} pending transactions.", saved);
+    audit.close();
+    db.flush().context("Could not flush the database.")?;
     Ok(())
 }
@@ -0,0 +1,158 @@
+use crate::rate_limit::RateLimitKey;
+use crate::types::RevertReason;
+use prometheus::{
+    Encoder, GaugeVec, Histogram, HistogramOpts, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+/// The metrics of the backend, exposed in the Prometheus text format by
+/// `GET /metrics`.
+pub struct Metrics {
+    registry: Registry,
+    /// Requests to the submission endpoints, by endpoint.
+    pub submissions: IntCounterVec,
+    /// Rejected transaction simulations, by reject reason.
+    pub simulation_failures: IntCounterVec,
+    /// Requests refused by the rate limiter, by the kind of the limited key.
+    pub rate_limit_hits: IntCounterVec,
+    /// Failed requests to the node, by operation.
+    pub node_errors: IntCounterVec,
+    pub invoke_instance_duration: Histogram,
+    pub send_block_item_duration: Histogram,
+    /// The available balance of the sponsor accounts in CCD, by account.
+    pub sponsor_balance: GaugeVec,
+    /// The next nonce of the sponsor accounts, by account.
+    pub nonce: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("sponsor".into()), None)?;
+        let submissions = IntCounterVec::new(
+            Opts::new("submissions_total", "Requests to the submission endpoints."),
+            &["endpoint"],
+        )?;
+        let simulation_failures = IntCounterVec::new(
+            Opts::new(
+                "simulation_failures_total",
+                "Rejected simulations of sponsored transactions.",
+            ),
+            &["reason"],
+        )?;
+        let rate_limit_hits = IntCounterVec::new(
+            Opts::new(
+                "rate_limit_hits_total",
+                "Sponsored transactions refused by the rate limiter.",
+            ),
+            &["key"],
+        )?;
+        let node_errors = IntCounterVec::new(
+            Opts::new("node_errors_total", "Failed requests to the node."),
+            &["operation"],
+        )?;
+        let invoke_instance_duration = Histogram::with_opts(HistogramOpts::new(
+            "invoke_instance_duration_seconds",
+            "Latency of invoking contract instances on the node.",
+        ))?;
+        let send_block_item_duration = Histogram::with_opts(HistogramOpts::new(
+            "send_block_item_duration_seconds",
+            "Latency of submitting transactions to the node.",
+        ))?;
+        let sponsor_balance = GaugeVec::new(
+            Opts::new(
+                "balance_ccd",
+                "Available balance of the sponsor accounts in CCD.",
+            ),
+            &["account"],
+        )?;
+        let nonce = IntGaugeVec::new(
+            Opts::new("account_nonce", "Next nonce of the sponsor accounts."),
+            &["account"],
+        )?;
+
+        registry.register(Box::new(submissions.clone()))?;
+        registry.register(Box::new(simulation_failures.clone()))?;
+        registry.register(Box::new(rate_limit_hits.clone()))?;
+        registry.register(Box::new(node_errors.clone()))?;
+        registry.register(Box::new(invoke_instance_duration.clone()))?;
+        registry.register(Box::new(send_block_item_duration.clone()))?;
+        registry.register(Box::new(sponsor_balance.clone()))?;
+        registry.register(Box::new(nonce.clone()))?;
+
+        Ok(Self {
+            registry,
+            submissions,
+            simulation_failures,
+            rate_limit_hits,
+            node_errors,
+            invoke_instance_duration,
+            send_block_item_duration,
+            sponsor_balance,
+            nonce,
+        })
+    }
+
+    /// Count a rejected simulation. The reason is the name of the contract
+    /// error if it was decoded, otherwise the kind of the reject reason.
+    pub fn record_simulation_failure(&self, reason: &RevertReason) {
+        self.simulation_failures
+            .with_label_values(&[&reason.name()])
+            .inc();
+    }
+
+    pub fn record_rate_limit_hit(&self, key: &RateLimitKey) {
+        let label = match key {
+            RateLimitKey::Account(_) => "account",
+            RateLimitKey::CoinKey(_) => "coinKey",
+            RateLimitKey::Ip(_) => "ip",
+        };
+        self.rate_limit_hits.with_label_values(&[label]).inc();
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Could not encode the metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use concordium_rust_sdk::types::RejectReason;
+
+    #[test]
+    fn test_encode() {
+        let metrics = Metrics::new().unwrap();
+        metrics
+            .submissions
+            .with_label_values(&["submitRedeem"])
+            .inc();
+        metrics.record_simulation_failure(&RevertReason {
+            reason: RejectReason::OutOfEnergy,
+            return_value: None,
+            code: None,
+        });
+        metrics.record_simulation_failure(&RevertReason {
+            reason: RejectReason::OutOfEnergy,
+            return_value: None,
+            code: Some("CoinAlreadyRedeemed".into()),
+        });
+        metrics
+            .nonce
+            .with_label_values(&["3kBx2h5Y2veb4hZgAJWPrr8RyQESKm5TjzF3ti1QQ4VSYLwK1G"])
+            .set(7);
+
+        let encoded = metrics.encode();
+        assert!(encoded.contains(r#"sponsor_submissions_total{endpoint="submitRedeem"} 1"#));
+        assert!(encoded.contains(r#"sponsor_simulation_failures_total{reason="OutOfEnergy"} 1"#));
+        assert!(encoded
+            .contains(r#"sponsor_simulation_failures_total{reason="CoinAlreadyRedeemed"} 1"#));
+        assert!(encoded.contains(
+            r#"sponsor_account_nonce{account="3kBx2h5Y2veb4hZgAJWPrr8RyQESKm5TjzF3ti1QQ4VSYLwK1G"} 7"#
+        ));
+    }
+}
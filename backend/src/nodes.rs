@@ -0,0 +1,420 @@
+use crate::nonce::NonceSource;
+use concordium_rust_sdk::endpoints::{QueryError, QueryResult, RPCError, RPCResult};
+use concordium_rust_sdk::smart_contracts::common::AccountAddress;
+use concordium_rust_sdk::types::hashes::{BlockHash, TransactionHash};
+use concordium_rust_sdk::types::queries::AccountNonceResponse;
+use concordium_rust_sdk::types::smart_contracts::{ContractContext, InvokeContractResult};
+use concordium_rust_sdk::types::transactions::{BlockItem, PayloadLike};
+use concordium_rust_sdk::types::{AccountInfo, TransactionStatus};
+use concordium_rust_sdk::v2::{
+    AccountIdentifier, BlockIdentifier, ChainParameters, Client, Endpoint, QueryResponse,
+};
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long a node may take to connect or to respond to a health check.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An error of a request to a node.
+trait NodeError: Display {
+    /// Whether the node is unreachable or overloaded, as opposed to rejecting
+    /// the request. Only then the request is sent to another node.
+    fn is_node_failure(&self) -> bool;
+
+    /// The error if no node is available.
+    fn unavailable() -> Self;
+}
+
+impl NodeError for RPCError {
+    fn is_node_failure(&self) -> bool {
+        use tonic::Code;
+        match self {
+            RPCError::CallError(status) => matches!(
+                status.code(),
+                Code::Unavailable
+                    | Code::DeadlineExceeded
+                    | Code::Cancelled
+                    | Code::Unknown
+                    | Code::ResourceExhausted
+            ),
+            RPCError::InvalidMetadata(_) | RPCError::ParseError(_) => false,
+        }
+    }
+
+    fn unavailable() -> Self {
+        RPCError::CallError(tonic::Status::unavailable("No node is available."))
+    }
+}
+
+impl NodeError for QueryError {
+    fn is_node_failure(&self) -> bool {
+        match self {
+            QueryError::RPCError(e) => e.is_node_failure(),
+            QueryError::NotFound => false,
+        }
+    }
+
+    fn unavailable() -> Self {
+        QueryError::RPCError(RPCError::unavailable())
+    }
+}
+
+#[derive(Default)]
+struct NodeState {
+    /// The connection to the node, if it was established and the node is on
+    /// the expected chain.
+    client: Option<Client>,
+    healthy: bool,
+    /// Why the node is not healthy.
+    error: Option<String>,
+}
+
+struct Node {
+    endpoint: Endpoint,
+    state: Mutex<NodeState>,
+}
+
+impl Node {
+    fn uri(&self) -> String {
+        self.endpoint.uri().to_string()
+    }
+
+    fn set_healthy(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.healthy {
+            log::info!("The node {} is healthy.", self.uri());
+        }
+        state.healthy = true;
+        state.error = None;
+    }
+
+    fn set_unhealthy(&self, error: String) {
+        let mut state = self.state.lock().unwrap();
+        if state.healthy || state.error.as_ref() != Some(&error) {
+            log::warn!("The node {} is not healthy: {}", self.uri(), error);
+        }
+        state.healthy = false;
+        state.error = Some(error);
+    }
+}
+
+/// The health of a node, as reported by `GET /ready`.
+#[derive(serde::Serialize, Debug)]
+pub struct NodeReport {
+    pub endpoint: String,
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+struct NodesInner {
+    nodes: Vec<Node>,
+    /// The genesis block of the chain all nodes must be on.
+    genesis: BlockHash,
+    /// The node to start at for the next request.
+    next: AtomicUsize,
+}
+
+/// The nodes the backend talks to. Requests are distributed across the healthy
+/// nodes in turn. Simulations, submissions and nonce queries are sent to the
+/// next node if a node cannot be reached. All nodes must report the same
+/// genesis block, so nodes of different chains are never mixed.
+#[derive(Clone)]
+pub struct Nodes {
+    inner: Arc<NodesInner>,
+}
+
+/// Connect to the node and query its genesis block.
+async fn connect(endpoint: &Endpoint) -> Result<(Client, BlockHash), String> {
+    let mut client = tokio::time::timeout(HEALTH_CHECK_TIMEOUT, Client::new(endpoint.clone()))
+        .await
+        .map_err(|_| "The connection timed out.".to_string())?
+        .map_err(|e| e.to_string())?;
+    let genesis = genesis_block(&mut client).await?;
+    Ok((client, genesis))
+}
+
+async fn genesis_block(client: &mut Client) -> Result<BlockHash, String> {
+    let info = tokio::time::timeout(HEALTH_CHECK_TIMEOUT, client.get_consensus_info())
+        .await
+        .map_err(|_| "The node did not respond in time.".to_string())?
+        .map_err(|e| e.to_string())?;
+    Ok(info.genesis_block)
+}
+
+fn wrong_chain(genesis: BlockHash, expected: BlockHash) -> String {
+    format!(
+        "The node is on the chain with genesis block {}, not {}.",
+        genesis, expected
+    )
+}
+
+impl Nodes {
+    /// Connect to the nodes. At least one node must be reachable, and all
+    /// reachable nodes must be on the same chain. The other nodes are
+    /// connected to by the health checks later.
+    pub async fn connect(endpoints: Vec<Endpoint>) -> anyhow::Result<Self> {
+        let mut nodes = Vec::with_capacity(endpoints.len());
+        let mut genesis: Option<(BlockHash, String)> = None;
+        for endpoint in endpoints {
+            let uri = endpoint.uri().to_string();
+            let state = match connect(&endpoint).await {
+                Ok((client, hash)) => {
+                    match &genesis {
+                        Some((expected, first)) if *expected != hash => anyhow::bail!(
+                            "The node {} has the genesis block {}, but the node {} has {}. All \
+                             nodes must be on the same chain.",
+                            uri,
+                            hash,
+                            first,
+                            expected
+                        ),
+                        Some(_) => {}
+                        None => genesis = Some((hash, uri)),
+                    }
+                    NodeState {
+                        client: Some(client),
+                        healthy: true,
+                        error: None,
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Could not connect to the node {}: {}", uri, e);
+                    NodeState {
+                        client: None,
+                        healthy: false,
+                        error: Some(e),
+                    }
+                }
+            };
+            nodes.push(Node {
+                endpoint,
+                state: Mutex::new(state),
+            });
+        }
+        let Some((genesis, _)) = genesis else {
+            anyhow::bail!("None of the nodes can be reached.");
+        };
+        log::info!(
+            "Connected to {} of {} nodes on the chain with genesis block {}.",
+            nodes
+                .iter()
+                .filter(|node| node.state.lock().unwrap().healthy)
+                .count(),
+            nodes.len(),
+            genesis
+        );
+        Ok(Self {
+            inner: Arc::new(NodesInner {
+                nodes,
+                genesis,
+                next: AtomicUsize::new(0),
+            }),
+        })
+    }
+
+    /// The nodes to try for a request: the healthy nodes, starting with the
+    /// next one in turn, followed by the connected unhealthy nodes, in case
+    /// they recovered since the last health check.
+    fn candidates(&self) -> Vec<(usize, Client)> {
+        let nodes = &self.inner.nodes;
+        let start = self.inner.next.fetch_add(1, Ordering::Relaxed);
+        let mut healthy = Vec::new();
+        let mut unhealthy = Vec::new();
+        for i in (0..nodes.len()).map(|i| (start + i) % nodes.len()) {
+            let state = nodes[i].state.lock().unwrap();
+            if let Some(client) = &state.client {
+                if state.healthy {
+                    healthy.push((i, client.clone()));
+                } else {
+                    unhealthy.push((i, client.clone()));
+                }
+            }
+        }
+        healthy.append(&mut unhealthy);
+        healthy
+    }
+
+    /// A client of the next healthy node, for requests that are not failed
+    /// over.
+    // The error is the one the requests of the client return, so callers can
+    // handle both alike.
+    #[allow(clippy::result_large_err)]
+    pub fn client(&self) -> Result<Client, QueryError> {
+        self.candidates()
+            .into_iter()
+            .next()
+            .map(|(_, client)| client)
+            .ok_or_else(QueryError::unavailable)
+    }
+
+    /// Make the request to the candidates in turn, until one of them does not
+    /// fail as a node.
+    async fn with_failover<T, E: NodeError, F: Future<Output = Result<T, E>>>(
+        &self,
+        mut request: impl FnMut(Client) -> F,
+    ) -> Result<T, E> {
+        let mut last_error = None;
+        for (i, client) in self.candidates() {
+            match request(client).await {
+                Err(e) if e.is_node_failure() => {
+                    self.inner.nodes[i].set_unhealthy(e.to_string());
+                    last_error = Some(e);
+                }
+                result => return result,
+            }
+        }
+        Err(last_error.unwrap_or_else(E::unavailable))
+    }
+
+    pub async fn invoke_instance(
+        &self,
+        block: &BlockIdentifier,
+        context: &ContractContext,
+    ) -> QueryResult<QueryResponse<InvokeContractResult>> {
+        self.with_failover(|mut client| async move { client.invoke_instance(block, context).await })
+            .await
+    }
+
+    /// The account in the last finalized block.
+    pub async fn get_account_info(&self, address: &AccountAddress) -> QueryResult<AccountInfo> {
+        let account = AccountIdentifier::Address(*address);
+        let account = &account;
+        self.with_failover(|mut client| async move {
+            client
+                .get_account_info(account, BlockIdentifier::LastFinal)
+                .await
+                .map(|info| info.response)
+        })
+        .await
+    }
+
+    /// The chain parameters in the last finalized block.
+    pub async fn get_chain_parameters(&self) -> QueryResult<ChainParameters> {
+        self.with_failover(|mut client| async move {
+            client
+                .get_block_chain_parameters(BlockIdentifier::LastFinal)
+                .await
+                .map(|parameters| parameters.response)
+        })
+        .await
+    }
+
+    /// The status of a transaction, `NotFound` if the node does not know it.
+    pub async fn get_block_item_status(
+        &self,
+        tx_hash: &TransactionHash,
+    ) -> QueryResult<TransactionStatus> {
+        self.with_failover(|mut client| async move { client.get_block_item_status(tx_hash).await })
+            .await
+    }
+
+    pub async fn send_block_item<P: PayloadLike>(
+        &self,
+        item: &BlockItem<P>,
+    ) -> RPCResult<TransactionHash> {
+        let tx_hash = item.hash();
+        let mut attempts = 0;
+        self.with_failover(|mut client| {
+            attempts += 1;
+            let retried = attempts > 1;
+            async move {
+                match client.send_block_item(item).await {
+                    // A node that failed before might have received and
+                    // propagated the transaction nevertheless.
+                    Err(RPCError::CallError(status))
+                        if retried && status.code() == tonic::Code::AlreadyExists =>
+                    {
+                        Ok(tx_hash)
+                    }
+                    result => result,
+                }
+            }
+        })
+        .await
+    }
+
+    /// Check the health of each node, connecting to the nodes that are not
+    /// connected yet.
+    async fn check_health(&self) {
+        for node in &self.inner.nodes {
+            let client = node.state.lock().unwrap().client.clone();
+            let result = match client {
+                Some(mut client) => genesis_block(&mut client).await.map(|hash| (client, hash)),
+                None => connect(&node.endpoint).await,
+            };
+            match result {
+                Ok((client, genesis)) if genesis == self.inner.genesis => {
+                    node.state.lock().unwrap().client = Some(client);
+                    node.set_healthy();
+                }
+                Ok((_, genesis)) => {
+                    // The node is not used until it is on the expected chain.
+                    node.state.lock().unwrap().client = None;
+                    node.set_unhealthy(wrong_chain(genesis, self.inner.genesis));
+                }
+                Err(e) => node.set_unhealthy(e),
+            }
+        }
+    }
+
+    /// Periodically check the health of the nodes.
+    pub async fn run_health_checks(self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick completes immediately, right after the nodes were connected.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            self.check_health().await;
+        }
+    }
+
+    pub fn reports(&self) -> Vec<NodeReport> {
+        self.inner
+            .nodes
+            .iter()
+            .map(|node| {
+                let state = node.state.lock().unwrap();
+                NodeReport {
+                    endpoint: node.uri(),
+                    healthy: state.healthy,
+                    error: state.error.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+impl NonceSource for Nodes {
+    async fn next_nonce(
+        &mut self,
+        address: &AccountAddress,
+    ) -> Result<AccountNonceResponse, QueryError> {
+        self.with_failover(|mut client| async move {
+            client.get_next_account_sequence_number(address).await
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_failures() {
+        assert!(
+            RPCError::CallError(tonic::Status::unavailable("connection refused")).is_node_failure()
+        );
+        assert!(QueryError::unavailable().is_node_failure());
+        assert!(
+            !RPCError::CallError(tonic::Status::invalid_argument("insufficient funds"))
+                .is_node_failure()
+        );
+        assert!(!RPCError::CallError(tonic::Status::already_exists("duplicate")).is_node_failure());
+        assert!(!QueryError::NotFound.is_node_failure());
+    }
+}
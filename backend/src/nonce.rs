@@ -0,0 +1,436 @@
+use crate::types::LogError;
+use concordium_rust_sdk::endpoints::{QueryError, RPCError};
+use concordium_rust_sdk::smart_contracts::common::AccountAddress;
+use concordium_rust_sdk::types::queries::AccountNonceResponse;
+use concordium_rust_sdk::types::Nonce;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Notify, Semaphore, SemaphorePermit};
+
+/// A source of the next nonce (account sequence number) of an account, as
+/// known to the node.
+pub trait NonceSource: Clone + Send + Sync {
+    fn next_nonce(
+        &mut self,
+        address: &AccountAddress,
+    ) -> impl Future<Output = Result<AccountNonceResponse, QueryError>> + Send;
+}
+
+/// Whether a failure to submit a transaction indicates that the local nonce
+/// might be out of sync with the node. This is the case if the node rejected
+/// the nonce, or if the transaction might have been accepted even though no
+/// response was received.
+pub fn requires_resync(err: &RPCError) -> bool {
+    use tonic::Code;
+    match err {
+        RPCError::CallError(status) => match status.code() {
+            Code::AlreadyExists
+            | Code::Unavailable
+            | Code::DeadlineExceeded
+            | Code::Cancelled
+            | Code::Unknown
+            | Code::Internal => true,
+            Code::InvalidArgument => {
+                let message = status.message().to_lowercase();
+                message.contains("nonce") || message.contains("sequence number")
+            }
+            _ => false,
+        },
+        RPCError::InvalidMetadata(_) => false,
+        RPCError::ParseError(_) => true,
+    }
+}
+
+#[derive(Debug)]
+struct NonceState {
+    /// The nonce to use for the next transaction.
+    next: Nonce,
+    /// The number of nonces that are reserved but not yet confirmed or
+    /// released.
+    in_flight: usize,
+    /// Whether the nonce must be queried from the node before the next
+    /// reservation.
+    needs_resync: bool,
+}
+
+/// Manages the nonce of the sponsor account.
+///
+/// Nonces are handed out in order by [`reserve`](Self::reserve). Up to
+/// `max_in_flight` transactions can be pipelined, i.e., have a reserved nonce
+/// at the same time. If a submission fails in a way that indicates that the
+/// local nonce is out of sync with the node, the nonce is queried from the
+/// node again once all in-flight transactions are resolved.
+pub struct NonceManager<C = crate::nodes::Nodes> {
+    client: C,
+    address: AccountAddress,
+    /// Serializes reservations, resynchronizations and drift checks.
+    reserve_lock: tokio::sync::Mutex<()>,
+    state: Mutex<NonceState>,
+    /// Limits the number of in-flight transactions.
+    permits: Semaphore,
+    /// Notified whenever an in-flight transaction is resolved.
+    resolved: Notify,
+}
+
+impl<C: NonceSource> NonceManager<C> {
+    /// Create a new manager for the account, querying its current nonce.
+    pub async fn new(
+        client: C,
+        address: AccountAddress,
+        max_in_flight: usize,
+    ) -> Result<Self, LogError> {
+        let manager = Self {
+            client,
+            address,
+            reserve_lock: tokio::sync::Mutex::new(()),
+            state: Mutex::new(NonceState {
+                next: Nonce::from(0),
+                in_flight: 0,
+                needs_resync: true,
+            }),
+            permits: Semaphore::new(max_in_flight.max(1)),
+            resolved: Notify::new(),
+        };
+        let response = manager.query().await?;
+        {
+            let mut state = manager.state.lock().unwrap();
+            state.next = response.nonce;
+            state.needs_resync = false;
+        }
+        Ok(manager)
+    }
+
+    async fn query(&self) -> Result<AccountNonceResponse, LogError> {
+        self.client
+            .clone()
+            .next_nonce(&self.address)
+            .await
+            .map_err(|e| {
+                log::warn!("NonceQueryError {:#?}.", e);
+                LogError::NonceQueryError
+            })
+    }
+
+    /// The nonce that will be used for the next transaction.
+    pub fn current(&self) -> Nonce {
+        self.state.lock().unwrap().next
+    }
+
+    /// The number of transactions with a reserved nonce that are not yet
+    /// confirmed or released.
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    /// Whether the nonce is known, i.e., not waiting to be queried from the
+    /// node again after a failed submission.
+    pub fn is_synced(&self) -> bool {
+        !self.state.lock().unwrap().needs_resync
+    }
+
+    /// Wait until no transaction is in flight, if a resynchronization is
+    /// pending. Must be called while holding the `reserve_lock`.
+    async fn wait_for_resync(&self) {
+        loop {
+            let notified = self.resolved.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let state = self.state.lock().unwrap();
+                if !state.needs_resync || state.in_flight == 0 {
+                    return;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Reserve the next nonce. This waits if the maximum number of in-flight
+    /// transactions is reached, and queries the nonce from the node first if
+    /// it might be out of sync.
+    pub async fn reserve(&self) -> Result<ReservedNonce<'_, C>, LogError> {
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("The semaphore is never closed.");
+        let _guard = self.reserve_lock.lock().await;
+        self.wait_for_resync().await;
+
+        if self.state.lock().unwrap().needs_resync {
+            let response = self.query().await?;
+            let mut state = self.state.lock().unwrap();
+            log::info!(
+                "Resynchronized nonce from {} to {}.",
+                state.next,
+                response.nonce
+            );
+            state.next = response.nonce;
+            state.needs_resync = false;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let nonce = state.next;
+        state.next = nonce.next();
+        state.in_flight += 1;
+        Ok(ReservedNonce {
+            manager: self,
+            nonce,
+            resolved: false,
+            _permit: permit,
+        })
+    }
+
+    /// Compare the local nonce with the one known to the node, and adopt the
+    /// node's nonce if they drifted apart, e.g., because another process
+    /// submitted transactions from the same account. The check is skipped while
+    /// transactions are in flight.
+    pub async fn check_drift(&self) -> Result<(), LogError> {
+        let _guard = self.reserve_lock.lock().await;
+        if self.state.lock().unwrap().in_flight > 0 {
+            return Ok(());
+        }
+        let response = self.query().await?;
+        let mut state = self.state.lock().unwrap();
+        // If not all transactions of the account are finalized, the node might
+        // not know about our latest transactions yet, so only a larger nonce is
+        // adopted.
+        if response.nonce != state.next
+            && (response.all_final || response.nonce.nonce > state.next.nonce)
+        {
+            log::warn!(
+                "Nonce drift detected: local nonce is {}, node reports {}.",
+                state.next,
+                response.nonce
+            );
+            state.next = response.nonce;
+            state.needs_resync = false;
+        }
+        Ok(())
+    }
+
+    /// Periodically check the nonce for drift.
+    pub async fn run_drift_detection(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick completes immediately, right after the nonce was queried.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.check_drift().await {
+                log::warn!("Nonce drift detection failed: {}", e);
+            }
+        }
+    }
+
+    /// Resolve an in-flight nonce.
+    fn resolve(&self, nonce: Nonce, outcome: Outcome) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        match outcome {
+            Outcome::Used => {}
+            Outcome::Unused => {
+                if state.next == nonce.next() {
+                    // This was the last reserved nonce, so it can be handed out again.
+                    state.next = nonce;
+                } else {
+                    // Later nonces are already handed out, so there is a gap now.
+                    state.needs_resync = true;
+                }
+            }
+            Outcome::Unknown => state.needs_resync = true,
+        }
+        drop(state);
+        self.resolved.notify_waiters();
+    }
+}
+
+/// How a reserved nonce was resolved.
+enum Outcome {
+    /// A transaction with the nonce was submitted.
+    Used,
+    /// No transaction with the nonce was submitted.
+    Unused,
+    /// It is unknown whether a transaction with the nonce was accepted.
+    Unknown,
+}
+
+/// A nonce reserved by the [`NonceManager`]. It must be resolved with
+/// [`confirm`](Self::confirm) or [`fail`](Self::fail) once the transaction
+/// is submitted. If it is dropped instead, the nonce is released unused.
+pub struct ReservedNonce<'a, C: NonceSource> {
+    manager: &'a NonceManager<C>,
+    nonce: Nonce,
+    resolved: bool,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<'a, C: NonceSource> ReservedNonce<'a, C> {
+    pub fn nonce(&self) -> Nonce {
+        self.nonce
+    }
+
+    /// The transaction with this nonce was submitted successfully.
+    pub fn confirm(mut self) {
+        self.resolved = true;
+        self.manager.resolve(self.nonce, Outcome::Used);
+    }
+
+    /// Submitting the transaction with this nonce failed with the given error.
+    pub fn fail(mut self, err: &RPCError) {
+        self.resolved = true;
+        let outcome = if requires_resync(err) {
+            Outcome::Unknown
+        } else {
+            Outcome::Unused
+        };
+        self.manager.resolve(self.nonce, outcome);
+    }
+}
+
+impl<'a, C: NonceSource> Drop for ReservedNonce<'a, C> {
+    fn drop(&mut self) {
+        if !self.resolved {
+            self.manager.resolve(self.nonce, Outcome::Unused);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    const ADDRESS: AccountAddress = AccountAddress([0; 32]);
+
+    /// A mocked node that reports a settable nonce and counts the queries.
+    #[derive(Clone, Default)]
+    struct MockClient {
+        nonce: Arc<AtomicU64>,
+        all_final: Arc<std::sync::atomic::AtomicBool>,
+        queries: Arc<AtomicUsize>,
+    }
+
+    impl MockClient {
+        fn new(nonce: u64) -> Self {
+            let client = Self::default();
+            client.set_nonce(nonce, true);
+            client
+        }
+
+        fn set_nonce(&self, nonce: u64, all_final: bool) {
+            self.nonce.store(nonce, Ordering::SeqCst);
+            self.all_final.store(all_final, Ordering::SeqCst);
+        }
+
+        fn queries(&self) -> usize {
+            self.queries.load(Ordering::SeqCst)
+        }
+    }
+
+    impl NonceSource for MockClient {
+        async fn next_nonce(
+            &mut self,
+            _address: &AccountAddress,
+        ) -> Result<AccountNonceResponse, QueryError> {
+            self.queries.fetch_add(1, Ordering::SeqCst);
+            Ok(AccountNonceResponse {
+                nonce: Nonce::from(self.nonce.load(Ordering::SeqCst)),
+                all_final: self.all_final.load(Ordering::SeqCst),
+            })
+        }
+    }
+
+    fn unavailable() -> RPCError {
+        RPCError::CallError(tonic::Status::unavailable("connection lost"))
+    }
+
+    fn insufficient_funds() -> RPCError {
+        RPCError::CallError(tonic::Status::invalid_argument("insufficient funds"))
+    }
+
+    #[tokio::test]
+    async fn test_confirmed_nonces_are_consecutive() {
+        let client = MockClient::new(5);
+        let manager = NonceManager::new(client.clone(), ADDRESS, 1).await.unwrap();
+
+        for expected in 5..8 {
+            let reserved = manager.reserve().await.unwrap();
+            assert_eq!(reserved.nonce(), Nonce::from(expected));
+            reserved.confirm();
+        }
+        assert_eq!(client.queries(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unused_nonce_is_reused() {
+        let client = MockClient::new(5);
+        let manager = NonceManager::new(client.clone(), ADDRESS, 1).await.unwrap();
+
+        drop(manager.reserve().await.unwrap());
+        manager.reserve().await.unwrap().fail(&insufficient_funds());
+
+        assert_eq!(manager.reserve().await.unwrap().nonce(), Nonce::from(5));
+        assert_eq!(client.queries(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resync_after_lost_response() {
+        let client = MockClient::new(5);
+        let manager = NonceManager::new(client.clone(), ADDRESS, 1).await.unwrap();
+
+        // The transaction was accepted by the node, but the response was lost.
+        manager.reserve().await.unwrap().fail(&unavailable());
+        client.set_nonce(6, false);
+
+        assert_eq!(manager.reserve().await.unwrap().nonce(), Nonce::from(6));
+        assert_eq!(client.queries(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_resync_waits_for_in_flight_transactions() {
+        let client = MockClient::new(5);
+        let manager = NonceManager::new(client.clone(), ADDRESS, 2).await.unwrap();
+
+        let first = manager.reserve().await.unwrap();
+        let second = manager.reserve().await.unwrap();
+        assert_eq!(first.nonce(), Nonce::from(5));
+        assert_eq!(second.nonce(), Nonce::from(6));
+
+        first.fail(&unavailable());
+        client.set_nonce(7, false);
+
+        let third = async {
+            let third = manager.reserve().await.unwrap();
+            assert_eq!(client.queries(), 2);
+            third.nonce()
+        };
+        let resolve_second = async {
+            tokio::task::yield_now().await;
+            // The resync must not happen before the second transaction is resolved.
+            assert_eq!(client.queries(), 1);
+            second.confirm();
+        };
+        let (third, ()) = tokio::join!(third, resolve_second);
+        assert_eq!(third, Nonce::from(7));
+    }
+
+    #[tokio::test]
+    async fn test_drift_detection() {
+        let client = MockClient::new(5);
+        let manager = NonceManager::new(client.clone(), ADDRESS, 1).await.unwrap();
+
+        // Another process used the account.
+        client.set_nonce(9, false);
+        manager.check_drift().await.unwrap();
+        assert_eq!(manager.current(), Nonce::from(9));
+
+        // A smaller nonce is only adopted once all transactions are finalized.
+        client.set_nonce(8, false);
+        manager.check_drift().await.unwrap();
+        assert_eq!(manager.current(), Nonce::from(9));
+        client.set_nonce(8, true);
+        manager.check_drift().await.unwrap();
+        assert_eq!(manager.current(), Nonce::from(8));
+    }
+}
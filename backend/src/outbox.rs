@@ -0,0 +1,843 @@
+use crate::audit::{AuditEvent, AuditRecord};
+use crate::contract::ContractInstance;
+use crate::crypto_common::types::TransactionTime;
+use crate::idempotency::{Claim, Claims, IdempotencyKey};
+use crate::tx_status::{TxStatus, TxTracker, TRANSACTION_EXPIRY};
+use crate::types::{LogError, Server};
+use concordium_rust_sdk::endpoints::{QueryError, QueryResult, RPCError};
+use concordium_rust_sdk::smart_contracts::common::{Amount, ContractAddress};
+use concordium_rust_sdk::types::hashes::TransactionHash;
+use concordium_rust_sdk::types::smart_contracts::{OwnedParameter, OwnedReceiveName};
+use concordium_rust_sdk::types::{transactions, Energy, TransactionStatus};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How long submitted and failed jobs are kept so their status can be polled.
+const JOB_RETENTION: Duration = Duration::from_secs(24 * 3600);
+
+/// How often the outbox checks for jobs that are due for a retry.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a new job is left to the request handler for its first attempt,
+/// before the outbox retries it.
+const FIRST_ATTEMPT_LEASE: Duration = Duration::from_secs(300);
+
+/// The status of a job, as returned by `GET /api/job/{id}`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum JobStatus {
+    /// The transaction is waiting to be sent to the node.
+    Queued,
+    /// The transaction was sent to the node. Its status is tracked under
+    /// `GET /api/tx/{hash}`.
+    #[serde(rename_all = "camelCase")]
+    Submitted { tx_hash: TransactionHash },
+    /// The transaction could not be sent.
+    Failed { error: String },
+}
+
+/// A sponsored transaction accepted by the backend.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Job {
+    pub id: Uuid,
+    pub contract: ContractAddress,
+    pub receive_name: OwnedReceiveName,
+    /// The serialized parameter of the `permit` function.
+    #[serde(with = "hex")]
+    pub parameter: Vec<u8>,
+    /// The estimated energy of the transaction, without the header.
+    pub energy: Energy,
+    /// The keys identifying the submission the job was created for.
+    #[serde(default)]
+    pub idempotency_keys: Vec<IdempotencyKey>,
+    pub status: JobStatus,
+    /// The number of attempts to send the transaction.
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// The hash of the transaction sent in the last attempt, if it is unknown
+    /// whether the node received it.
+    pub last_tx_hash: Option<TransactionHash>,
+    /// When the transaction is sent next, if it is queued.
+    pub next_attempt: chrono::DateTime<chrono::Utc>,
+    /// When the job was last updated.
+    pub updated: chrono::DateTime<chrono::Utc>,
+}
+
+/// The view of a job returned to clients.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JobView {
+    pub job_id: Uuid,
+    #[serde(flatten)]
+    pub status: JobStatus,
+    pub attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_attempt: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl From<&Job> for JobView {
+    fn from(job: &Job) -> Self {
+        Self {
+            job_id: job.id,
+            status: job.status.clone(),
+            attempts: job.attempts,
+            last_error: job.last_error.clone(),
+            next_attempt: (job.status == JobStatus::Queued).then_some(job.next_attempt),
+        }
+    }
+}
+
+/// How sending a transaction failed.
+#[derive(Debug)]
+pub enum SendError {
+    /// The node could not be reached or did not process the transaction. The
+    /// transaction can be sent again later.
+    Transient(String),
+    /// The node rejected the transaction.
+    Permanent(String),
+}
+
+/// Whether sending a transaction failed for reasons that might go away,
+/// i.e., the node was unreachable or overloaded, or the nonce was out of sync.
+pub fn is_transient(err: &RPCError) -> bool {
+    use tonic::Code;
+    match err {
+        RPCError::CallError(status) => match status.code() {
+            Code::Unavailable
+            | Code::DeadlineExceeded
+            | Code::Cancelled
+            | Code::Unknown
+            | Code::Internal
+            | Code::ResourceExhausted
+            | Code::Aborted => true,
+            Code::InvalidArgument => {
+                let message = status.message().to_lowercase();
+                message.contains("nonce") || message.contains("sequence number")
+            }
+            _ => false,
+        },
+        RPCError::InvalidMetadata(_) => false,
+        RPCError::ParseError(_) => true,
+    }
+}
+
+/// When to retry a job that failed `attempts` times: after `initial`,
+/// doubling with every attempt, up to `max`.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Persists accepted sponsored transactions and retries sending them if the
+/// node cannot be reached.
+pub struct Outbox {
+    tree: sled::Tree,
+    jobs: Mutex<Jobs>,
+    max_attempts: u32,
+    backoff: Backoff,
+    claims: Claims,
+}
+
+/// The outcome of checking a submission for duplicates.
+pub enum Submission<'a> {
+    /// No earlier submission with the same idempotency keys is known. The
+    /// keys are claimed until the job is created.
+    New(Claim<'a>),
+    /// The job of an earlier submission with the same idempotency keys.
+    Duplicate(Box<Job>),
+}
+
+/// The jobs by their ID, indexed by their idempotency keys.
+#[derive(Default)]
+struct Jobs {
+    by_id: BTreeMap<Uuid, Job>,
+    by_key: HashMap<IdempotencyKey, BTreeSet<Uuid>>,
+}
+
+impl Jobs {
+    fn insert(&mut self, job: Job) {
+        for key in &job.idempotency_keys {
+            self.by_key.entry(key.clone()).or_default().insert(job.id);
+        }
+        self.by_id.insert(job.id, job);
+    }
+
+    fn remove(&mut self, id: &Uuid) {
+        let Some(job) = self.by_id.remove(id) else {
+            return;
+        };
+        for key in &job.idempotency_keys {
+            if let Some(ids) = self.by_key.get_mut(key) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.by_key.remove(key);
+                }
+            }
+        }
+    }
+}
+
+impl Outbox {
+    /// Open the outbox, loading the jobs saved in the tree.
+    pub fn open(tree: sled::Tree, max_attempts: u32, backoff: Backoff) -> anyhow::Result<Self> {
+        let mut jobs = Jobs::default();
+        for entry in tree.iter() {
+            let (_, value) = entry?;
+            match serde_json::from_slice::<Job>(&value) {
+                Ok(mut job) => {
+                    // Attempts interrupted by a restart are retried right away.
+                    if job.status == JobStatus::Queued {
+                        job.next_attempt = job.next_attempt.min(chrono::Utc::now());
+                    }
+                    jobs.insert(job);
+                }
+                Err(e) => log::warn!("Skipping malformed outbox entry: {}", e),
+            }
+        }
+        Ok(Self {
+            tree,
+            jobs: Mutex::new(jobs),
+            max_attempts: max_attempts.max(1),
+            backoff,
+            claims: Claims::default(),
+        })
+    }
+
+    pub fn job(&self, id: &Uuid) -> Option<Job> {
+        self.jobs.lock().unwrap().by_id.get(id).cloned()
+    }
+
+    /// Check whether a submission with any of the keys was accepted before and
+    /// did not fail, i.e., it is queued, or its transaction is pending or
+    /// finalized successfully. Jobs are kept for [`JOB_RETENTION`] after they
+    /// are done, so duplicates are recognized at least that long.
+    ///
+    /// The status of a transaction that is not known to be final by the
+    /// tracker, e.g., because it is no longer tracked after a restart, is
+    /// queried with `query_status`, see [`is_open`](Self::is_open).
+    pub async fn deduplicate<F, Fut>(
+        &self,
+        keys: &[IdempotencyKey],
+        parameter: &[u8],
+        tracker: &TxTracker,
+        query_status: F,
+    ) -> Result<Submission<'_>, LogError>
+    where
+        F: Fn(TransactionHash) -> Fut,
+        Fut: Future<Output = QueryResult<TransactionStatus>>,
+    {
+        // The keys are claimed before the jobs are searched, so a concurrent
+        // submission either holds the claim or has created its job already.
+        let claim = self.claims.claim(keys)?;
+        let candidates: Vec<(&IdempotencyKey, Job)> = {
+            let jobs = self.jobs.lock().unwrap();
+            let mut seen = BTreeSet::new();
+            keys.iter()
+                .flat_map(|key| {
+                    let ids = jobs.by_key.get(key).into_iter().flatten();
+                    ids.map(move |id| (key, id))
+                })
+                .filter(|(_, id)| seen.insert(**id))
+                .filter_map(|(key, id)| Some((key, jobs.by_id.get(id)?.clone())))
+                .filter(|(_, job)| !matches!(job.status, JobStatus::Failed { .. }))
+                .collect()
+        };
+        for (key, job) in candidates {
+            if !Self::is_open(&job, tracker, &query_status).await? {
+                continue;
+            }
+            if key.is_client() && job.parameter != parameter {
+                return Err(LogError::IdempotencyKeyReused);
+            }
+            return Ok(Submission::Duplicate(Box::new(job)));
+        }
+        Ok(Submission::New(claim))
+    }
+
+    /// Whether the job is queued, or its transaction is pending or finalized
+    /// successfully. The transaction of a submitted job is pending while it is
+    /// tracked and has not expired yet. Otherwise its status is queried, and
+    /// transactions unknown to the node have expired.
+    async fn is_open<F, Fut>(
+        job: &Job,
+        tracker: &TxTracker,
+        query_status: &F,
+    ) -> Result<bool, LogError>
+    where
+        F: Fn(TransactionHash) -> Fut,
+        Fut: Future<Output = QueryResult<TransactionStatus>>,
+    {
+        let tx_hash = match &job.status {
+            JobStatus::Queued => return Ok(true),
+            JobStatus::Failed { .. } => return Ok(false),
+            JobStatus::Submitted { tx_hash } => *tx_hash,
+        };
+        match tracker.status(&tx_hash) {
+            Some(status) if status.is_final() => {
+                return Ok(matches!(status, TxStatus::Finalized { .. }))
+            }
+            Some(_) if chrono::Utc::now() - job.updated < expiry() => return Ok(true),
+            _ => {}
+        }
+        match query_status(tx_hash).await {
+            Ok(status) => Ok(!matches!(
+                TxTracker::convert(None, &status),
+                TxStatus::Failed { .. }
+            )),
+            Err(QueryError::NotFound) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, job: &Job) -> Result<(), LogError> {
+        let value = serde_json::to_vec(job).map_err(|_| LogError::OutboxError)?;
+        self.tree.insert(job.id.as_bytes(), value).map_err(|e| {
+            log::error!("Could not save job {}: {}", job.id, e);
+            LogError::OutboxError
+        })?;
+        Ok(())
+    }
+
+    /// Update the job, saving it.
+    fn update(&self, id: &Uuid, f: impl FnOnce(&mut Job)) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.by_id.get_mut(id)?;
+        f(job);
+        job.updated = chrono::Utc::now();
+        // The job is kept in memory even if it cannot be saved.
+        let _ = self.save(job);
+        Some(job.clone())
+    }
+
+    /// Persist a new job, before its transaction is sent for the first time by
+    /// the caller.
+    pub fn create(
+        &self,
+        contract: ContractAddress,
+        receive_name: OwnedReceiveName,
+        parameter: Vec<u8>,
+        energy: Energy,
+        idempotency_keys: Vec<IdempotencyKey>,
+    ) -> Result<Job, LogError> {
+        let now = chrono::Utc::now();
+        let job = Job {
+            id: Uuid::new_v4(),
+            contract,
+            receive_name,
+            parameter,
+            energy,
+            idempotency_keys,
+            status: JobStatus::Queued,
+            attempts: 0,
+            last_error: None,
+            last_tx_hash: None,
+            next_attempt: now + chrono::Duration::from_std(FIRST_ATTEMPT_LEASE).unwrap(),
+            updated: now,
+        };
+        self.save(&job)?;
+        self.jobs.lock().unwrap().insert(job.clone());
+        Ok(job)
+    }
+
+    pub fn submitted(&self, id: &Uuid, tx_hash: TransactionHash) -> Option<Job> {
+        self.update(id, |job| {
+            job.status = JobStatus::Submitted { tx_hash };
+            job.last_error = None;
+            job.last_tx_hash = None;
+        })
+    }
+
+    pub fn failed(&self, id: &Uuid, error: String) -> Option<Job> {
+        self.update(id, |job| job.status = JobStatus::Failed { error })
+    }
+
+    /// Record a failed attempt. The job is retried after a backoff, unless it
+    /// ran out of attempts.
+    pub fn retry_later(
+        &self,
+        id: &Uuid,
+        error: String,
+        tx_hash: Option<TransactionHash>,
+    ) -> Option<Job> {
+        let (max_attempts, backoff) = (self.max_attempts, self.backoff);
+        self.update(id, |job| {
+            if job.attempts >= max_attempts {
+                job.status = JobStatus::Failed {
+                    error: format!("Gave up after {} attempts: {}", job.attempts, error),
+                };
+            } else {
+                let delay = chrono::Duration::from_std(backoff.delay(job.attempts))
+                    .unwrap_or_else(|_| chrono::Duration::zero());
+                job.next_attempt = chrono::Utc::now() + delay;
+            }
+            job.last_error = Some(error);
+            if tx_hash.is_some() {
+                job.last_tx_hash = tx_hash;
+            }
+        })
+    }
+
+    /// Remove the jobs that are done and were last updated before `cutoff`.
+    fn prune(&self, cutoff: chrono::DateTime<chrono::Utc>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let expired: Vec<Uuid> = jobs
+            .by_id
+            .values()
+            .filter(|job| job.status != JobStatus::Queued && job.updated < cutoff)
+            .map(|job| job.id)
+            .collect();
+        for id in expired {
+            jobs.remove(&id);
+            if let Err(e) = self.tree.remove(id.as_bytes()) {
+                log::warn!("Could not remove job {}: {}", id, e);
+            }
+        }
+    }
+
+    /// The queued jobs that are due at `now`, oldest first.
+    fn due(&self, now: chrono::DateTime<chrono::Utc>) -> Vec<Job> {
+        let mut due: Vec<Job> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .by_id
+            .values()
+            .filter(|job| job.status == JobStatus::Queued && job.next_attempt <= now)
+            .cloned()
+            .collect();
+        due.sort_by_key(|job| job.next_attempt);
+        due
+    }
+
+    /// Make an attempt to send the transaction of the job, and record the
+    /// outcome.
+    pub async fn attempt(&self, state: &Server, contract: &ContractInstance, job: &Job) -> Job {
+        let updated = self.try_send(state, contract, job).await;
+        if let Some(Job {
+            status: JobStatus::Failed { error },
+            ..
+        }) = &updated
+        {
+            state.audit.record(job_failed_record(job, error.clone()));
+        }
+        updated.unwrap_or_else(|| job.clone())
+    }
+
+    /// Send the transaction of the job unless the node already knows it, and
+    /// return the updated job.
+    async fn try_send(
+        &self,
+        state: &Server,
+        contract: &ContractInstance,
+        job: &Job,
+    ) -> Option<Job> {
+        self.update(&job.id, |job| job.attempts += 1);
+        // If the node did not respond to the last attempt, it might have
+        // received the transaction nevertheless. It is only sent again if the
+        // node does not know it.
+        if let Some(tx_hash) = job.last_tx_hash {
+            let status = match state.nodes.client() {
+                Ok(mut client) => client.get_block_item_status(&tx_hash).await,
+                Err(e) => Err(e),
+            };
+            match status {
+                Ok(_) => {
+                    state.tx_tracker.track(tx_hash, contract);
+                    // The sponsor account of the earlier attempt is not known
+                    // here. It is the sender of the transaction on-chain.
+                    state.audit.record(AuditRecord {
+                        job_id: Some(job.id),
+                        contract: Some(job.contract.to_string()),
+                        tx_hash: Some(tx_hash),
+                        ..AuditRecord::new(AuditEvent::Sent)
+                    });
+                    return self.submitted(&job.id, tx_hash);
+                }
+                Err(QueryError::NotFound) => {}
+                Err(e) => return self.retry_later(&job.id, e.to_string(), None),
+            }
+        }
+        match send(state, contract, job).await {
+            Ok(tx_hash) => self.submitted(&job.id, tx_hash),
+            Err((SendError::Transient(error), tx_hash)) => {
+                log::warn!("Sending job {} failed, retrying later: {}", job.id, error);
+                self.retry_later(&job.id, error, tx_hash)
+            }
+            Err((SendError::Permanent(error), _)) => {
+                log::error!("Sending job {} failed: {}", job.id, error);
+                self.failed(&job.id, error)
+            }
+        }
+    }
+
+    /// Retry the queued jobs when they are due.
+    pub async fn run(self: Arc<Self>, state: Server, instances: Vec<ContractInstance>) {
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            ticker.tick().await;
+            // The jobs are saved without waiting for the disk, and flushed
+            // together here.
+            if let Err(e) = self.tree.flush_async().await {
+                log::error!("Could not flush the outbox: {}", e);
+            }
+            let now = chrono::Utc::now();
+            self.prune(now - chrono::Duration::from_std(JOB_RETENTION).unwrap());
+            // Queued jobs stay queued while sponsoring is paused.
+            if state.admin.is_paused() {
+                continue;
+            }
+            for job in self.due(now) {
+                // No new attempts are made once the backend is shutting down.
+                // The queued jobs are retried after the restart.
+                let Ok(_in_flight) = state.drain.enter() else {
+                    return;
+                };
+                match instances
+                    .iter()
+                    .find(|instance| instance.address == job.contract)
+                {
+                    Some(contract) => {
+                        self.attempt(&state, contract, &job).await;
+                    }
+                    None => {
+                        let error = format!(
+                            "The contract instance {} is no longer served.",
+                            job.contract
+                        );
+                        self.failed(&job.id, error.clone());
+                        state.audit.record(job_failed_record(&job, error));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// How long a sponsored transaction is valid after it was signed.
+fn expiry() -> chrono::Duration {
+    chrono::Duration::from_std(TRANSACTION_EXPIRY).unwrap()
+}
+
+/// The audit record of a job given up with the error.
+fn job_failed_record(job: &Job, error: String) -> AuditRecord {
+    AuditRecord {
+        job_id: Some(job.id),
+        contract: Some(job.contract.to_string()),
+        outcome: Some("failed".into()),
+        reason: Some(error),
+        ..AuditRecord::new(AuditEvent::JobFailed)
+    }
+}
+
+/// Sign the transaction of the job with the next nonce of a sponsor account
+/// from the pool and a fresh expiry, and send it. On failure, the hash of the
+/// transaction is returned if it is unknown whether the node received it.
+async fn send(
+    state: &Server,
+    contract: &ContractInstance,
+    job: &Job,
+) -> Result<TransactionHash, (SendError, Option<TransactionHash>)> {
+    let parameter = OwnedParameter::try_from(job.parameter.clone()).map_err(|_| {
+        (
+            SendError::Permanent("The parameter is too large.".into()),
+            None,
+        )
+    })?;
+
+    let payload = transactions::Payload::Update {
+        payload: transactions::UpdateContractPayload {
+            amount: Amount::zero(),
+            address: job.contract,
+            receive_name: job.receive_name.clone(),
+            message: parameter,
+        },
+    };
+
+    // The transaction is sent from another account of the pool if the one of
+    // the previous attempt is drained or excluded.
+    let sponsor = state
+        .sponsors
+        .select()
+        .map_err(|e| (SendError::Transient(e.to_string()), None))?;
+
+    // Reserve the next nonce of the sponsor account. This is necessary since it is possible that
+    // API requests come in parallel. The nonce is confirmed once the transaction is submitted to
+    // the blockchain, or released again if the submission failed.
+    let nonce = sponsor
+        .nonce
+        .reserve()
+        .await
+        .map_err(|e| (SendError::Transient(e.to_string()), None))?;
+
+    let expiry = (chrono::Utc::now() + expiry()).timestamp() as u64;
+
+    let tx = transactions::send::make_and_sign_transaction(
+        &sponsor.key.keys,
+        sponsor.key.address,
+        nonce.nonce(),
+        TransactionTime { seconds: expiry },
+        // The energy for the header of the transaction is added to the estimate.
+        transactions::send::GivenEnergy::Add(job.energy),
+        payload,
+    );
+
+    let bi = transactions::BlockItem::AccountTransaction(tx);
+    let tx_hash = bi.hash();
+
+    log::debug!("Submit transaction.");
+
+    let timer = state.metrics.send_block_item_duration.start_timer();
+    let result = state.nodes.send_block_item(&bi).await;
+    timer.observe_duration();
+
+    match result {
+        Ok(hash) => {
+            nonce.confirm();
+            sponsor.record_success();
+            state.tx_tracker.track(hash, contract);
+            state.audit.record(AuditRecord {
+                job_id: Some(job.id),
+                contract: Some(job.contract.to_string()),
+                sponsor: Some(sponsor.address()),
+                tx_hash: Some(hash),
+                ..AuditRecord::new(AuditEvent::Sent)
+            });
+            Ok(hash)
+        }
+        Err(e) => {
+            log::error!("SubmitSponsoredTransactionError {:#?}.", e);
+
+            state
+                .metrics
+                .node_errors
+                .with_label_values(&["send_block_item"])
+                .inc();
+
+            nonce.fail(&e);
+
+            if is_transient(&e) {
+                return Err((SendError::Transient(e.to_string()), Some(tx_hash)));
+            }
+            // The node refused the transaction of this account, e.g., because of
+            // its nonce or funds. If the pool has other accounts, the job is
+            // retried from one of them.
+            sponsor.record_error();
+            if state.sponsors.accounts().len() > 1 {
+                Err((SendError::Transient(e.to_string()), None))
+            } else {
+                Err((SendError::Permanent(e.to_string()), None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use concordium_rust_sdk::smart_contracts::common::{AccountAddress, PublicKeyEd25519};
+
+    fn outbox(tree: sled::Tree) -> Outbox {
+        Outbox::open(
+            tree,
+            3,
+            Backoff {
+                initial: Duration::from_secs(2),
+                max: Duration::from_secs(5),
+            },
+        )
+        .unwrap()
+    }
+
+    fn keys() -> Vec<IdempotencyKey> {
+        IdempotencyKey::for_submission(
+            ContractAddress::new(4184, 0),
+            AccountAddress([1; 32]),
+            Some(&PublicKeyEd25519([1; 32])),
+            Some("order-1".into()),
+        )
+        .unwrap()
+    }
+
+    fn create(outbox: &Outbox) -> Job {
+        outbox
+            .create(
+                ContractAddress::new(4184, 0),
+                OwnedReceiveName::new_unchecked("ccd_redeem.permit".into()),
+                vec![1, 2, 3],
+                Energy { energy: 5000 },
+                keys(),
+            )
+            .unwrap()
+    }
+
+    async fn not_found(_: TransactionHash) -> QueryResult<TransactionStatus> {
+        Err(QueryError::NotFound)
+    }
+
+    async fn is_duplicate(outbox: &Outbox, keys: &[IdempotencyKey], parameter: &[u8]) -> bool {
+        matches!(
+            outbox
+                .deduplicate(keys, parameter, &TxTracker::new(), not_found)
+                .await,
+            Ok(Submission::Duplicate(_))
+        )
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(2),
+            max: Duration::from_secs(60),
+        };
+        assert_eq!(backoff.delay(1), Duration::from_secs(2));
+        assert_eq!(backoff.delay(2), Duration::from_secs(4));
+        assert_eq!(backoff.delay(5), Duration::from_secs(32));
+        assert_eq!(backoff.delay(6), Duration::from_secs(60));
+        assert_eq!(backoff.delay(100), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_jobs_are_persisted() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("outbox").unwrap();
+        let first = outbox(tree.clone());
+        let job = create(&first);
+        // The first attempt is left to the request handler.
+        assert!(first.due(chrono::Utc::now()).is_empty());
+        drop(first);
+
+        let reopened = outbox(tree);
+        let loaded = reopened.job(&job.id).unwrap();
+        assert_eq!(loaded.parameter, vec![1, 2, 3]);
+        assert_eq!(loaded.status, JobStatus::Queued);
+        assert_eq!(reopened.due(chrono::Utc::now()).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_deduplicate() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let outbox = outbox(db.open_tree("outbox").unwrap());
+        let keys = keys();
+
+        let Ok(Submission::New(claim)) = outbox
+            .deduplicate(&keys, &[1, 2, 3], &TxTracker::new(), not_found)
+            .await
+        else {
+            panic!("The first submission is new.");
+        };
+        assert!(matches!(
+            outbox
+                .deduplicate(&keys[..1], &[1, 2, 3], &TxTracker::new(), not_found)
+                .await,
+            Err(LogError::IdempotencyConflict)
+        ));
+        create(&outbox);
+        drop(claim);
+
+        // The coin may be redeemed with a new signature, but a client key
+        // must not be reused for a different request.
+        assert!(is_duplicate(&outbox, &keys[..1], &[4, 5, 6]).await);
+        assert!(matches!(
+            outbox
+                .deduplicate(&keys[1..], &[4, 5, 6], &TxTracker::new(), not_found)
+                .await,
+            Err(LogError::IdempotencyKeyReused)
+        ));
+        assert!(is_duplicate(&outbox, &keys[1..], &[1, 2, 3]).await);
+    }
+
+    #[tokio::test]
+    async fn test_pruned_jobs_are_removed_from_the_index() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let outbox = outbox(db.open_tree("outbox").unwrap());
+        let job = create(&outbox);
+        outbox.submitted(&job.id, TransactionHash::new([1; 32]));
+        assert_eq!(outbox.jobs.lock().unwrap().by_key.len(), keys().len());
+
+        outbox.prune(chrono::Utc::now() + chrono::Duration::seconds(1));
+        assert!(outbox.job(&job.id).is_none());
+        assert!(outbox.jobs.lock().unwrap().by_key.is_empty());
+        assert!(!is_duplicate(&outbox, &keys(), &[1, 2, 3]).await);
+    }
+
+    #[tokio::test]
+    async fn test_deduplicate_after_restart() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("outbox").unwrap();
+        let tx_hash = TransactionHash::new([7; 32]);
+        let first = outbox(tree.clone());
+        let job = create(&first);
+        first.submitted(&job.id, tx_hash);
+
+        // While the transaction is tracked, the node is not asked.
+        let tracker = TxTracker::new();
+        tracker.track(
+            tx_hash,
+            &ContractInstance::new("ccd_redeem".into(), job.contract, None),
+        );
+        assert!(matches!(
+            first
+                .deduplicate(&keys(), &[1, 2, 3], &tracker, |_| async {
+                    panic!("The status of a tracked transaction is not queried.")
+                })
+                .await,
+            Ok(Submission::Duplicate(_))
+        ));
+        drop(first);
+
+        // After a restart, the transaction is no longer tracked. It is still
+        // open if the node knows it, and expired otherwise.
+        let restarted = outbox(tree);
+        let received = restarted
+            .deduplicate(&keys(), &[1, 2, 3], &TxTracker::new(), |_| async {
+                Ok(TransactionStatus::Received)
+            })
+            .await;
+        assert!(matches!(received, Ok(Submission::Duplicate(_))));
+        assert!(!is_duplicate(&restarted, &keys(), &[1, 2, 3]).await);
+        let unavailable = restarted
+            .deduplicate(&keys(), &[1, 2, 3], &TxTracker::new(), |_| async {
+                Err(QueryError::RPCError(RPCError::CallError(
+                    tonic::Status::unavailable("down"),
+                )))
+            })
+            .await;
+        assert!(matches!(unavailable, Err(LogError::NodeAccess(_))));
+    }
+
+    #[tokio::test]
+    async fn test_retries_are_limited() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let outbox = outbox(db.open_tree("outbox").unwrap());
+        let job = create(&outbox);
+
+        for attempt in 1..=3 {
+            outbox.update(&job.id, |job| job.attempts += 1);
+            let updated = outbox
+                .retry_later(&job.id, "unavailable".into(), None)
+                .unwrap();
+            if attempt < 3 {
+                assert_eq!(updated.status, JobStatus::Queued);
+                assert!(updated.next_attempt > chrono::Utc::now());
+                assert!(outbox.due(chrono::Utc::now()).is_empty());
+                assert!(is_duplicate(&outbox, &keys(), &[1, 2, 3]).await);
+            } else {
+                assert!(matches!(updated.status, JobStatus::Failed { .. }));
+                assert!(!is_duplicate(&outbox, &keys(), &[1, 2, 3]).await);
+            }
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use crate::balance::{BalanceMonitor, BalanceReport};
+use crate::nonce::NonceManager;
+use crate::types::LogError;
+use concordium_rust_sdk::smart_contracts::common::AccountAddress;
+use concordium_rust_sdk::types::WalletAccount;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How the sponsor account of a transaction is chosen from the pool.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionStrategy {
+    /// The accounts take turns.
+    RoundRobin,
+    /// The account with the fewest transactions in flight, taking turns among
+    /// accounts with the same number.
+    LeastPending,
+}
+
+/// When an account is excluded because its transactions keep failing.
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorPolicy {
+    /// The number of consecutive failed submissions after which the account is
+    /// excluded.
+    pub threshold: u32,
+    /// How long the account is excluded before it is tried again.
+    pub cooldown: Duration,
+}
+
+#[derive(Debug, Default)]
+struct AccountHealth {
+    consecutive_errors: u32,
+    excluded_until: Option<Instant>,
+}
+
+impl AccountHealth {
+    fn is_excluded(&self, now: Instant) -> bool {
+        self.excluded_until.is_some_and(|until| until > now)
+    }
+
+    /// Count an error, returning whether the account got excluded.
+    fn record_error(&mut self, now: Instant, policy: &ErrorPolicy) -> bool {
+        self.consecutive_errors += 1;
+        if self.consecutive_errors >= policy.threshold && !self.is_excluded(now) {
+            self.excluded_until = Some(now + policy.cooldown);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// An account paying for sponsored transactions, with its own nonce and
+/// balance tracking.
+pub struct SponsorAccount {
+    pub key: WalletAccount,
+    pub nonce: Arc<NonceManager>,
+    pub balance: Arc<BalanceMonitor>,
+    health: Mutex<AccountHealth>,
+    errors: ErrorPolicy,
+}
+
+impl SponsorAccount {
+    pub fn new(
+        key: WalletAccount,
+        nonce: Arc<NonceManager>,
+        balance: Arc<BalanceMonitor>,
+        errors: ErrorPolicy,
+    ) -> Self {
+        Self {
+            key,
+            nonce,
+            balance,
+            health: Mutex::new(AccountHealth::default()),
+            errors,
+        }
+    }
+
+    pub fn address(&self) -> AccountAddress {
+        self.key.address
+    }
+
+    /// Whether the account can pay for a new transaction, i.e., its balance is
+    /// above the low watermark and it is not excluded because of errors.
+    pub fn is_available(&self) -> bool {
+        self.balance.check().is_ok() && !self.health.lock().unwrap().is_excluded(Instant::now())
+    }
+
+    /// Record that a transaction of the account was accepted by the node.
+    pub fn record_success(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_errors = 0;
+        health.excluded_until = None;
+    }
+
+    /// Record that the node refused a transaction of the account. After
+    /// `threshold` consecutive errors, the account is excluded for the
+    /// cooldown. It is excluded again on the first error after the cooldown.
+    pub fn record_error(&self) {
+        let mut health = self.health.lock().unwrap();
+        if health.record_error(Instant::now(), &self.errors) {
+            log::error!(
+                "Excluding sponsor account {} for {} seconds after {} consecutive errors.",
+                self.key.address,
+                self.errors.cooldown.as_secs(),
+                health.consecutive_errors
+            );
+        }
+    }
+
+    pub fn report(&self) -> SponsorReport {
+        SponsorReport {
+            available: self.is_available(),
+            pending_transactions: self.nonce.in_flight(),
+            consecutive_errors: self.health.lock().unwrap().consecutive_errors,
+            balance: self.balance.report(),
+        }
+    }
+}
+
+/// The state of a sponsor account, as returned by `GET /health/balance`.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SponsorReport {
+    pub available: bool,
+    pub pending_transactions: usize,
+    pub consecutive_errors: u32,
+    #[serde(flatten)]
+    pub balance: BalanceReport,
+}
+
+/// The response of `GET /health/balance`.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolReport {
+    /// The number of accounts that can pay for new transactions.
+    pub available: usize,
+    pub accounts: Vec<SponsorReport>,
+}
+
+impl PoolReport {
+    pub fn new(pool: &SponsorPool) -> Self {
+        let accounts: Vec<SponsorReport> = pool
+            .accounts
+            .iter()
+            .map(|account| account.report())
+            .collect();
+        Self {
+            available: accounts.iter().filter(|account| account.available).count(),
+            accounts,
+        }
+    }
+}
+
+/// The accounts paying for sponsored transactions. Each transaction is sent
+/// from one of the available accounts, so transactions of different accounts
+/// do not wait for each other's nonces, and a compromised key only exposes the
+/// funds of its account.
+pub struct SponsorPool {
+    accounts: Vec<Arc<SponsorAccount>>,
+    strategy: SelectionStrategy,
+    /// The account to start the search for the next selection at.
+    next: AtomicUsize,
+}
+
+/// Choose one of the candidates, given as their number of pending
+/// transactions if they are available, starting the search at `start`.
+fn choose(
+    candidates: &[Option<usize>],
+    strategy: SelectionStrategy,
+    start: usize,
+) -> Option<usize> {
+    let n = candidates.len();
+    let rotated = (0..n).map(|i| (start + i) % n);
+    match strategy {
+        SelectionStrategy::RoundRobin => rotated.into_iter().find(|&i| candidates[i].is_some()),
+        SelectionStrategy::LeastPending => rotated
+            .filter_map(|i| Some((candidates[i]?, i)))
+            // The first of the accounts with the fewest pending transactions.
+            .min_by_key(|&(pending, _)| pending)
+            .map(|(_, i)| i),
+    }
+}
+
+impl SponsorPool {
+    pub fn new(accounts: Vec<Arc<SponsorAccount>>, strategy: SelectionStrategy) -> Self {
+        assert!(!accounts.is_empty(), "The pool needs at least one account.");
+        Self {
+            accounts,
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn accounts(&self) -> &[Arc<SponsorAccount>] {
+        &self.accounts
+    }
+
+    fn candidates(&self) -> Vec<Option<usize>> {
+        self.accounts
+            .iter()
+            .map(|account| account.is_available().then(|| account.nonce.in_flight()))
+            .collect()
+    }
+
+    /// The reason no account is available.
+    fn unavailable(&self) -> LogError {
+        if self
+            .accounts
+            .iter()
+            .all(|account| account.balance.check().is_err())
+        {
+            LogError::InsufficientSponsorBalance
+        } else {
+            LogError::NoSponsorAvailable
+        }
+    }
+
+    /// An account that can pay for a new transaction, without selecting it.
+    pub fn check(&self) -> Result<Arc<SponsorAccount>, LogError> {
+        self.accounts
+            .iter()
+            .find(|account| account.is_available())
+            .cloned()
+            .ok_or_else(|| self.unavailable())
+    }
+
+    /// Select the account to send the next transaction from.
+    pub fn select(&self) -> Result<Arc<SponsorAccount>, LogError> {
+        let start = self.next.load(Ordering::Relaxed) % self.accounts.len();
+        let index =
+            choose(&self.candidates(), self.strategy, start).ok_or_else(|| self.unavailable())?;
+        self.next.store(index + 1, Ordering::Relaxed);
+        Ok(self.accounts[index].clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose() {
+        let candidates = [Some(2), None, Some(1), Some(1)];
+        let round_robin = SelectionStrategy::RoundRobin;
+        assert_eq!(choose(&candidates, round_robin, 0), Some(0));
+        assert_eq!(choose(&candidates, round_robin, 1), Some(2));
+        assert_eq!(choose(&candidates, round_robin, 4 % 4), Some(0));
+
+        let least_pending = SelectionStrategy::LeastPending;
+        assert_eq!(choose(&candidates, least_pending, 0), Some(2));
+        assert_eq!(choose(&candidates, least_pending, 3), Some(3));
+        assert_eq!(choose(&[None, None], least_pending, 0), None);
+    }
+
+    #[test]
+    fn test_exclusion() {
+        let policy = ErrorPolicy {
+            threshold: 2,
+            cooldown: Duration::from_secs(60),
+        };
+        let mut health = AccountHealth::default();
+        let now = Instant::now();
+        assert!(!health.record_error(now, &policy));
+        assert!(!health.is_excluded(now));
+        assert!(health.record_error(now, &policy));
+        assert!(health.is_excluded(now + Duration::from_secs(59)));
+
+        // After the cooldown, the account is tried again, but excluded on the
+        // next error.
+        let later = now + Duration::from_secs(61);
+        assert!(!health.is_excluded(later));
+        assert!(health.record_error(later, &policy));
+        assert!(health.is_excluded(later));
+    }
+}
@@ -0,0 +1,548 @@
+use crate::types::LogError;
+use concordium_rust_sdk::smart_contracts::common::{AccountAddress, PublicKeyEd25519};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A key by which sponsored transactions are rate limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RateLimitKey {
+    /// The account that signed the sponsored transaction.
+    Account(AccountAddress),
+    /// The public key of the coin that is redeemed.
+    CoinKey(PublicKeyEd25519),
+    /// The IP address the request came from.
+    Ip(IpAddr),
+}
+
+impl RateLimitKey {
+    /// The byte representation of the key, used as the key in the on-disk
+    /// store. The first byte tags the kind of the key.
+    pub fn to_bytes(self) -> Vec<u8> {
+        let (tag, bytes): (u8, &[u8]) = match &self {
+            RateLimitKey::Account(account) => (0, account.as_ref()),
+            RateLimitKey::CoinKey(key) => (1, &key.0),
+            RateLimitKey::Ip(IpAddr::V4(ip)) => (2, &ip.octets()[..]),
+            RateLimitKey::Ip(IpAddr::V6(ip)) => (3, &ip.octets()[..]),
+        };
+        let mut out = Vec::with_capacity(1 + bytes.len());
+        out.push(tag);
+        out.extend_from_slice(bytes);
+        out
+    }
+
+    /// The key of the byte representation returned by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (tag, bytes) = bytes.split_first()?;
+        match tag {
+            0 => Some(RateLimitKey::Account(AccountAddress(
+                bytes.try_into().ok()?,
+            ))),
+            1 => Some(RateLimitKey::CoinKey(PublicKeyEd25519(
+                bytes.try_into().ok()?,
+            ))),
+            2 => Some(RateLimitKey::Ip(<[u8; 4]>::try_from(bytes).ok()?.into())),
+            3 => Some(RateLimitKey::Ip(<[u8; 16]>::try_from(bytes).ok()?.into())),
+            _ => None,
+        }
+    }
+}
+
+/// The backend used to store the rate limits.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitStoreKind {
+    /// Keep the rate limits in memory. They are reset on server restart.
+    Memory,
+    /// Keep the rate limits in an embedded on-disk database.
+    Sled,
+}
+
+/// A limit of at most `max_requests` requests within a sliding `window`. A
+/// limit with `max_requests` equal to 0 is disabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub max_requests: u32,
+    pub window: Duration,
+}
+
+/// The rate limits for each kind of [`RateLimitKey`].
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    pub account: RateLimit,
+    pub coin_key: RateLimit,
+    pub ip: RateLimit,
+}
+
+impl RateLimits {
+    fn get(&self, key: &RateLimitKey) -> RateLimit {
+        match key {
+            RateLimitKey::Account(_) => self.account,
+            RateLimitKey::CoinKey(_) => self.coin_key,
+            RateLimitKey::Ip(_) => self.ip,
+        }
+    }
+}
+
+/// A store of the times at which requests were accepted, per rate limit key.
+/// Times are in milliseconds since the Unix epoch.
+pub trait RateLimitStore: Send + Sync {
+    /// The number of requests recorded for the key at or after `since`.
+    fn count_since(&self, key: &RateLimitKey, since: u64) -> anyhow::Result<usize>;
+
+    /// Record a request for the key at time `now`. Records before `since` are
+    /// no longer needed and may be discarded.
+    fn record(&self, key: &RateLimitKey, now: u64, since: u64) -> anyhow::Result<()>;
+
+    /// Discard all records of the key.
+    fn reset(&self, key: &RateLimitKey) -> anyhow::Result<()>;
+}
+
+/// How often the stores discard the keys whose records have all left their
+/// window, in milliseconds.
+const PRUNE_INTERVAL: u64 = 60_000;
+
+/// A [`RateLimitStore`] that keeps the records in memory.
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    records: Mutex<MemoryRecords>,
+}
+
+#[derive(Default)]
+struct MemoryRecords {
+    /// The times of the records of each key, and the time at which the last
+    /// of them leaves the window.
+    keys: BTreeMap<RateLimitKey, (Vec<u64>, u64)>,
+    /// When the keys without records in their window are discarded next.
+    next_prune: u64,
+}
+
+impl RateLimitStore for MemoryRateLimitStore {
+    fn count_since(&self, key: &RateLimitKey, since: u64) -> anyhow::Result<usize> {
+        let records = self.records.lock().unwrap();
+        Ok(records.keys.get(key).map_or(0, |(times, _)| {
+            times.iter().filter(|&&t| t >= since).count()
+        }))
+    }
+
+    fn record(&self, key: &RateLimitKey, now: u64, since: u64) -> anyhow::Result<()> {
+        let mut records = self.records.lock().unwrap();
+        // Keys are otherwise only trimmed when they make another request, so
+        // every account, coin and IP address would be kept forever.
+        if now >= records.next_prune {
+            records.keys.retain(|_, (_, expiry)| *expiry >= now);
+            records.next_prune = now.saturating_add(PRUNE_INTERVAL);
+        }
+        let (times, expiry) = records.keys.entry(*key).or_default();
+        times.retain(|&t| t >= since);
+        times.push(now);
+        *expiry = now.saturating_add(now - since);
+        Ok(())
+    }
+
+    fn reset(&self, key: &RateLimitKey) -> anyhow::Result<()> {
+        self.records.lock().unwrap().keys.remove(key);
+        Ok(())
+    }
+}
+
+/// A [`RateLimitStore`] that keeps the records in an embedded on-disk
+/// database, so they survive server restarts. The value stored for a key is
+/// the big-endian encoded time at which the last of its records leaves the
+/// window, followed by the big-endian encoded times of its records.
+pub struct SledRateLimitStore {
+    tree: sled::Tree,
+    /// When the keys without records in their window are removed next.
+    next_prune: AtomicU64,
+}
+
+impl SledRateLimitStore {
+    pub fn new(tree: sled::Tree) -> Self {
+        Self {
+            tree,
+            next_prune: AtomicU64::new(0),
+        }
+    }
+
+    /// Remove the keys whose records have all left their window at `now`.
+    fn prune(&self, now: u64) -> anyhow::Result<()> {
+        for entry in self.tree.iter() {
+            let (key, value) = entry?;
+            if decode_expiry(&value) < now {
+                self.tree.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decode the time stored by the [`SledRateLimitStore`] at which the last
+/// record leaves the window.
+fn decode_expiry(bytes: &[u8]) -> u64 {
+    bytes
+        .get(..8)
+        .map_or(0, |expiry| u64::from_be_bytes(expiry.try_into().unwrap()))
+}
+
+/// Decode the times of the records stored by the [`SledRateLimitStore`].
+fn decode_times(bytes: &[u8]) -> impl Iterator<Item = u64> + '_ {
+    bytes
+        .get(8..)
+        .unwrap_or_default()
+        .chunks_exact(8)
+        .map(|chunk| u64::from_be_bytes(chunk.try_into().unwrap()))
+}
+
+impl RateLimitStore for SledRateLimitStore {
+    fn count_since(&self, key: &RateLimitKey, since: u64) -> anyhow::Result<usize> {
+        let value = self.tree.get(key.to_bytes())?;
+        Ok(value.map_or(0, |bytes| {
+            decode_times(&bytes).filter(|&t| t >= since).count()
+        }))
+    }
+
+    fn record(&self, key: &RateLimitKey, now: u64, since: u64) -> anyhow::Result<()> {
+        // Like in the memory store, keys that make no further requests are
+        // removed periodically.
+        if now >= self.next_prune.load(Ordering::Relaxed) {
+            self.next_prune
+                .store(now.saturating_add(PRUNE_INTERVAL), Ordering::Relaxed);
+            self.prune(now)?;
+        }
+        let expiry = now.saturating_add(now - since);
+        self.tree.update_and_fetch(key.to_bytes(), |old| {
+            let mut new = expiry.to_be_bytes().to_vec();
+            if let Some(bytes) = old {
+                new.extend(
+                    decode_times(bytes)
+                        .filter(|&t| t >= since)
+                        .flat_map(u64::to_be_bytes),
+                );
+            }
+            new.extend_from_slice(&now.to_be_bytes());
+            Some(new)
+        })?;
+        // The record is not flushed here, since the limiter lock is held on
+        // the request path. The database flushes in the background, so at
+        // most the records of the last moments are lost on a crash.
+        Ok(())
+    }
+
+    fn reset(&self, key: &RateLimitKey) -> anyhow::Result<()> {
+        self.tree.remove(key.to_bytes())?;
+        self.tree.flush()?;
+        Ok(())
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// The start of the window of the limit ending at `now`.
+fn window_start(limit: RateLimit, now: u64) -> u64 {
+    now.saturating_sub(limit.window.as_millis() as u64)
+}
+
+/// Enforces the [`RateLimits`] using a [`RateLimitStore`].
+pub struct RateLimiter {
+    store: Box<dyn RateLimitStore>,
+    limits: RateLimits,
+    /// Limits of individual keys that replace the limit of their kind, set
+    /// through the admin API.
+    overrides: Mutex<BTreeMap<RateLimitKey, RateLimit>>,
+    /// Makes checking and recording atomic, so parallel requests cannot exceed
+    /// the limits.
+    lock: Mutex<()>,
+}
+
+impl RateLimiter {
+    pub fn new(store: Box<dyn RateLimitStore>, limits: RateLimits) -> Self {
+        Self {
+            store,
+            limits,
+            overrides: Mutex::new(BTreeMap::new()),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// The limits of the kinds of keys.
+    pub fn limits(&self) -> RateLimits {
+        self.limits
+    }
+
+    /// The limit of the key, and whether it replaces the limit of its kind.
+    pub fn limit(&self, key: &RateLimitKey) -> (RateLimit, bool) {
+        match self.overrides.lock().unwrap().get(key) {
+            Some(limit) => (*limit, true),
+            None => (self.limits.get(key), false),
+        }
+    }
+
+    /// Replace the limit of the key, or restore the limit of its kind.
+    pub fn set_limit(&self, key: RateLimitKey, limit: Option<RateLimit>) {
+        let mut overrides = self.overrides.lock().unwrap();
+        match limit {
+            Some(limit) => overrides.insert(key, limit),
+            None => overrides.remove(&key),
+        };
+    }
+
+    /// The number of requests of the key within the window of its limit.
+    pub fn usage(&self, key: &RateLimitKey) -> Result<usize, LogError> {
+        let (limit, _) = self.limit(key);
+        self.store
+            .count_since(key, window_start(limit, now_millis()))
+            .map_err(|e| {
+                log::error!("RateLimitStoreError {:#}.", e);
+                LogError::RateLimitStoreError
+            })
+    }
+
+    /// Discard the requests recorded for the key, so it can make the full
+    /// number of requests again.
+    pub fn reset(&self, key: &RateLimitKey) -> Result<(), LogError> {
+        let _guard = self.lock.lock().unwrap();
+        self.store.reset(key).map_err(|e| {
+            log::error!("RateLimitStoreError {:#}.", e);
+            LogError::RateLimitStoreError
+        })
+    }
+
+    /// Check that none of the keys has reached its limit and, if so, record
+    /// the request for all of them.
+    pub fn check_and_record(&self, keys: &[RateLimitKey]) -> Result<(), LogError> {
+        self.check_and_record_at(keys, now_millis())
+    }
+
+    /// Check that none of the keys has reached its limit, without recording a
+    /// request.
+    pub fn check(&self, keys: &[RateLimitKey]) -> Result<(), LogError> {
+        let _guard = self.lock.lock().unwrap();
+        self.check_at(keys, now_millis())
+    }
+
+    /// Record a request for all of the keys, even if they reached their limit.
+    pub fn record(&self, keys: &[RateLimitKey]) -> Result<(), LogError> {
+        let _guard = self.lock.lock().unwrap();
+        self.record_at(keys, now_millis())
+    }
+
+    fn check_and_record_at(&self, keys: &[RateLimitKey], now: u64) -> Result<(), LogError> {
+        let _guard = self.lock.lock().unwrap();
+        self.check_at(keys, now)?;
+        self.record_at(keys, now)
+    }
+
+    fn check_at(&self, keys: &[RateLimitKey], now: u64) -> Result<(), LogError> {
+        for key in keys {
+            let (limit, _) = self.limit(key);
+            if limit.max_requests == 0 {
+                continue;
+            }
+            let count = self
+                .store
+                .count_since(key, window_start(limit, now))
+                .map_err(|e| {
+                    log::error!("RateLimitStoreError {:#}.", e);
+                    LogError::RateLimitStoreError
+                })?;
+            if count >= limit.max_requests as usize {
+                log::error!("Rate limit for {:?} reached.", key);
+                return Err(LogError::RateLimitError(*key));
+            }
+        }
+        Ok(())
+    }
+
+    fn record_at(&self, keys: &[RateLimitKey], now: u64) -> Result<(), LogError> {
+        for key in keys {
+            let (limit, _) = self.limit(key);
+            if limit.max_requests == 0 {
+                continue;
+            }
+            self.store
+                .record(key, now, window_start(limit, now))
+                .map_err(|e| {
+                    log::error!("RateLimitStoreError {:#}.", e);
+                    LogError::RateLimitStoreError
+                })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACCOUNT: AccountAddress = AccountAddress([1; 32]);
+    const COIN_KEY: PublicKeyEd25519 = PublicKeyEd25519([2; 32]);
+
+    fn limits() -> RateLimits {
+        RateLimits {
+            account: RateLimit {
+                max_requests: 2,
+                window: Duration::from_secs(10),
+            },
+            coin_key: RateLimit {
+                max_requests: 1,
+                window: Duration::from_secs(10),
+            },
+            ip: RateLimit {
+                max_requests: 0,
+                window: Duration::from_secs(10),
+            },
+        }
+    }
+
+    fn check_sliding_window(store: Box<dyn RateLimitStore>) {
+        let limiter = RateLimiter::new(store, limits());
+        let keys = [RateLimitKey::Account(ACCOUNT)];
+
+        assert!(limiter.check_and_record_at(&keys, 0).is_ok());
+        assert!(limiter.check_and_record_at(&keys, 5_000).is_ok());
+        assert!(matches!(
+            limiter.check_and_record_at(&keys, 9_000),
+            Err(LogError::RateLimitError(RateLimitKey::Account(_)))
+        ));
+        // The first request drops out of the window.
+        assert!(limiter.check_and_record_at(&keys, 10_001).is_ok());
+        assert!(limiter.check_and_record_at(&keys, 12_000).is_err());
+    }
+
+    #[test]
+    fn test_memory_store_sliding_window() {
+        check_sliding_window(Box::<MemoryRateLimitStore>::default());
+    }
+
+    #[test]
+    fn test_sled_store_sliding_window() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        check_sliding_window(Box::new(SledRateLimitStore::new(
+            db.open_tree("rate_limits").unwrap(),
+        )));
+    }
+
+    /// Check that the store discards the keys whose records left their window
+    /// when it is pruned, given whether it contains a key.
+    fn check_prunes_expired_keys(
+        store: &dyn RateLimitStore,
+        contains: impl Fn(&RateLimitKey) -> bool,
+    ) {
+        let account = RateLimitKey::Account(ACCOUNT);
+        let coin_key = RateLimitKey::CoinKey(COIN_KEY);
+
+        store.record(&account, 0, 0).unwrap();
+        store.record(&coin_key, 1_000, 0).unwrap();
+        // The window of the account ends before the next pruning, the one of
+        // the coin key after it.
+        store.record(&account, 10_000, 5_000).unwrap();
+        store.record(&coin_key, 40_000, 0).unwrap();
+        assert!(contains(&account) && contains(&coin_key));
+        assert_eq!(store.count_since(&coin_key, 0).unwrap(), 2);
+
+        store
+            .record(&RateLimitKey::Ip([127, 0, 0, 1].into()), PRUNE_INTERVAL, 0)
+            .unwrap();
+        assert!(!contains(&account));
+        assert!(contains(&coin_key));
+        assert_eq!(store.count_since(&coin_key, 0).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_memory_store_prunes_expired_keys() {
+        let store = MemoryRateLimitStore::default();
+        check_prunes_expired_keys(&store, |key| {
+            store.records.lock().unwrap().keys.contains_key(key)
+        });
+    }
+
+    #[test]
+    fn test_sled_store_prunes_expired_keys() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = SledRateLimitStore::new(db.open_tree("rate_limits").unwrap());
+        check_prunes_expired_keys(&store, |key| {
+            store.tree.contains_key(key.to_bytes()).unwrap()
+        });
+    }
+
+    #[test]
+    fn test_check_and_record_separately() {
+        let limiter = RateLimiter::new(Box::<MemoryRateLimitStore>::default(), limits());
+        let coin_key = [RateLimitKey::CoinKey(COIN_KEY)];
+
+        // Checking does not use up the limit.
+        assert!(limiter.check(&coin_key).is_ok());
+        assert!(limiter.check(&coin_key).is_ok());
+        assert!(limiter.record(&coin_key).is_ok());
+        assert!(limiter.check(&coin_key).is_err());
+        // Requests are recorded even beyond the limit.
+        assert!(limiter.record(&coin_key).is_ok());
+        assert_eq!(limiter.usage(&coin_key[0]).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_rejected_request_is_not_recorded() {
+        let limiter = RateLimiter::new(Box::<MemoryRateLimitStore>::default(), limits());
+        let ip = RateLimitKey::Ip([127, 0, 0, 1].into());
+
+        assert!(limiter
+            .check_and_record_at(&[RateLimitKey::CoinKey(COIN_KEY), ip], 0)
+            .is_ok());
+        // The coin key limit is reached, so the account is not charged.
+        assert!(limiter
+            .check_and_record_at(
+                &[
+                    RateLimitKey::Account(ACCOUNT),
+                    RateLimitKey::CoinKey(COIN_KEY),
+                    ip
+                ],
+                1_000
+            )
+            .is_err());
+        assert!(limiter
+            .check_and_record_at(&[RateLimitKey::Account(ACCOUNT)], 1_000)
+            .is_ok());
+        assert!(limiter
+            .check_and_record_at(&[RateLimitKey::Account(ACCOUNT)], 1_000)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_override_and_reset() {
+        let limiter = RateLimiter::new(Box::<MemoryRateLimitStore>::default(), limits());
+        let account = RateLimitKey::Account(ACCOUNT);
+        let other = RateLimitKey::Account(AccountAddress([3; 32]));
+
+        limiter.set_limit(
+            account,
+            Some(RateLimit {
+                max_requests: 1,
+                window: Duration::from_secs(10),
+            }),
+        );
+        assert!(limiter.check_and_record_at(&[account], 0).is_ok());
+        assert!(limiter.check_and_record_at(&[account], 1_000).is_err());
+        // Other accounts keep the limit of their kind.
+        assert!(limiter.check_and_record_at(&[other], 1_000).is_ok());
+        assert!(limiter.check_and_record_at(&[other], 1_000).is_ok());
+
+        limiter.reset(&account).unwrap();
+        assert!(limiter.check_and_record_at(&[account], 2_000).is_ok());
+
+        limiter.set_limit(account, None);
+        assert_eq!(limiter.limit(&account), (limits().account, false));
+
+        for key in [
+            account,
+            RateLimitKey::CoinKey(COIN_KEY),
+            RateLimitKey::Ip([127, 0, 0, 1].into()),
+        ] {
+            assert_eq!(RateLimitKey::from_bytes(&key.to_bytes()), Some(key));
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::types::LogError;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+/// Wait for SIGINT or SIGTERM.
+pub async fn shutdown_signal() {
+    let interrupt = tokio::signal::ctrl_c();
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(terminate) => terminate,
+                Err(e) => {
+                    log::warn!("Could not listen for SIGTERM: {}", e);
+                    let _ = interrupt.await;
+                    return;
+                }
+            };
+        tokio::select! {
+            _ = interrupt => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = interrupt.await;
+    }
+}
+
+#[derive(Default)]
+struct DrainState {
+    /// Whether new submissions are refused.
+    closed: bool,
+    in_flight: usize,
+}
+
+/// Keeps track of the submissions in flight, so they can finish before the
+/// process exits.
+#[derive(Default)]
+pub struct Drain {
+    state: Mutex<DrainState>,
+    /// Notified whenever a submission finishes.
+    finished: Notify,
+}
+
+/// Marks a submission as in flight until it is dropped.
+pub struct InFlight<'a> {
+    drain: &'a Drain,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.drain.state.lock().unwrap().in_flight -= 1;
+        self.drain.finished.notify_waiters();
+    }
+}
+
+impl Drain {
+    /// Start a submission, unless the backend is shutting down.
+    pub fn enter(&self) -> Result<InFlight<'_>, LogError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(LogError::ShuttingDown);
+        }
+        state.in_flight += 1;
+        Ok(InFlight { drain: self })
+    }
+
+    /// Refuse new submissions.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+    }
+
+    /// The number of submissions in flight.
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    /// Wait until no submission is in flight.
+    pub async fn wait(&self) {
+        loop {
+            let finished = self.finished.notified();
+            tokio::pin!(finished);
+            finished.as_mut().enable();
+            if self.in_flight() == 0 {
+                return;
+            }
+            finished.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let drain = Arc::new(Drain::default());
+        let (entered, mut entered_rx) = tokio::sync::mpsc::channel(1);
+        let (release, release_rx) = tokio::sync::oneshot::channel::<()>();
+        let submission = tokio::spawn({
+            let drain = drain.clone();
+            async move {
+                let _in_flight = drain.enter().unwrap();
+                entered.send(()).await.unwrap();
+                release_rx.await.unwrap();
+            }
+        });
+        entered_rx.recv().await.unwrap();
+
+        drain.close();
+        assert!(matches!(drain.enter(), Err(LogError::ShuttingDown)));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), drain.wait())
+                .await
+                .is_err()
+        );
+
+        release.send(()).unwrap();
+        submission.await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), drain.wait())
+            .await
+            .unwrap();
+    }
+}
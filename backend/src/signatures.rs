@@ -0,0 +1,530 @@
+use crate::contract::{REDEEM_ENTRYPOINT, REDEEM_MERKLE_ENTRYPOINT};
+use crate::nodes::Nodes;
+use crate::types::{LogError, PermitMessage, RedeemMerkleParams, RedeemParams, RequestSignature};
+use concordium_rust_sdk::base::ed25519;
+use concordium_rust_sdk::common::types::{CredentialIndex, KeyIndex, Signature};
+use concordium_rust_sdk::endpoints::QueryError;
+use concordium_rust_sdk::id::types::VerifyKey;
+use concordium_rust_sdk::smart_contracts::common::{
+    self as contracts_common, AccountAddress, AccountSignatures, CredentialSignatures,
+    EntrypointName, PublicKeyEd25519, SignatureEd25519,
+};
+use concordium_rust_sdk::types::transactions::{
+    verify_data_signature, AccountAccessStructure, HasAccountAccessStructure,
+};
+use concordium_rust_sdk::types::AccountInfo;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Verify the signature of the account address by the coin key, as the
+/// smart contract does when redeeming the coin.
+pub fn verify_coin_signature(
+    public_key: &PublicKeyEd25519,
+    signature: &SignatureEd25519,
+    account: &AccountAddress,
+) -> Result<(), LogError> {
+    let key =
+        ed25519::PublicKey::from_bytes(&public_key.0).map_err(|_| LogError::PublicKeyError)?;
+    let signature = Signature {
+        sig: signature.0.to_vec(),
+    };
+    if VerifyKey::Ed25519VerifyKey(key).verify(account.0, &signature) {
+        Ok(())
+    } else {
+        Err(LogError::InvalidCoinSignature)
+    }
+}
+
+/// The coin key of a call of the entrypoint with the serialized payload, if it
+/// redeems a coin with `redeem` or `redeemMerkle`. The coin signature is
+/// verified, so redemptions sponsored with `POST /api/permit` are checked like
+/// those of `submitRedeem`.
+pub fn permit_coin_key(
+    entry_point: EntrypointName,
+    payload: &[u8],
+) -> Result<Option<PublicKeyEd25519>, LogError> {
+    let invalid = |_| LogError::PermitPayloadError("Invalid redeem parameter.".into());
+    let (public_key, signature, account) = if entry_point
+        == EntrypointName::new_unchecked(REDEEM_ENTRYPOINT)
+    {
+        let redeem: RedeemParams = contracts_common::from_bytes(payload).map_err(invalid)?;
+        (redeem.public_key, redeem.signature, redeem.account)
+    } else if entry_point == EntrypointName::new_unchecked(REDEEM_MERKLE_ENTRYPOINT) {
+        let redeem: RedeemMerkleParams = contracts_common::from_bytes(payload).map_err(invalid)?;
+        (redeem.public_key, redeem.signature, redeem.account)
+    } else {
+        return Ok(None);
+    };
+    verify_coin_signature(&public_key, &signature, &account)?;
+    Ok(Some(public_key))
+}
+
+fn decode_signature(signature: &str) -> Option<contracts_common::Signature> {
+    let mut bytes = [0; 64];
+    hex::decode_to_slice(signature, &mut bytes).ok()?;
+    Some(contracts_common::Signature::Ed25519(SignatureEd25519(
+        bytes,
+    )))
+}
+
+/// The signatures of the request in the form of the `permit` parameter. A
+/// single signature is the signature by the key 0 of the credential 0.
+pub fn account_signatures(signature: &RequestSignature) -> Result<AccountSignatures, LogError> {
+    let map = match signature {
+        RequestSignature::Single(signature) => {
+            let signature = decode_signature(signature).ok_or(LogError::SignatureError)?;
+            return Ok(AccountSignatures {
+                sigs: [(
+                    0,
+                    CredentialSignatures {
+                        sigs: [(0, signature)].into(),
+                    },
+                )]
+                .into(),
+            });
+        }
+        RequestSignature::Map(map) => map,
+    };
+    if map.is_empty() {
+        return Err(LogError::SignatureMapError("No signature is given.".into()));
+    }
+    // Only the canonical form of an index is accepted, so an index cannot be
+    // given twice, e.g., as "1" and "01".
+    let index = |index: &str, kind: &str| {
+        index
+            .parse::<u8>()
+            .ok()
+            .filter(|parsed| parsed.to_string() == index)
+            .ok_or_else(|| {
+                LogError::SignatureMapError(format!("{:?} is not a {} index.", index, kind))
+            })
+    };
+    let mut sigs = BTreeMap::new();
+    for (credential, signatures) in map {
+        let credential = index(credential, "credential")?;
+        if signatures.is_empty() {
+            return Err(LogError::SignatureMapError(format!(
+                "No signature is given for the credential {}.",
+                credential
+            )));
+        }
+        let mut credential_sigs = BTreeMap::new();
+        for (key, signature) in signatures {
+            let key = index(key, "key")?;
+            let signature = decode_signature(signature).ok_or_else(|| {
+                LogError::SignatureMapError(format!(
+                    "The signature by the key {} of the credential {} is not a hex encoded \
+                     Ed25519 signature.",
+                    key, credential
+                ))
+            })?;
+            credential_sigs.insert(key, signature);
+        }
+        sigs.insert(
+            credential,
+            CredentialSignatures {
+                sigs: credential_sigs,
+            },
+        );
+    }
+    Ok(AccountSignatures { sigs })
+}
+
+/// Check that the signatures are given by keys of the account and meet its
+/// thresholds, so a signature map that cannot be valid is reported precisely.
+fn check_structure(
+    keys: &AccountAccessStructure,
+    signatures: &AccountSignatures,
+) -> Result<(), LogError> {
+    let error = |message: String| Err(LogError::SignatureMapError(message));
+    let threshold = u8::from(keys.threshold);
+    if signatures.sigs.len() < usize::from(threshold) {
+        return error(format!(
+            "The signer account requires signatures by {} credentials.",
+            threshold
+        ));
+    }
+    for (&credential, credential_signatures) in &signatures.sigs {
+        let Some(credential_keys) = keys.credential_keys(CredentialIndex { index: credential })
+        else {
+            return error(format!(
+                "The signer account has no credential {}.",
+                credential
+            ));
+        };
+        let threshold = u8::from(credential_keys.threshold);
+        if credential_signatures.sigs.len() < usize::from(threshold) {
+            return error(format!(
+                "The credential {} of the signer account requires signatures by {} keys.",
+                credential, threshold
+            ));
+        }
+        if let Some(key) = credential_signatures
+            .sigs
+            .keys()
+            .find(|&&key| credential_keys.get(KeyIndex(key)).is_none())
+        {
+            return error(format!(
+                "The credential {} of the signer account has no key {}.",
+                credential, key
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The hash of the message signed by the signer account, as computed by the
+/// `viewMessageHash` function of the smart contract. The wallet prepends the
+/// account address and 8 zero bytes to the message, so a message cannot be a
+/// transaction.
+pub fn message_hash(signer: &AccountAddress, message: &PermitMessage) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(signer.0);
+    hasher.update([0u8; 8]);
+    hasher.update(contracts_common::to_bytes(message));
+    hasher.finalize().into()
+}
+
+/// Verify the signatures of the message hash against the keys of the signer
+/// account, as the `check_account_signature` host function does: the
+/// signatures of at least the account threshold of credentials must be given,
+/// each with at least the signature threshold of its credential, and all of
+/// them must be valid.
+pub fn verify_account_signature(
+    keys: &AccountAccessStructure,
+    message_hash: &[u8; 32],
+    signatures: &AccountSignatures,
+) -> Result<(), LogError> {
+    check_structure(keys, signatures)?;
+    let signatures: BTreeMap<CredentialIndex, BTreeMap<KeyIndex, Signature>> = signatures
+        .sigs
+        .iter()
+        .map(|(&credential, credential_signatures)| {
+            let signatures = credential_signatures
+                .sigs
+                .iter()
+                .map(|(&key, signature)| {
+                    let sig = match signature {
+                        contracts_common::Signature::Ed25519(signature) => signature.0.to_vec(),
+                        // Other kinds of signatures cannot be verified and
+                        // are rejected as invalid.
+                        _ => Vec::new(),
+                    };
+                    (KeyIndex(key), Signature { sig })
+                })
+                .collect();
+            (CredentialIndex { index: credential }, signatures)
+        })
+        .collect();
+    if verify_data_signature(keys, message_hash, &signatures) {
+        Ok(())
+    } else {
+        Err(LogError::InvalidAccountSignature)
+    }
+}
+
+/// The keys of all credentials of the account.
+fn access_structure(info: &AccountInfo) -> AccountAccessStructure {
+    AccountAccessStructure {
+        keys: info
+            .account_credentials
+            .keys()
+            .filter_map(|&index| Some((index, info.credential_keys(index)?.clone())))
+            .collect(),
+        threshold: info.account_threshold,
+    }
+}
+
+/// How long an account unknown to the node is remembered as unknown, at most
+/// the TTL of the cache. It is short, so accounts created meanwhile are soon
+/// accepted.
+const UNKNOWN_ACCOUNT_TTL: Duration = Duration::from_secs(30);
+
+/// How often the expired entries of the [`AccountKeysCache`] are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Caches the keys of signer accounts queried from the node, so requests with
+/// invalid signatures are rejected without a request to the node. Updates of
+/// the keys of an account are noticed once its entry expires. Accounts that
+/// do not exist are cached as well, for [`UNKNOWN_ACCOUNT_TTL`].
+pub struct AccountKeysCache {
+    ttl: Duration,
+    keys: Mutex<CachedKeys>,
+}
+
+struct CachedKeys {
+    /// The keys of each account, `None` if it does not exist, and when the
+    /// entry expires.
+    accounts: HashMap<AccountAddress, (Option<Arc<AccountAccessStructure>>, Instant)>,
+    /// When the expired entries are dropped next.
+    next_prune: Instant,
+}
+
+impl AccountKeysCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            keys: Mutex::new(CachedKeys {
+                accounts: HashMap::new(),
+                next_prune: Instant::now(),
+            }),
+        }
+    }
+
+    /// The cached keys of the account, `Some(None)` if it is cached as
+    /// unknown.
+    fn get_at(
+        &self,
+        account: &AccountAddress,
+        now: Instant,
+    ) -> Option<Option<Arc<AccountAccessStructure>>> {
+        let mut keys = self.keys.lock().unwrap();
+        let (structure, expires) = keys.accounts.get(account)?;
+        if now < *expires {
+            Some(structure.clone())
+        } else {
+            keys.accounts.remove(account);
+            None
+        }
+    }
+
+    fn insert_at(
+        &self,
+        account: AccountAddress,
+        structure: Option<Arc<AccountAccessStructure>>,
+        now: Instant,
+    ) {
+        let ttl = match structure {
+            Some(_) => self.ttl,
+            None => self.ttl.min(UNKNOWN_ACCOUNT_TTL),
+        };
+        if ttl.is_zero() {
+            return;
+        }
+        let mut keys = self.keys.lock().unwrap();
+        // Expired entries are dropped periodically, so accounts that sign only
+        // once do not pile up.
+        if now >= keys.next_prune {
+            keys.accounts.retain(|_, (_, expires)| now < *expires);
+            keys.next_prune = now + PRUNE_INTERVAL;
+        }
+        keys.accounts.insert(account, (structure, now + ttl));
+    }
+
+    /// The keys of the account, queried from the node if they are not cached.
+    pub async fn get(
+        &self,
+        nodes: &Nodes,
+        account: &AccountAddress,
+    ) -> Result<Arc<AccountAccessStructure>, LogError> {
+        match self.get_at(account, Instant::now()) {
+            Some(Some(structure)) => return Ok(structure),
+            Some(None) => return Err(LogError::UnknownSigner),
+            None => {}
+        }
+        match nodes.get_account_info(account).await {
+            Ok(info) => {
+                let structure = Arc::new(access_structure(&info));
+                self.insert_at(*account, Some(structure.clone()), Instant::now());
+                Ok(structure)
+            }
+            Err(QueryError::NotFound) => {
+                self.insert_at(*account, None, Instant::now());
+                Err(LogError::UnknownSigner)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use concordium_rust_sdk::common::types::KeyPair;
+    use concordium_rust_sdk::smart_contracts::common::{
+        ContractAddress, CredentialSignatures, OwnedEntrypointName, Timestamp,
+    };
+
+    fn key_pair(seed: u8) -> KeyPair {
+        let secret = ed25519::SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = ed25519::PublicKey::from(&secret);
+        KeyPair { secret, public }
+    }
+
+    fn signature(key: &KeyPair, message: &[u8]) -> SignatureEd25519 {
+        SignatureEd25519(key.sign(message).sig.try_into().unwrap())
+    }
+
+    #[test]
+    fn test_coin_signature() {
+        let coin = key_pair(1);
+        let public_key = PublicKeyEd25519(coin.public.to_bytes());
+        let account = AccountAddress([2; 32]);
+        let signature = signature(&coin, &account.0);
+
+        assert!(verify_coin_signature(&public_key, &signature, &account).is_ok());
+        assert!(matches!(
+            verify_coin_signature(&public_key, &signature, &AccountAddress([3; 32])),
+            Err(LogError::InvalidCoinSignature)
+        ));
+    }
+
+    #[test]
+    fn test_permit_coin_key() {
+        let coin = key_pair(1);
+        let public_key = PublicKeyEd25519(coin.public.to_bytes());
+        let account = AccountAddress([2; 32]);
+        let merkle = |signature| {
+            contracts_common::to_bytes(&RedeemMerkleParams {
+                public_key,
+                signature,
+                account,
+                amount: contracts_common::Amount::from_micro_ccd(1000),
+                root: [4; 32],
+                leaf_index: 0,
+                proof: vec![[5; 32]],
+            })
+        };
+        let redeem_merkle = EntrypointName::new_unchecked(REDEEM_MERKLE_ENTRYPOINT);
+
+        let key = permit_coin_key(redeem_merkle, &merkle(signature(&coin, &account.0))).unwrap();
+        assert_eq!(key, Some(public_key));
+        let redeem = contracts_common::to_bytes(&RedeemParams {
+            public_key,
+            signature: signature(&coin, &account.0),
+            account,
+        });
+        let key = permit_coin_key(EntrypointName::new_unchecked(REDEEM_ENTRYPOINT), &redeem);
+        assert_eq!(key.unwrap(), Some(public_key));
+        assert!(matches!(
+            permit_coin_key(redeem_merkle, &merkle(signature(&coin, &[3; 32]))),
+            Err(LogError::InvalidCoinSignature)
+        ));
+        assert!(matches!(
+            permit_coin_key(redeem_merkle, &redeem),
+            Err(LogError::PermitPayloadError(_))
+        ));
+        let transfer = EntrypointName::new_unchecked("transfer");
+        assert_eq!(permit_coin_key(transfer, &[]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_account_signature() {
+        let account_key = key_pair(1);
+        let keys = AccountAccessStructure::singleton(account_key.public);
+        let signer = AccountAddress([2; 32]);
+        let message = PermitMessage {
+            contract_address: ContractAddress::new(4184, 0),
+            nonce: 0,
+            timestamp: Timestamp::from_timestamp_millis(0),
+            entry_point: OwnedEntrypointName::new_unchecked("redeem".into()),
+            payload: vec![1, 2, 3],
+        };
+        let hash = message_hash(&signer, &message);
+        let signatures = |credential, key, signature| AccountSignatures {
+            sigs: [(
+                credential,
+                CredentialSignatures {
+                    sigs: [(key, contracts_common::Signature::Ed25519(signature))].into(),
+                },
+            )]
+            .into(),
+        };
+
+        let valid = signature(&account_key, &hash);
+        assert!(verify_account_signature(&keys, &hash, &signatures(0, 0, valid)).is_ok());
+        let invalid = signature(&key_pair(3), &hash);
+        assert!(matches!(
+            verify_account_signature(&keys, &hash, &signatures(0, 0, invalid)),
+            Err(LogError::InvalidAccountSignature)
+        ));
+        // Signatures by keys or credentials the account does not have.
+        for signatures in [signatures(0, 1, valid), signatures(1, 0, valid)] {
+            assert!(matches!(
+                verify_account_signature(&keys, &hash, &signatures),
+                Err(LogError::SignatureMapError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_account_signatures() {
+        let hex_signature = hex::encode([1; 64]);
+        let single = account_signatures(&RequestSignature::Single(hex_signature.clone())).unwrap();
+        assert_eq!(single.sigs.len(), 1);
+        assert!(single.sigs[&0].sigs.contains_key(&0));
+
+        let map: RequestSignature = serde_json::from_value(serde_json::json!({
+            "0": {"0": hex_signature, "2": hex_signature},
+            "1": {"1": hex_signature},
+        }))
+        .unwrap();
+        let map = account_signatures(&map).unwrap();
+        assert_eq!(map.sigs.len(), 2);
+        assert_eq!(map.sigs[&0].sigs.len(), 2);
+        assert!(map.sigs[&1].sigs.contains_key(&1));
+
+        for invalid in [
+            serde_json::json!({}),
+            serde_json::json!({"0": {}}),
+            serde_json::json!({"0": {"0": "00"}}),
+            serde_json::json!({"256": {"0": hex_signature}}),
+        ] {
+            let invalid: RequestSignature = serde_json::from_value(invalid).unwrap();
+            assert!(matches!(
+                account_signatures(&invalid),
+                Err(LogError::SignatureMapError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_cache_expires() {
+        let cache = AccountKeysCache::new(Duration::from_secs(10));
+        let account = AccountAddress([1; 32]);
+        let structure = Arc::new(AccountAccessStructure::singleton(key_pair(1).public));
+        let now = Instant::now();
+
+        cache.insert_at(account, Some(structure), now);
+        assert!(matches!(
+            cache.get_at(&account, now + Duration::from_secs(9)),
+            Some(Some(_))
+        ));
+        assert!(cache
+            .get_at(&account, now + Duration::from_secs(10))
+            .is_none());
+
+        // Unknown accounts are remembered for a shorter time.
+        let unknown = AccountAddress([2; 32]);
+        let cache = AccountKeysCache::new(Duration::from_secs(300));
+        cache.insert_at(unknown, None, now);
+        assert!(matches!(
+            cache.get_at(&unknown, now + UNKNOWN_ACCOUNT_TTL / 2),
+            Some(None)
+        ));
+        assert!(cache.get_at(&unknown, now + UNKNOWN_ACCOUNT_TTL).is_none());
+    }
+
+    #[test]
+    fn test_cache_prunes_expired_entries() {
+        let cache = AccountKeysCache::new(Duration::from_secs(10));
+        let structure = Arc::new(AccountAccessStructure::singleton(key_pair(1).public));
+        let now = Instant::now();
+
+        cache.insert_at(AccountAddress([1; 32]), Some(structure.clone()), now);
+        cache.insert_at(AccountAddress([2; 32]), None, now);
+        // Expired entries are kept until the next pruning.
+        let later = now + Duration::from_secs(20);
+        cache.insert_at(AccountAddress([3; 32]), Some(structure.clone()), later);
+        assert_eq!(cache.keys.lock().unwrap().accounts.len(), 3);
+
+        cache.insert_at(
+            AccountAddress([3; 32]),
+            Some(structure),
+            now + PRUNE_INTERVAL,
+        );
+        assert_eq!(cache.keys.lock().unwrap().accounts.len(), 1);
+    }
+}
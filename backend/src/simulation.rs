@@ -0,0 +1,214 @@
+use crate::contract::ContractInstance;
+use crate::types::RevertReason;
+use concordium_rust_sdk::smart_contracts::common::{
+    AccountAddress, Amount, ContractAddress, Cursor,
+};
+use concordium_rust_sdk::types::smart_contracts::{
+    ContractEvent, OwnedParameter, OwnedReceiveName,
+};
+use concordium_rust_sdk::types::transactions::{
+    construct::TRANSACTION_HEADER_SIZE, cost, Payload, PayloadLike, UpdateContractPayload,
+};
+use concordium_rust_sdk::types::{ContractTraceElement, Energy, RejectReason};
+
+/// The outcome of a simulated sponsored transaction, as returned by
+/// `POST /api/simulate`.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulationReport {
+    pub success: bool,
+    /// The energy used by the execution of the contract.
+    pub used_energy: u64,
+    /// The energy the transaction would be given, including the energy for
+    /// its size and signatures.
+    pub energy: u64,
+    /// The fee the sponsor account would pay, if the exchange rate could be
+    /// queried from the node.
+    pub estimated_fee: Option<Amount>,
+    /// The events the contracts would log.
+    pub events: Vec<SimulatedEvent>,
+    /// The CCD the contracts would transfer to accounts, e.g., the amount of a
+    /// redeemed coin.
+    pub transfers: Vec<SimulatedTransfer>,
+    /// The name of the contract error, if the contract would reject the
+    /// transaction and its schema describes the error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reject_reason: Option<RejectReason>,
+}
+
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub struct SimulatedEvent {
+    pub contract: ContractAddress,
+    /// The event, hex encoded.
+    pub event: String,
+    /// The event in JSON, if it was logged by the contract instance of the
+    /// request and its schema describes the events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decoded: Option<serde_json::Value>,
+}
+
+#[derive(serde::Serialize, Debug, PartialEq)]
+pub struct SimulatedTransfer {
+    pub from: ContractAddress,
+    pub to: AccountAddress,
+    pub amount: Amount,
+}
+
+/// The energy for the size and signatures of the transaction calling the
+/// contract, which is charged in addition to the energy of the execution.
+pub fn header_energy(
+    contract: ContractAddress,
+    receive_name: &OwnedReceiveName,
+    parameter: &OwnedParameter,
+    num_signatures: u32,
+) -> Energy {
+    let payload = Payload::Update {
+        payload: UpdateContractPayload {
+            amount: Amount::zero(),
+            address: contract,
+            receive_name: receive_name.clone(),
+            message: parameter.clone(),
+        },
+    };
+    let size = TRANSACTION_HEADER_SIZE + u64::from(u32::from(payload.encode().size()));
+    cost::base_cost(size, num_signatures)
+}
+
+impl SimulationReport {
+    /// The report of a transaction the contract would reject.
+    pub fn failure(used_energy: Energy, energy: Energy, revert_reason: RevertReason) -> Self {
+        Self {
+            success: false,
+            used_energy: used_energy.energy,
+            energy: energy.energy,
+            estimated_fee: None,
+            events: Vec::new(),
+            transfers: Vec::new(),
+            code: revert_reason.code,
+            reject_reason: Some(revert_reason.reason),
+        }
+    }
+
+    /// The report of a successful transaction with the given trace.
+    pub fn success(
+        used_energy: Energy,
+        energy: Energy,
+        contract: &ContractInstance,
+        trace: &[ContractTraceElement],
+    ) -> Self {
+        let event_schema = contract
+            .schema
+            .as_deref()
+            .and_then(|schema| schema.get_event_schema(&contract.name).ok());
+        let event = |address: ContractAddress, event: &ContractEvent| SimulatedEvent {
+            contract: address,
+            event: hex::encode(event.as_ref()),
+            decoded: event_schema
+                .as_ref()
+                .filter(|_| address == contract.address)
+                .and_then(|schema| schema.to_json(&mut Cursor::new(event.as_ref())).ok()),
+        };
+
+        let mut events = Vec::new();
+        let mut transfers = Vec::new();
+        for element in trace {
+            match element {
+                ContractTraceElement::Updated { data } => {
+                    events.extend(data.events.iter().map(|e| event(data.address, e)))
+                }
+                ContractTraceElement::Interrupted { address, events: e } => {
+                    events.extend(e.iter().map(|e| event(*address, e)))
+                }
+                ContractTraceElement::Transferred { from, amount, to } => {
+                    transfers.push(SimulatedTransfer {
+                        from: *from,
+                        to: *to,
+                        amount: *amount,
+                    })
+                }
+                ContractTraceElement::Resumed { .. } | ContractTraceElement::Upgraded { .. } => {}
+            }
+        }
+
+        Self {
+            success: true,
+            used_energy: used_energy.energy,
+            energy: energy.energy,
+            estimated_fee: None,
+            events,
+            transfers,
+            code: None,
+            reject_reason: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use concordium_rust_sdk::types::smart_contracts::InstanceUpdatedEvent;
+    use concordium_rust_sdk::types::Address;
+
+    #[test]
+    fn test_success_report() {
+        let contract =
+            ContractInstance::new("ccd_redeem".into(), ContractAddress::new(4184, 0), None);
+        let receive_name = contract.receive_name("permit").unwrap();
+        let account = AccountAddress([1; 32]);
+        let trace = [
+            ContractTraceElement::Interrupted {
+                address: contract.address,
+                events: vec![ContractEvent::from(vec![1, 2])],
+            },
+            ContractTraceElement::Transferred {
+                from: contract.address,
+                amount: Amount::from_micro_ccd(5),
+                to: account,
+            },
+            ContractTraceElement::Updated {
+                data: InstanceUpdatedEvent {
+                    contract_version: Default::default(),
+                    address: contract.address,
+                    instigator: Address::Account(account),
+                    amount: Amount::zero(),
+                    message: OwnedParameter::empty(),
+                    receive_name: receive_name.clone(),
+                    events: vec![ContractEvent::from(vec![3])],
+                },
+            },
+        ];
+
+        let report = SimulationReport::success(
+            Energy { energy: 1000 },
+            Energy { energy: 1500 },
+            &contract,
+            &trace,
+        );
+        assert!(report.success);
+        let events: Vec<&str> = report.events.iter().map(|e| e.event.as_str()).collect();
+        assert_eq!(events, ["0102", "03"]);
+        assert_eq!(
+            report.transfers,
+            [SimulatedTransfer {
+                from: contract.address,
+                to: account,
+                amount: Amount::from_micro_ccd(5),
+            }]
+        );
+
+        // The header energy grows with the size of the parameter and the
+        // number of signatures.
+        let small = header_energy(contract.address, &receive_name, &OwnedParameter::empty(), 1);
+        let large = OwnedParameter::try_from(vec![0; 100]).unwrap();
+        assert_eq!(
+            header_energy(contract.address, &receive_name, &large, 1).energy,
+            small.energy + 100
+        );
+        assert_eq!(
+            header_energy(contract.address, &receive_name, &large, 2).energy,
+            small.energy + 100 + cost::A
+        );
+    }
+}
@@ -0,0 +1,284 @@
+use crate::balance::available_balance;
+use crate::contract::{ContractInstance, REDEEM_ENTRYPOINT, REDEEM_MERKLE_ENTRYPOINT};
+use crate::nodes::Nodes;
+use anyhow::{ensure, Context};
+use concordium_rust_sdk::smart_contracts::common::{
+    schema::{Fields, SchemaType, SizeLength, Type, VersionedModuleSchema},
+    AccountAddress, AccountSignatures, Amount, ContractAddress, OwnedEntrypointName,
+    PublicKeyEd25519, SignatureEd25519, Timestamp,
+};
+use concordium_rust_sdk::types::smart_contracts::InstanceInfo;
+use concordium_rust_sdk::v2::BlockIdentifier;
+
+/// Check that the sponsor account has at least `min_balance` available.
+pub async fn check_sponsor_balance(
+    mut client: concordium_rust_sdk::v2::Client,
+    address: AccountAddress,
+    min_balance: Amount,
+) -> anyhow::Result<()> {
+    let info = client
+        .get_account_info(&address.into(), BlockIdentifier::LastFinal)
+        .await
+        .context("Could not get the account info of the sponsor account.")?;
+    let balance = available_balance(&info.response);
+    ensure!(
+        balance >= min_balance,
+        "The sponsor account {} has a balance of {} CCD, but at least {} CCD are required.",
+        address,
+        balance,
+        min_balance
+    );
+    Ok(())
+}
+
+/// Check that the contract instance exists and is a `ccd_redeem` instance
+/// that the backend can sponsor redemptions for.
+pub async fn check_contract_instance(
+    nodes: &Nodes,
+    contract: &ContractInstance,
+) -> anyhow::Result<()> {
+    let info = nodes
+        .client()?
+        .get_instance_info(contract.address, &BlockIdentifier::LastFinal)
+        .await
+        .with_context(|| format!("Could not get the contract instance {}.", contract.address))?;
+    let name = match &info.response {
+        InstanceInfo::V0 { name, .. } | InstanceInfo::V1 { name, .. } => name,
+    };
+    ensure!(
+        name.as_contract_name().contract_name() == contract.name,
+        "The contract instance {} is an instance of {}, but init_{} is expected.",
+        contract.address,
+        name,
+        contract.name
+    );
+
+    check_supports_permit(nodes, contract).await?;
+
+    let schema = contract.schema.as_deref().with_context(|| {
+        format!(
+            "The module of the contract instance {} has no embedded schema.",
+            contract.address
+        )
+    })?;
+    check_schema(schema, &contract.name).with_context(|| {
+        format!(
+            "The contract instance {} is not supported.",
+            contract.address
+        )
+    })
+}
+
+/// Check that `permit` of the contract supports the `redeem` entrypoint.
+async fn check_supports_permit(nodes: &Nodes, contract: &ContractInstance) -> anyhow::Result<()> {
+    let supported = contract
+        .supports_permit(
+            nodes,
+            &OwnedEntrypointName::new_unchecked(REDEEM_ENTRYPOINT.into()),
+        )
+        .await
+        .context("Could not invoke supportsPermit.")?;
+    ensure!(
+        supported,
+        "The permit function of the contract instance {} does not support {}.",
+        contract.address,
+        REDEEM_ENTRYPOINT
+    );
+    Ok(())
+}
+
+fn named(fields: Vec<(&str, Type)>) -> Type {
+    Type::Struct(Fields::Named(
+        fields
+            .into_iter()
+            .map(|(name, ty)| (name.into(), ty))
+            .collect(),
+    ))
+}
+
+/// The schema of `PermitParam` in `types.rs`.
+fn permit_param_type() -> Type {
+    named(vec![
+        ("signature", AccountSignatures::get_type()),
+        ("signer", AccountAddress::get_type()),
+        (
+            "message",
+            named(vec![
+                ("contract_address", ContractAddress::get_type()),
+                ("nonce", u64::get_type()),
+                ("timestamp", Timestamp::get_type()),
+                ("entry_point", OwnedEntrypointName::get_type()),
+                ("payload", Type::List(SizeLength::U16, Box::new(Type::U8))),
+            ]),
+        ),
+    ])
+}
+
+/// The schema of `RedeemParams` in `types.rs`.
+fn redeem_params_type() -> Type {
+    named(vec![
+        ("public_key", PublicKeyEd25519::get_type()),
+        ("signature", SignatureEd25519::get_type()),
+        ("account", AccountAddress::get_type()),
+    ])
+}
+
+/// The schema of `RedeemMerkleParams` in `types.rs`.
+fn redeem_merkle_params_type() -> Type {
+    named(vec![
+        ("public_key", PublicKeyEd25519::get_type()),
+        ("signature", SignatureEd25519::get_type()),
+        ("account", AccountAddress::get_type()),
+        ("amount", Amount::get_type()),
+        ("root", Type::ByteArray(32)),
+        ("leaf_index", u32::get_type()),
+        (
+            "proof",
+            Type::List(SizeLength::U32, Box::new(Type::ByteArray(32))),
+        ),
+    ])
+}
+
+/// The schema of `ViewCoinReturnData` in `types.rs`.
+fn view_coin_return_type() -> Type {
+    named(vec![
+        ("amount", Amount::get_type()),
+        ("is_redeemed", bool::get_type()),
+    ])
+}
+
+/// Check that the types in the schema of the contract match the types used by
+/// the backend to communicate with the contract.
+pub fn check_schema(schema: &VersionedModuleSchema, contract_name: &str) -> anyhow::Result<()> {
+    let check = |function: &str, kind: &str, actual: Option<Type>, expected: Type| {
+        let actual = actual.with_context(|| {
+            format!("The schema does not describe the {} of {}.", kind, function)
+        })?;
+        ensure!(
+            actual == expected,
+            "The {} of {} in the schema does not match the type expected by the backend.",
+            kind,
+            function
+        );
+        Ok(())
+    };
+    check(
+        "permit",
+        "parameter",
+        schema
+            .get_receive_param_schema(contract_name, "permit")
+            .ok(),
+        permit_param_type(),
+    )?;
+    check(
+        REDEEM_ENTRYPOINT,
+        "parameter",
+        schema
+            .get_receive_param_schema(contract_name, REDEEM_ENTRYPOINT)
+            .ok(),
+        redeem_params_type(),
+    )?;
+    // Older versions of the contract cannot redeem coins of Merkle batches, so
+    // the entrypoint is only checked if the schema describes it.
+    if let Ok(actual) = schema.get_receive_param_schema(contract_name, REDEEM_MERKLE_ENTRYPOINT) {
+        check(
+            REDEEM_MERKLE_ENTRYPOINT,
+            "parameter",
+            Some(actual),
+            redeem_merkle_params_type(),
+        )?;
+    }
+    check(
+        "viewCoin",
+        "parameter",
+        schema
+            .get_receive_param_schema(contract_name, "viewCoin")
+            .ok(),
+        PublicKeyEd25519::get_type(),
+    )?;
+    check(
+        "viewCoin",
+        "return value",
+        schema
+            .get_receive_return_value_schema(contract_name, "viewCoin")
+            .ok(),
+        view_coin_return_type(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use concordium_rust_sdk::smart_contracts::common::schema::{ContractV3, FunctionV2, ModuleV3};
+    use std::collections::BTreeMap;
+
+    fn schema(
+        view_coin_return_value: Type,
+        redeem_merkle_param: Option<Type>,
+    ) -> VersionedModuleSchema {
+        let function = |parameter, return_value| FunctionV2 {
+            parameter: Some(parameter),
+            return_value,
+            error: None,
+        };
+        let mut receive = BTreeMap::from([
+            ("permit".into(), function(permit_param_type(), None)),
+            ("redeem".into(), function(redeem_params_type(), None)),
+            (
+                "viewCoin".into(),
+                function(PublicKeyEd25519::get_type(), Some(view_coin_return_value)),
+            ),
+        ]);
+        if let Some(parameter) = redeem_merkle_param {
+            receive.insert("redeemMerkle".into(), function(parameter, None));
+        }
+        VersionedModuleSchema::V3(ModuleV3 {
+            contracts: BTreeMap::from([(
+                "ccd_redeem".into(),
+                ContractV3 {
+                    init: None,
+                    receive,
+                    event: None,
+                },
+            )]),
+        })
+    }
+
+    #[test]
+    fn test_redeem_merkle_params_schema() {
+        use crate::types::RedeemMerkleParams;
+        use concordium_rust_sdk::smart_contracts::common::{to_bytes, Cursor};
+
+        let params = RedeemMerkleParams {
+            public_key: PublicKeyEd25519([1; 32]),
+            signature: SignatureEd25519([2; 64]),
+            account: AccountAddress([3; 32]),
+            amount: Amount::from_micro_ccd(5),
+            root: [4; 32],
+            leaf_index: 6,
+            proof: vec![[7; 32], [8; 32]],
+        };
+        let bytes = to_bytes(&params);
+        let mut cursor = Cursor::new(&bytes[..]);
+        let json = redeem_merkle_params_type().to_json(&mut cursor).unwrap();
+        assert_eq!(cursor.offset, bytes.len());
+        assert_eq!(json["amount"], "5");
+        assert_eq!(json["leaf_index"], 6);
+        assert_eq!(json["proof"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_check_schema() {
+        let check = |view_coin_return_value, redeem_merkle_param| {
+            check_schema(
+                &schema(view_coin_return_value, redeem_merkle_param),
+                "ccd_redeem",
+            )
+        };
+        assert!(check(view_coin_return_type(), None).is_ok());
+        assert!(check(view_coin_return_type(), Some(redeem_merkle_params_type())).is_ok());
+        assert!(check(view_coin_return_type(), Some(redeem_params_type())).is_err());
+        assert!(check(Amount::get_type(), None).is_err());
+        assert!(check_schema(&schema(view_coin_return_type(), None), "cis3_nft").is_err());
+    }
+}
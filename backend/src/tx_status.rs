@@ -0,0 +1,506 @@
+use crate::contract::ContractInstance;
+use crate::handlers::decode_reject_code;
+use crate::nodes::Nodes;
+use concordium_rust_sdk::endpoints::QueryError;
+use concordium_rust_sdk::smart_contracts::common::schema::VersionedModuleSchema;
+use concordium_rust_sdk::smart_contracts::common::{Amount, ContractAddress};
+use concordium_rust_sdk::types::hashes::{BlockHash, TransactionHash};
+use concordium_rust_sdk::types::{
+    BlockItemSummary, BlockItemSummaryDetails, RejectReason, TransactionStatus,
+};
+use futures::StreamExt;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+
+/// How long a sponsored transaction is valid after it was signed.
+pub const TRANSACTION_EXPIRY: Duration = Duration::from_secs(3600);
+
+/// How long the status of a transaction is kept after it last changed.
+/// Sponsored transactions expire after one hour, so their status is final by
+/// then.
+const TX_STATUS_RETENTION: Duration = Duration::from_secs(2 * 3600);
+
+/// How long to wait before reconnecting to the stream of finalized blocks.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The status of a sponsored transaction submitted by the backend.
+#[derive(serde::Serialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum TxStatus {
+    /// The transaction was received by the node, but is not in any block yet.
+    Pending,
+    /// The transaction is in a block that is not finalized yet.
+    #[serde(rename_all = "camelCase")]
+    Committed { block_hash: BlockHash },
+    /// The transaction was executed successfully in a finalized block. The
+    /// sponsor account paid `cost` for it.
+    #[serde(rename_all = "camelCase")]
+    Finalized { block_hash: BlockHash, cost: Amount },
+    /// The transaction was rejected in a finalized block. If the smart
+    /// contract rejected it, `code` is the name of the contract error.
+    #[serde(rename_all = "camelCase")]
+    Failed {
+        block_hash: BlockHash,
+        cost: Amount,
+        reject_reason: RejectReason,
+        code: Option<String>,
+    },
+    /// The transaction is not known to the node and has expired, so it will
+    /// never be executed. The sponsor account paid nothing for it.
+    Expired,
+}
+
+impl PartialEq for TxStatus {
+    fn eq(&self, other: &Self) -> bool {
+        // The outcome of a transaction is determined by the block it is in.
+        match (self, other) {
+            (TxStatus::Pending, TxStatus::Pending) | (TxStatus::Expired, TxStatus::Expired) => true,
+            (TxStatus::Committed { block_hash: a }, TxStatus::Committed { block_hash: b })
+            | (
+                TxStatus::Finalized { block_hash: a, .. },
+                TxStatus::Finalized { block_hash: b, .. },
+            )
+            | (TxStatus::Failed { block_hash: a, .. }, TxStatus::Failed { block_hash: b, .. }) => {
+                a == b
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TxStatus {
+    /// Whether the status can no longer change.
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            TxStatus::Finalized { .. } | TxStatus::Failed { .. } | TxStatus::Expired
+        )
+    }
+
+    /// The amount the sponsor account paid for the transaction, if it is
+    /// final.
+    pub fn cost(&self) -> Option<Amount> {
+        match self {
+            TxStatus::Finalized { cost, .. } | TxStatus::Failed { cost, .. } => Some(*cost),
+            TxStatus::Pending | TxStatus::Committed { .. } | TxStatus::Expired => None,
+        }
+    }
+}
+
+/// A status change of a tracked transaction.
+#[derive(Debug, Clone)]
+pub struct TxStatusUpdate {
+    pub tx_hash: TransactionHash,
+    pub status: TxStatus,
+}
+
+struct TrackedTx {
+    status: TxStatus,
+    /// The contract instance the transaction was sent to.
+    contract: ContractAddress,
+    /// The schema of the contract the transaction was sent to, used to decode
+    /// the contract error if the transaction is rejected.
+    schema: Option<Arc<VersionedModuleSchema>>,
+    /// When the transaction was sent, at the latest.
+    sent: Instant,
+    /// When the status last changed.
+    updated: Instant,
+}
+
+/// Tracks the status of the transactions submitted by the backend.
+///
+/// The status of the transactions that are not final yet is queried from the
+/// node whenever a block is finalized. Status changes are broadcast to the
+/// subscribers, and final statuses are also sent to the receivers of
+/// [`outcomes`](Self::outcomes).
+pub struct TxTracker {
+    transactions: Mutex<BTreeMap<TransactionHash, TrackedTx>>,
+    updates: broadcast::Sender<TxStatusUpdate>,
+    outcomes: Mutex<Vec<mpsc::UnboundedSender<TxStatusUpdate>>>,
+}
+
+impl TxTracker {
+    pub fn new() -> Self {
+        let (updates, _) = broadcast::channel(256);
+        Self {
+            transactions: Mutex::new(BTreeMap::new()),
+            updates,
+            outcomes: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Start tracking a transaction submitted to the contract instance. The
+    /// schema of the contract is used to decode the contract error if the
+    /// transaction is rejected.
+    pub fn track(&self, tx_hash: TransactionHash, contract: &ContractInstance) {
+        let now = Instant::now();
+        self.transactions.lock().unwrap().insert(
+            tx_hash,
+            TrackedTx {
+                status: TxStatus::Pending,
+                contract: contract.address,
+                schema: contract.schema.clone(),
+                sent: now,
+                updated: now,
+            },
+        );
+    }
+
+    /// Replace the transactions in the tree with the tracked transactions
+    /// whose status is not final yet, so they can be tracked again after a
+    /// restart. Returns the number of saved transactions.
+    pub fn save_pending(&self, tree: &sled::Tree) -> sled::Result<usize> {
+        let pending: Vec<(TransactionHash, ContractAddress)> = self
+            .transactions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, tx)| !tx.status.is_final())
+            .map(|(tx_hash, tx)| (*tx_hash, tx.contract))
+            .collect();
+        tree.clear()?;
+        for (tx_hash, contract) in &pending {
+            let mut address = contract.index.to_be_bytes().to_vec();
+            address.extend_from_slice(&contract.subindex.to_be_bytes());
+            tree.insert(tx_hash.as_ref(), address)?;
+        }
+        tree.flush()?;
+        Ok(pending.len())
+    }
+
+    /// Track the transactions saved by [`save_pending`](Self::save_pending)
+    /// again. Transactions sent to an instance that is no longer served are
+    /// tracked without a schema. Returns the number of loaded transactions.
+    pub fn load_pending(
+        &self,
+        tree: &sled::Tree,
+        instances: &[&ContractInstance],
+    ) -> sled::Result<usize> {
+        let mut loaded = 0;
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+            let (Ok(tx_hash), Ok(address)) = (
+                <[u8; 32]>::try_from(key.as_ref()),
+                <[u8; 16]>::try_from(value.as_ref()),
+            ) else {
+                log::warn!("Skipping malformed pending transaction entry.");
+                continue;
+            };
+            let address = ContractAddress {
+                index: u64::from_be_bytes(address[..8].try_into().unwrap()),
+                subindex: u64::from_be_bytes(address[8..].try_into().unwrap()),
+            };
+            let contract = instances
+                .iter()
+                .find(|instance| instance.address == address)
+                .map_or_else(
+                    || ContractInstance::new(String::new(), address, None),
+                    |instance| (*instance).clone(),
+                );
+            self.track(TransactionHash::new(tx_hash), &contract);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    /// The status of a tracked transaction.
+    pub fn status(&self, tx_hash: &TransactionHash) -> Option<TxStatus> {
+        self.transactions
+            .lock()
+            .unwrap()
+            .get(tx_hash)
+            .map(|tx| tx.status.clone())
+    }
+
+    /// Subscribe to the status changes of the tracked transactions.
+    pub fn subscribe(&self) -> broadcast::Receiver<TxStatusUpdate> {
+        self.updates.subscribe()
+    }
+
+    /// Receive the final status of each tracked transaction. Unlike with
+    /// [`subscribe`](Self::subscribe), no status is missed if the receiver
+    /// falls behind, so this is used for records that must be complete.
+    pub fn outcomes(&self) -> mpsc::UnboundedReceiver<TxStatusUpdate> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.outcomes.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Set the status of a tracked transaction, notifying the subscribers if
+    /// it changed.
+    fn update(&self, tx_hash: TransactionHash, status: TxStatus) {
+        let mut transactions = self.transactions.lock().unwrap();
+        let Some(tx) = transactions.get_mut(&tx_hash) else {
+            return;
+        };
+        if tx.status == status {
+            return;
+        }
+        log::debug!("Transaction {} changed status to {:?}.", tx_hash, status);
+        tx.status = status.clone();
+        tx.updated = Instant::now();
+        let update = TxStatusUpdate { tx_hash, status };
+        if update.status.is_final() {
+            // Receivers that were dropped are removed.
+            self.outcomes
+                .lock()
+                .unwrap()
+                .retain(|outcomes| outcomes.send(update.clone()).is_ok());
+        }
+        // Sending only fails if there are no subscribers.
+        let _ = self.updates.send(update);
+    }
+
+    /// Stop tracking transactions whose status has not changed for a while.
+    fn prune(&self, now: Instant) {
+        self.transactions
+            .lock()
+            .unwrap()
+            .retain(|_, tx| now.duration_since(tx.updated) < TX_STATUS_RETENTION);
+    }
+
+    /// Convert the status reported by the node.
+    pub fn convert(schema: Option<&VersionedModuleSchema>, status: &TransactionStatus) -> TxStatus {
+        let summary = |outcomes: &BTreeMap<BlockHash, BlockItemSummary>| {
+            outcomes
+                .iter()
+                .next()
+                .map(|(block_hash, summary)| (*block_hash, summary.clone()))
+        };
+        match status {
+            TransactionStatus::Received => TxStatus::Pending,
+            TransactionStatus::Committed(outcomes) => match summary(outcomes) {
+                Some((block_hash, _)) => TxStatus::Committed { block_hash },
+                None => TxStatus::Pending,
+            },
+            TransactionStatus::Finalized(outcomes) => match summary(outcomes) {
+                Some((block_hash, summary)) => {
+                    let cost = match &summary.details {
+                        BlockItemSummaryDetails::AccountTransaction(details) => details.cost,
+                        _ => Amount::zero(),
+                    };
+                    match summary.is_rejected_account_transaction() {
+                        Some(reject_reason) => TxStatus::Failed {
+                            block_hash,
+                            cost,
+                            code: schema
+                                .and_then(|schema| decode_reject_code(schema, reject_reason)),
+                            reject_reason: reject_reason.clone(),
+                        },
+                        None => TxStatus::Finalized { block_hash, cost },
+                    }
+                }
+                None => TxStatus::Pending,
+            },
+        }
+    }
+
+    /// Mark the transaction as expired if it was sent longer than
+    /// [`TRANSACTION_EXPIRY`] before `now`. Called when the transaction is not
+    /// known to the node.
+    fn expire(&self, tx_hash: TransactionHash, now: Instant) {
+        let expired = self
+            .transactions
+            .lock()
+            .unwrap()
+            .get(&tx_hash)
+            .is_some_and(|tx| now.duration_since(tx.sent) >= TRANSACTION_EXPIRY);
+        if expired {
+            self.update(tx_hash, TxStatus::Expired);
+        }
+    }
+
+    /// Query the status of the transactions that are not final yet.
+    async fn refresh(&self, client: &mut concordium_rust_sdk::v2::Client) {
+        self.prune(Instant::now());
+        let open: Vec<(TransactionHash, Option<Arc<VersionedModuleSchema>>)> = self
+            .transactions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, tx)| !tx.status.is_final())
+            .map(|(tx_hash, tx)| (*tx_hash, tx.schema.clone()))
+            .collect();
+        for (tx_hash, schema) in open {
+            match client.get_block_item_status(&tx_hash).await {
+                Ok(status) => self.update(tx_hash, Self::convert(schema.as_deref(), &status)),
+                // The transaction is not known to the node (yet or anymore).
+                // It stays pending until it has expired.
+                Err(QueryError::NotFound) => self.expire(tx_hash, Instant::now()),
+                Err(e) => log::warn!("Could not query the status of {}: {}", tx_hash, e),
+            }
+        }
+    }
+
+    /// Follow the finalized blocks of a node and refresh the status of the
+    /// tracked transactions for each of them. If the stream breaks, it is
+    /// resumed with the next healthy node.
+    pub async fn run(self: Arc<Self>, nodes: Nodes) {
+        loop {
+            let mut client = match nodes.client() {
+                Ok(client) => client,
+                Err(e) => {
+                    log::warn!("Could not follow finalized blocks: {}", e);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    continue;
+                }
+            };
+            match client.get_finalized_blocks().await {
+                Ok(mut blocks) => {
+                    while let Some(block) = blocks.next().await {
+                        if let Err(e) = block {
+                            log::warn!("Finalized blocks stream error: {}", e);
+                            break;
+                        }
+                        self.refresh(&mut client).await;
+                    }
+                }
+                Err(e) => log::warn!("Could not follow finalized blocks: {}", e),
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx_hash() -> TransactionHash {
+        TransactionHash::new([1; 32])
+    }
+
+    fn block_hash() -> BlockHash {
+        BlockHash::new([2; 32])
+    }
+
+    fn contract() -> ContractInstance {
+        ContractInstance::new("ccd_redeem".into(), ContractAddress::new(4184, 0), None)
+    }
+
+    #[test]
+    fn test_status_changes_are_broadcast() {
+        let tracker = TxTracker::new();
+        let mut updates = tracker.subscribe();
+
+        // Untracked transactions are ignored.
+        tracker.update(
+            tx_hash(),
+            TxStatus::Committed {
+                block_hash: block_hash(),
+            },
+        );
+        assert_eq!(tracker.status(&tx_hash()), None);
+
+        tracker.track(tx_hash(), &contract());
+        assert_eq!(tracker.status(&tx_hash()), Some(TxStatus::Pending));
+
+        let committed = TxStatus::Committed {
+            block_hash: block_hash(),
+        };
+        tracker.update(tx_hash(), committed.clone());
+        tracker.update(tx_hash(), committed.clone());
+        let finalized = TxStatus::Finalized {
+            block_hash: block_hash(),
+            cost: Amount::from_micro_ccd(1000),
+        };
+        tracker.update(tx_hash(), finalized.clone());
+
+        assert_eq!(updates.try_recv().unwrap().status, committed);
+        assert_eq!(updates.try_recv().unwrap().status, finalized);
+        assert!(updates.try_recv().is_err());
+        assert_eq!(tracker.status(&tx_hash()), Some(finalized));
+    }
+
+    #[test]
+    fn test_outcomes_are_not_missed() {
+        let tracker = TxTracker::new();
+        let mut updates = tracker.subscribe();
+        let mut outcomes = tracker.outcomes();
+
+        for i in 0..300u16 {
+            let mut hash = [0; 32];
+            hash[..2].copy_from_slice(&i.to_be_bytes());
+            let tx_hash = TransactionHash::new(hash);
+            tracker.track(tx_hash, &contract());
+            tracker.update(
+                tx_hash,
+                TxStatus::Committed {
+                    block_hash: block_hash(),
+                },
+            );
+            tracker.update(
+                tx_hash,
+                TxStatus::Finalized {
+                    block_hash: block_hash(),
+                    cost: Amount::from_micro_ccd(1000),
+                },
+            );
+        }
+
+        // The broadcast drops the oldest changes, but every outcome is kept.
+        assert!(matches!(
+            updates.try_recv(),
+            Err(broadcast::error::TryRecvError::Lagged(_))
+        ));
+        for _ in 0..300 {
+            assert!(outcomes.try_recv().unwrap().status.is_final());
+        }
+        assert!(outcomes.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_unknown_transactions_expire() {
+        let tracker = TxTracker::new();
+        let mut outcomes = tracker.outcomes();
+        tracker.track(tx_hash(), &contract());
+
+        tracker.expire(tx_hash(), Instant::now() + TRANSACTION_EXPIRY / 2);
+        assert_eq!(tracker.status(&tx_hash()), Some(TxStatus::Pending));
+        assert!(outcomes.try_recv().is_err());
+
+        tracker.expire(tx_hash(), Instant::now() + TRANSACTION_EXPIRY);
+        assert_eq!(tracker.status(&tx_hash()), Some(TxStatus::Expired));
+        let outcome = outcomes.try_recv().unwrap();
+        assert_eq!(outcome.status, TxStatus::Expired);
+        assert_eq!(outcome.status.cost(), None);
+    }
+
+    #[test]
+    fn test_prune() {
+        let tracker = TxTracker::new();
+        tracker.track(tx_hash(), &contract());
+
+        tracker.prune(Instant::now() + TX_STATUS_RETENTION / 2);
+        assert!(tracker.status(&tx_hash()).is_some());
+        tracker.prune(Instant::now() + TX_STATUS_RETENTION);
+        assert!(tracker.status(&tx_hash()).is_none());
+    }
+
+    #[test]
+    fn test_save_and_load_pending() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("pending_transactions").unwrap();
+
+        let tracker = TxTracker::new();
+        tracker.track(tx_hash(), &contract());
+        tracker.track(TransactionHash::new([3; 32]), &contract());
+        tracker.update(
+            TransactionHash::new([3; 32]),
+            TxStatus::Finalized {
+                block_hash: block_hash(),
+                cost: Amount::from_micro_ccd(1000),
+            },
+        );
+        assert_eq!(tracker.save_pending(&tree).unwrap(), 1);
+
+        let restarted = TxTracker::new();
+        assert_eq!(restarted.load_pending(&tree, &[&contract()]).unwrap(), 1);
+        assert_eq!(restarted.status(&tx_hash()), Some(TxStatus::Pending));
+        assert_eq!(restarted.status(&TransactionHash::new([3; 32])), None);
+        let contract = restarted.transactions.lock().unwrap()[&tx_hash()].contract;
+        assert_eq!(contract, ContractAddress::new(4184, 0));
+    }
+}
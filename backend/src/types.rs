@@ -1,20 +1,29 @@
+use crate::admin::Admin;
+use crate::audit::AuditLog;
+use crate::coin_cache::CoinCache;
+use crate::contract::EnergyConfig;
+use crate::metrics::Metrics;
+use crate::nodes::Nodes;
+use crate::outbox::Outbox;
+use crate::pool::SponsorPool;
+use crate::rate_limit::{RateLimitKey, RateLimiter};
+use crate::shutdown::Drain;
+use crate::signatures::AccountKeysCache;
+use crate::tx_status::TxTracker;
 use concordium_rust_sdk::cis2::{TokenId, Transfer, UpdateOperator};
 use concordium_rust_sdk::smart_contracts::common as concordium_std;
 use concordium_rust_sdk::types::RejectReason;
 use concordium_rust_sdk::{
     endpoints::{QueryError, RPCError},
     smart_contracts::common::{
-        AccountAddress, AccountSignatures, ContractAddress, OwnedEntrypointName, Serial, Timestamp,
-    },
-    types::{
-        hashes::{HashBytes, TransactionMarker},
-        Nonce,
+        AccountAddress, AccountSignatures, Amount, ContractAddress, Deserial, OwnedEntrypointName,
+        PublicKeyEd25519, Serial, SignatureEd25519, Timestamp,
     },
+    types::hashes::{HashBytes, TransactionMarker},
 };
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 
 #[derive(Debug, thiserror::Error)]
 pub enum LogError {
@@ -25,32 +34,118 @@ pub enum LogError {
     #[error("Simulation invoke error.")]
     SimulationInvokeError,
     #[error("Transaction simulation error.")]
-    TransactionSimulationError(RevertReason),
+    TransactionSimulationError(Box<RevertReason>),
     #[error("Owned received name error.")]
     OwnedReceiveNameError,
     #[error("TokenAmount error.")]
     TokenAmountError,
     #[error("Rate limit error.")]
-    RateLimitError,
+    RateLimitError(RateLimitKey),
+    #[error("Rate limit store error.")]
+    RateLimitStoreError,
     #[error("Parameter error.")]
     ParameterError,
     #[error("Signature error.")]
     SignatureError,
+    #[error("Public key error.")]
+    PublicKeyError,
+    #[error("Merkle proof error.")]
+    MerkleProofError,
+    #[error("Invalid signature map: {0}")]
+    SignatureMapError(String),
+    #[error("Invalid permit payload: {0}")]
+    PermitPayloadError(String),
+    #[error("Entrypoint not supported by permit.")]
+    EntrypointNotSupported(String),
+    #[error("Invalid coin signature.")]
+    InvalidCoinSignature,
+    #[error("Invalid account signature.")]
+    InvalidAccountSignature,
+    #[error("Unknown signer account.")]
+    UnknownSigner,
     #[error("AdditionalData error.")]
     AdditionalDataError,
+    #[error("Coin not found.")]
+    CoinNotFound,
+    #[error("Coin view error.")]
+    CoinViewError,
+    #[error("Unknown transaction.")]
+    UnknownTransaction,
+    #[error("Insufficient sponsor balance.")]
+    InsufficientSponsorBalance,
+    #[error("No sponsor account available.")]
+    NoSponsorAvailable,
+    #[error("Unauthorized.")]
+    Unauthorized,
+    #[error("Admin unauthorized.")]
+    AdminUnauthorized,
+    #[error("Admin store error.")]
+    AdminStoreError,
+    #[error("Sponsoring paused.")]
+    SponsoringPaused,
+    #[error("Blocked.")]
+    Blocked(RateLimitKey),
+    #[error("Challenge failed: {0}")]
+    ChallengeFailed(String),
+    #[error("Malformed body.")]
+    MalformedBody,
+    #[error("Shutting down.")]
+    ShuttingDown,
+    #[error("Outbox error.")]
+    OutboxError,
+    #[error("Unknown job.")]
+    UnknownJob,
+    #[error("Invalid idempotency key.")]
+    IdempotencyKeyError,
+    #[error("A submission with the same idempotency key is in progress.")]
+    IdempotencyConflict,
+    #[error("Idempotency key reused.")]
+    IdempotencyKeyReused,
     #[error("Node access error: {0}")]
-    NodeAccess(#[from] QueryError),
+    NodeAccess(Box<QueryError>),
+}
+
+impl From<QueryError> for LogError {
+    fn from(err: QueryError) -> Self {
+        Self::NodeAccess(Box::new(err))
+    }
 }
 
 impl From<RPCError> for LogError {
     fn from(err: RPCError) -> Self {
-        Self::NodeAccess(err.into())
+        Self::NodeAccess(Box::new(err.into()))
     }
 }
 
 #[derive(serde::Serialize, Debug)]
 pub struct RevertReason {
     pub reason: RejectReason,
+    /// The value returned by the contract when rejecting. This is the
+    /// serialized contract error, described by the contract's schema.
+    pub return_value: Option<Vec<u8>>,
+    /// The name of the contract error, if it could be decoded using the
+    /// contract's schema.
+    pub code: Option<String>,
+}
+
+impl RevertReason {
+    /// The name of the contract error if it could be decoded, otherwise the
+    /// kind of the reject reason, e.g., `OutOfEnergy`.
+    pub fn name(&self) -> String {
+        reject_reason_name(&self.reason, self.code.as_deref())
+    }
+}
+
+/// The name of the contract error `code` if given, otherwise the kind of the
+/// reject reason.
+pub fn reject_reason_name(reason: &RejectReason, code: Option<&str>) -> String {
+    match code {
+        Some(code) => code.into(),
+        None => serde_json::to_value(reason)
+            .ok()
+            .and_then(|value| value.get("tag")?.as_str().map(String::from))
+            .unwrap_or_else(|| "Unknown".into()),
+    }
 }
 
 impl fmt::Display for RevertReason {
@@ -69,11 +164,30 @@ pub struct ErrorResponse {
     pub message: String,
 }
 
+#[derive(serde::Serialize)]
+/// Response in case the contract rejects the transaction simulation. This is
+/// going to be encoded as a JSON body with field 'code', containing the name
+/// of the contract error, e.g., `{"code":"CoinAlreadyRedeemed"}`.
+pub struct ContractErrorResponse {
+    pub code: String,
+}
+
+/// The signature of the message by the signer account. Either a single hex
+/// encoded signature by the key 0 of the credential 0, or the hex encoded
+/// signatures by credential and key index, as returned by the wallet, e.g.,
+/// `{"0":{"0":"..."},"1":{"0":"...","2":"..."}}`.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum RequestSignature {
+    Single(String),
+    Map(BTreeMap<String, BTreeMap<String, String>>),
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct UpdateOperatorInputParams {
     pub signer: AccountAddress,
     pub nonce: u64,
-    pub signature: String,
+    pub signature: RequestSignature,
     pub operator: AccountAddress,
     pub add_operator: bool,
     pub timestamp: Timestamp,
@@ -83,19 +197,133 @@ pub struct UpdateOperatorInputParams {
 pub struct TransferInputParams {
     pub signer: AccountAddress,
     pub nonce: u64,
-    pub signature: String,
+    pub signature: RequestSignature,
     pub token_id: TokenId,
     pub from: AccountAddress,
     pub to: AccountAddress,
     pub timestamp: Timestamp,
 }
 
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct RedeemInputParams {
+    pub signer: AccountAddress,
+    pub nonce: u64,
+    pub signature: RequestSignature,
+    /// The public key of the coin, hex encoded.
+    pub public_key: String,
+    /// The signature of the `signer` account address by the coin key, hex
+    /// encoded.
+    pub coin_signature: String,
+    pub timestamp: Timestamp,
+    /// The proof of a coin issued in a Merkle batch, which is redeemed with
+    /// the `redeemMerkle` entrypoint instead of `redeem`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merkle_proof: Option<MerkleProofInput>,
+}
+
+/// The leaf of a coin in the Merkle tree of its batch, and the proof that the
+/// leaf is in the tree.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct MerkleProofInput {
+    /// The amount of the coin, as committed to in its leaf.
+    pub amount: Amount,
+    /// The root of the batch, hex encoded.
+    pub root: String,
+    pub leaf_index: u32,
+    /// The hex encoded siblings on the path from the leaf to the root,
+    /// bottom-up.
+    pub proof: Vec<String>,
+}
+
+/// The query of `GET /api/coin/{public_key}` for a coin issued in a Merkle
+/// batch, with the hashes of the `proof` separated by commas.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct MerkleProofQuery {
+    pub amount: Amount,
+    pub root: String,
+    pub leaf_index: u32,
+    #[serde(default)]
+    pub proof: String,
+}
+
+impl From<MerkleProofQuery> for MerkleProofInput {
+    fn from(query: MerkleProofQuery) -> Self {
+        Self {
+            amount: query.amount,
+            root: query.root,
+            leaf_index: query.leaf_index,
+            proof: query
+                .proof
+                .split(',')
+                .filter(|hash| !hash.is_empty())
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+/// The body of `POST /api/permit`. The payload is the parameter of the
+/// entrypoint in the JSON format of the schema embedded in the contract.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct PermitInputParams {
+    pub signer: AccountAddress,
+    pub nonce: u64,
+    pub signature: RequestSignature,
+    pub entry_point: String,
+    pub payload: serde_json::Value,
+    pub timestamp: Timestamp,
+}
+
+/// The body of `POST /api/simulate`, the body of any of the submission
+/// endpoints. The variants are told apart by their fields.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum SimulateInputParams {
+    UpdateOperator(UpdateOperatorInputParams),
+    Transfer(TransferInputParams),
+    Redeem(RedeemInputParams),
+    Permit(PermitInputParams),
+}
+
 #[derive(Debug, Serial, Clone)]
 pub struct TransferParams(#[concordium(size_length = 2)] pub Vec<Transfer>);
 
 #[derive(Debug, Serial, Clone)]
 pub struct UpdateOperatorParams(#[concordium(size_length = 2)] pub Vec<UpdateOperator>);
 
+#[derive(Debug, Serial, Deserial, Clone)]
+pub struct RedeemParams {
+    pub public_key: PublicKeyEd25519,
+    pub signature: SignatureEd25519,
+    pub account: AccountAddress,
+}
+
+/// The parameter of the `redeemMerkle` function of the smart contract.
+#[derive(Debug, Serial, Deserial, Clone)]
+pub struct RedeemMerkleParams {
+    pub public_key: PublicKeyEd25519,
+    pub signature: SignatureEd25519,
+    pub account: AccountAddress,
+    pub amount: Amount,
+    pub root: [u8; 32],
+    pub leaf_index: u32,
+    pub proof: Vec<[u8; 32]>,
+}
+
+/// The return value of the `viewCoin` function of the smart contract.
+#[derive(Debug, Deserial)]
+pub struct ViewCoinReturnData {
+    pub amount: Amount,
+    pub is_redeemed: bool,
+}
+
+/// The parameter of the `supportsPermit` function of the smart contract.
+#[derive(Debug, Serial)]
+pub struct SupportsPermitQueryParams {
+    #[concordium(size_length = 2)]
+    pub queries: Vec<OwnedEntrypointName>,
+}
+
 #[derive(Debug, Serial)]
 pub struct PermitParam {
     pub signature: AccountSignatures,
@@ -106,6 +334,8 @@ pub struct PermitParam {
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct TxHash {
     pub tx_hash: HashBytes<TransactionMarker>,
+    /// The job the transaction was sent for, see `GET /api/job/{id}`.
+    pub job_id: uuid::Uuid,
 }
 
 #[derive(Debug, Serial, Clone)]
@@ -118,9 +348,38 @@ pub struct PermitMessage {
     pub payload: Vec<u8>,
 }
 
+impl PermitMessage {
+    /// The message calling the entrypoint of the contract with the serialized
+    /// payload.
+    pub fn new(
+        contract_address: ContractAddress,
+        nonce: u64,
+        timestamp: Timestamp,
+        entry_point: OwnedEntrypointName,
+        payload: Vec<u8>,
+    ) -> Self {
+        Self {
+            contract_address,
+            nonce,
+            timestamp,
+            entry_point,
+            payload,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Server {
-    pub nonce: Arc<Mutex<Nonce>>,
-    // The rate_limits are transient and are reset on server restart.
-    pub rate_limits: Arc<Mutex<HashMap<AccountAddress, u8>>>,
+    pub nodes: Nodes,
+    pub sponsors: Arc<SponsorPool>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub tx_tracker: Arc<TxTracker>,
+    pub coin_cache: Arc<CoinCache>,
+    pub account_keys: Arc<AccountKeysCache>,
+    pub metrics: Arc<Metrics>,
+    pub drain: Arc<Drain>,
+    pub outbox: Arc<Outbox>,
+    pub admin: Arc<Admin>,
+    pub audit: Arc<AuditLog>,
+    pub energy: EnergyConfig,
 }
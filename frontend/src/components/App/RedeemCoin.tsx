@@ -15,17 +15,44 @@ import checkSeed, { SeedError, isPristineCoin } from './checkSeed';
 import { BackspaceFill, Bank } from 'react-bootstrap-icons';
 import Constants from "./Constants";
 
+/**
+ * The names of the variants of the contract error, decoded from the schema of the error. The contract rejects with
+ * the code `-(index + 1)` of the variant, e.g., `-3` for `CoinAlreadyRedeemed`.
+ */
+function contractErrorNames(schema: string): string[] {
+    const bytes = Uint8Array.from(atob(schema), (c) => c.charCodeAt(0));
+    const view = new DataView(bytes.buffer);
+    // The schema is an enum tag, the number of variants, and for each variant its name and the tag of its fields.
+    const count = view.getUint32(1, true);
+    const names = [];
+    let offset = 5;
+    for (let i = 0; i < count; i++) {
+        const length = view.getUint32(offset, true);
+        offset += 4;
+        names.push(new TextDecoder().decode(bytes.subarray(offset, offset + length)));
+        offset += length + 1;
+    }
+    return names;
+}
+
+const ERROR_MESSAGES: Record<string, string> = {
+    CoinNotFound: 'Coin does not exist.',
+    CoinAlreadyRedeemed: 'Coin is already redeemed.',
+    InvalidSignatures: 'The signature of the coin is invalid.',
+};
+
+/**
+ * The message for a failed redemption, given either the reject reason of the transaction or the error response of
+ * the backend, which names the contract error, e.g., `{"code":"CoinAlreadyRedeemed"}`.
+ */
 function getErrorMsg(error: any) {
-    if (error.rejectReason) {
-        switch (error.rejectReason) {
-            case -2:
-                return 'Coin does not exist.';
-            case -3:
-                return 'Coin is already redeemed.';
-            default:
-        }
+    let code: string | undefined;
+    if (typeof error.code === 'string') {
+        code = error.code;
+    } else if (typeof error.rejectReason === 'number') {
+        code = contractErrorNames(Constants.SCHEMAS.entrypoints.redeem.error)[-error.rejectReason - 1];
     }
-    return 'Unspecified error';
+    return (code && ERROR_MESSAGES[code]) || 'Unspecified error';
 }
 
 type Result = {
@@ -6,8 +6,13 @@ use concordium_cis2::*;
 use concordium_std::*;
 use core::fmt::Debug;
 
+pub mod merkle;
+
 /// List of supported entrypoints by the `permit` function (CIS3 standard).
-const SUPPORTS_PERMIT_ENTRYPOINTS: [EntrypointName; 1] = [EntrypointName::new_unchecked("redeem")];
+const SUPPORTS_PERMIT_ENTRYPOINTS: [EntrypointName; 2] = [
+    EntrypointName::new_unchecked("redeem"),
+    EntrypointName::new_unchecked("redeemMerkle"),
+];
 
 #[derive(Serialize, Clone, Copy, SchemaType, PartialEq, Eq, Debug)]
 pub struct CoinState {
@@ -25,12 +30,32 @@ impl CoinState {
     }
 }
 
+/// A batch of coins issued by committing to the root of a Merkle tree over
+/// the `(key, amount)` leaves of the coins (see the [`merkle`] module).
+#[derive(Serialize, Clone, Copy, SchemaType, PartialEq, Eq, Debug)]
+pub struct MerkleBatch {
+    /// The number of leaves (coins) in the tree.
+    pub leaf_count: u32,
+    /// The CCD amount that funds the coins in the batch.
+    pub total_amount: Amount,
+    /// The CCD amount of the coins in the batch redeemed so far.
+    pub redeemed_amount: Amount,
+}
+
 /// Smart contract state.
 #[derive(Serial, DeserialWithState)]
 #[concordium(state_parameter = "S")]
 pub struct State<S> {
     admin: AccountAddress,
     coins: StateMap<PublicKeyEd25519, CoinState, S>,
+    /// Coins that are identified by a SHA-256 commitment of their public key.
+    /// The public key is only revealed when the coin is redeemed.
+    hashed_coins: StateMap<HashSha2256, CoinState, S>,
+    /// Batches of coins issued by a Merkle root, indexed by the root.
+    merkle_batches: StateMap<HashSha2256, MerkleBatch, S>,
+    /// Bitmap of the redeemed leaves of each Merkle batch. Each entry holds the
+    /// bits of 64 consecutive leaves, indexed by the root and `leaf_index / 64`.
+    merkle_redeemed: StateMap<(HashSha2256, u32), u64, S>,
 }
 
 impl<S: HasStateApi> State<S> {
@@ -38,26 +63,96 @@ impl<S: HasStateApi> State<S> {
         State {
             admin,
             coins: state_builder.new_map(),
+            hashed_coins: state_builder.new_map(),
+            merkle_batches: state_builder.new_map(),
+            merkle_redeemed: state_builder.new_map(),
         }
     }
 
-    fn redeem(&mut self, key: PublicKeyEd25519) -> Result<Amount, Error> {
-        if let Some(mut c) = self.coins.get_mut(&key) {
-            if c.is_redeemed {
-                Err(Error::CoinAlreadyRedeemed)
-            } else {
-                c.is_redeemed = true;
-                Ok(c.amount)
-            }
+    /// Whether the leaf with the given index of a Merkle batch is redeemed.
+    fn is_merkle_leaf_redeemed(&self, root: HashSha2256, leaf_index: u32) -> bool {
+        self.merkle_redeemed
+            .get(&(root, leaf_index / 64))
+            .is_some_and(|word| *word & (1 << (leaf_index % 64)) != 0)
+    }
+
+    /// Mark the leaf with the given index of a Merkle batch as redeemed and
+    /// account for its amount in the batch.
+    fn redeem_merkle_leaf(
+        &mut self,
+        root: HashSha2256,
+        leaf_index: u32,
+        amount: Amount,
+    ) -> Result<(), Error> {
+        let mut batch = self
+            .merkle_batches
+            .get_mut(&root)
+            .ok_or(Error::CoinNotFound)?;
+        ensure!(leaf_index < batch.leaf_count, Error::InvalidMerkleProof);
+        let redeemed_amount = batch.redeemed_amount + amount;
+        ensure!(
+            redeemed_amount <= batch.total_amount,
+            Error::MerkleBatchExhausted
+        );
+
+        let bit = 1u64 << (leaf_index % 64);
+        self.merkle_redeemed
+            .entry((root, leaf_index / 64))
+            .or_insert(0)
+            .try_modify(|word| {
+                ensure!(*word & bit == 0, Error::CoinAlreadyRedeemed);
+                *word |= bit;
+                Ok(())
+            })?;
+        batch.redeemed_amount = redeemed_amount;
+        Ok(())
+    }
+
+    /// Redeem the coin with the given public key. Coins issued with the plain
+    /// public key take precedence over coins issued with a commitment.
+    fn redeem(
+        &mut self,
+        key: PublicKeyEd25519,
+        crypto_primitives: &impl HasCryptoPrimitives,
+    ) -> Result<Amount, Error> {
+        if let Some(c) = self.coins.get_mut(&key) {
+            return mark_redeemed(c);
+        }
+        let commitment = coin_commitment(crypto_primitives, &key);
+        if let Some(c) = self.hashed_coins.get_mut(&commitment) {
+            mark_redeemed(c)
         } else {
             Err(Error::CoinNotFound)
         }
     }
 }
 
+/// Mark a coin as redeemed and return its amount.
+fn mark_redeemed<S: HasStateApi>(mut coin: StateRefMut<CoinState, S>) -> Result<Amount, Error> {
+    if coin.is_redeemed {
+        Err(Error::CoinAlreadyRedeemed)
+    } else {
+        coin.is_redeemed = true;
+        Ok(coin.amount)
+    }
+}
+
+/// The commitment under which a coin with the given public key is stored in
+/// the hashed coin mode, i.e., the SHA-256 hash of the public key bytes.
+pub fn coin_commitment(
+    crypto_primitives: &impl HasCryptoPrimitives,
+    key: &PublicKeyEd25519,
+) -> HashSha2256 {
+    crypto_primitives.hash_sha2_256(&key.0)
+}
+
 /// Smart contract errors.
-#[derive(Debug, PartialEq, Eq, Reject, Serial, SchemaType)]
-enum Error {
+///
+/// When the contract rejects, the error is returned as the return value,
+/// described by the `error` schema of the entrypoint. The reject code is the
+/// negated index of the variant minus one, e.g., `-3` for `CoinAlreadyRedeemed`.
+#[derive(Debug, PartialEq, Eq, Reject, Serialize, SchemaType)]
+pub enum Error {
     /// Failed parsing the parameter.
     #[from(ParseError)]
     ParseParams,
@@ -74,6 +169,9 @@ enum Error {
     MissingAccount,
     MalformedSignatureData,
     AmountDoesNotMatch,
+    InvalidMerkleProof,
+    MerkleBatchExhausted,
+    EmptyMerkleBatch,
 }
 
 /// Mapping errors related to contract invocations to CustomContractError.
@@ -101,12 +199,17 @@ pub struct InitParam {
 /// Init function that creates a new smart contract.
 /// Adds the coins provided as input to the state and sets the account that
 /// deployed the contract to be the contract's admin.
-#[init(contract = "ccd_redeem", parameter = "InitParam", payable)]
+#[init(
+    contract = "ccd_redeem",
+    parameter = "InitParam",
+    error = "Error",
+    payable
+)]
 fn init<S: HasStateApi>(
     ctx: &impl HasInitContext,
     state_builder: &mut StateBuilder<S>,
     amount: Amount,
-) -> InitResult<State<S>> {
+) -> Result<State<S>, Error> {
     let param: InitParam = ctx.parameter_cursor().get()?;
     let admin = ctx.init_origin();
     let mut state = State::empty(state_builder, admin);
@@ -119,7 +222,7 @@ fn init<S: HasStateApi>(
             .into_iter()
             .fold(Amount::zero(), |acc, x| acc + x.1),
         amount,
-        Error::AmountDoesNotMatch.into()
+        Error::AmountDoesNotMatch
     );
 
     for (key, amount) in param.coins {
@@ -149,7 +252,9 @@ fn verify_signature_and_redeem<S: HasStateApi>(
     ensure!(is_valid, Error::InvalidSignatures);
 
     // Redeem coin
-    let amount = host.state_mut().redeem(param.public_key)?;
+    let amount = host
+        .state_mut()
+        .redeem(param.public_key, crypto_primitives)?;
     host.invoke_transfer(&param.account, amount)?;
 
     Ok(())
@@ -159,7 +264,8 @@ fn verify_signature_and_redeem<S: HasStateApi>(
 ///
 /// It rejects if:
 /// - It fails to parse the parameter.
-/// - the coin is not found in the state
+/// - the coin is not found in the state, neither by its public key nor by the
+///   commitment of its public key
 /// - the coin was already redeemed.
 #[receive(
     contract = "ccd_redeem",
@@ -181,6 +287,88 @@ fn contract_redeem<S: HasStateApi>(
     verify_signature_and_redeem(host, crypto_primitives, param)
 }
 
+#[derive(Serialize, SchemaType)]
+pub struct RedeemMerkleParam {
+    pub public_key: PublicKeyEd25519,
+    pub signature: SignatureEd25519,
+    pub account: AccountAddress,
+    /// The amount of the coin, as committed to in its leaf.
+    pub amount: Amount,
+    /// The root of the Merkle batch the coin was issued in.
+    pub root: HashSha2256,
+    /// The index of the coin's leaf in the tree.
+    pub leaf_index: u32,
+    /// The siblings on the path from the leaf to the root (bottom-up).
+    pub proof: Vec<HashSha2256>,
+}
+
+fn verify_proof_and_redeem_merkle<S: HasStateApi>(
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    crypto_primitives: &impl HasCryptoPrimitives,
+    param: RedeemMerkleParam,
+) -> Result<(), Error> {
+    // Verify coin signature
+    let is_valid = crypto_primitives.verify_ed25519_signature(
+        param.public_key,
+        param.signature,
+        &param.account.0,
+    );
+    ensure!(is_valid, Error::InvalidSignatures);
+
+    // Verify that the coin is a leaf of the committed tree
+    let batch = *host
+        .state()
+        .merkle_batches
+        .get(&param.root)
+        .ok_or(Error::CoinNotFound)?;
+    ensure!(
+        param.leaf_index < batch.leaf_count
+            && param.proof.len() == merkle::tree_depth(batch.leaf_count),
+        Error::InvalidMerkleProof
+    );
+    let hash = |data: &[u8]| crypto_primitives.hash_sha2_256(data);
+    let leaf = merkle::leaf_hash(&hash, &param.public_key, param.amount);
+    let root = merkle::root_from_proof(&hash, leaf, param.leaf_index, &param.proof);
+    ensure_eq!(root, param.root, Error::InvalidMerkleProof);
+
+    // Redeem coin
+    host.state_mut()
+        .redeem_merkle_leaf(param.root, param.leaf_index, param.amount)?;
+    host.invoke_transfer(&param.account, param.amount)?;
+
+    Ok(())
+}
+
+/// An entrypoint that redeems a coin issued in a Merkle batch, if it has not
+/// been redeemed already. The coin is identified by its leaf in the tree,
+/// proven by the Merkle proof in the parameter.
+///
+/// It rejects if:
+/// - It fails to parse the parameter.
+/// - the batch is not found in the state
+/// - the Merkle proof is invalid
+/// - the coin was already redeemed
+/// - the batch does not hold enough funds for the coin.
+#[receive(
+    contract = "ccd_redeem",
+    name = "redeemMerkle",
+    parameter = "RedeemMerkleParam",
+    error = "Error",
+    crypto_primitives,
+    mutable
+)]
+fn contract_redeem_merkle<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    crypto_primitives: &impl HasCryptoPrimitives,
+) -> Result<(), Error> {
+    // Parse parameters
+    let param: RedeemMerkleParam = ctx.parameter_cursor().get()?;
+
+    // Redeem after verifying the signature and the proof
+    verify_proof_and_redeem_merkle(host, crypto_primitives, param)
+}
+
 #[derive(Serialize, SchemaType)]
 pub struct IssueParam {
     pub coins: Vec<(PublicKeyEd25519, Amount)>,
@@ -231,11 +419,107 @@ fn contract_issue<S: HasStateApi>(
     Ok(())
 }
 
+#[derive(Serialize, SchemaType)]
+pub struct IssueHashedParam {
+    /// Coins identified by the SHA-256 commitment of their public key.
+    pub coins: Vec<(HashSha2256, Amount)>,
+}
+
+/// An entrypoint for batch issue of coins in the hashed mode. The coins are
+/// stored under a commitment of their public key, so the public key is not
+/// revealed before the coin is redeemed via the `redeem` entrypoint.
+///
+/// It rejects if:
+/// - It fails to parse the parameter.
+/// - Any of the commitments are already issued.
+#[receive(
+    contract = "ccd_redeem",
+    name = "issueHashed",
+    parameter = "IssueHashedParam",
+    error = "Error",
+    payable,
+    mutable
+)]
+fn contract_issue_hashed<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    amount: Amount,
+) -> Result<(), Error> {
+    let param: IssueHashedParam = ctx.parameter_cursor().get()?;
+
+    // check that the CCD amount is equal to the sum of all amounts in the coin list.
+    ensure_eq!(
+        param.coins.iter().fold(Amount::zero(), |acc, x| acc + x.1),
+        amount,
+        Error::AmountDoesNotMatch
+    );
+
+    for (commitment, amount) in param.coins {
+        let res = host
+            .state_mut()
+            .hashed_coins
+            .insert(commitment, CoinState::from_amount(amount));
+        if res.is_some() {
+            return Err(Error::CoinAlreadyExists);
+        }
+    }
+
+    Ok(())
+}
+
 /// Check whether the transaction `sender` is the admin.
 fn sender_is_admin<S: HasStateApi>(ctx: &impl HasReceiveContext, state: &State<S>) -> bool {
     ctx.sender().matches_account(&state.admin)
 }
 
+#[derive(Serialize, SchemaType)]
+pub struct IssueMerkleParam {
+    /// The root of the Merkle tree over the `(key, amount)` leaves of the coins.
+    pub root: HashSha2256,
+    /// The number of leaves (coins) in the tree.
+    pub leaf_count: u32,
+}
+
+/// An entrypoint for bulk issue of coins by committing to the root of a Merkle
+/// tree over the coins. The CCD amount sent funds all coins in the batch.
+/// Can be called only by the admin.
+///
+/// It rejects if:
+/// - It fails to parse the parameter.
+/// - The sender is not the admin.
+/// - The batch has no leaves, so its funds could never be redeemed.
+/// - A batch with the same root was already issued.
+#[receive(
+    contract = "ccd_redeem",
+    name = "issueMerkle",
+    parameter = "IssueMerkleParam",
+    error = "Error",
+    payable,
+    mutable
+)]
+fn contract_issue_merkle<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &mut impl HasHost<State<S>, StateApiType = S>,
+    amount: Amount,
+) -> Result<(), Error> {
+    let state = host.state_mut();
+    ensure!(sender_is_admin(ctx, state), Error::NotAuthorized);
+    let param: IssueMerkleParam = ctx.parameter_cursor().get()?;
+    ensure!(param.leaf_count > 0, Error::EmptyMerkleBatch);
+
+    let batch = MerkleBatch {
+        leaf_count: param.leaf_count,
+        total_amount: amount,
+        redeemed_amount: Amount::zero(),
+    };
+    ensure!(
+        state.merkle_batches.insert(param.root, batch).is_none(),
+        Error::CoinAlreadyExists
+    );
+
+    Ok(())
+}
+
 /// An entrypoint that updates the admin.
 /// Can be called only be the current admin.
 #[receive(
@@ -258,8 +542,8 @@ fn contract_set_admin<S: HasStateApi>(
 
 #[derive(Serialize, SchemaType)]
 pub struct ViewCoinReturnData {
-    amount: Amount,
-    is_redeemed: bool,
+    pub amount: Amount,
+    pub is_redeemed: bool,
 }
 
 
@@ -268,21 +552,101 @@ pub struct ViewCoinReturnData {
     contract = "ccd_redeem",
     name = "viewCoin",
     parameter = "PublicKeyEd25519",
-    return_value = "ViewCoinReturnData"
+    return_value = "ViewCoinReturnData",
+    error = "Error"
 )]
 fn contract_view_coin<S: HasStateApi>(
     ctx: &impl HasReceiveContext,
     host: &impl HasHost<State<S>, StateApiType = S>,
-) -> ReceiveResult<ViewCoinReturnData> {
+) -> Result<ViewCoinReturnData, Error> {
     let param: PublicKeyEd25519 = ctx.parameter_cursor().get()?;
     let coin_state =
         host.state().coins.get(&param).ok_or(Error::CoinNotFound)?;
     Ok(ViewCoinReturnData { amount: coin_state.amount, is_redeemed: coin_state.is_redeemed })
 }
 
+/// View function that returns coin value and status (redeemed or not) of a
+/// coin issued in the hashed mode, queried by the commitment of its public key.
+#[receive(
+    contract = "ccd_redeem",
+    name = "viewCoinHashed",
+    parameter = "HashSha2256",
+    return_value = "ViewCoinReturnData",
+    error = "Error"
+)]
+fn contract_view_coin_hashed<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> Result<ViewCoinReturnData, Error> {
+    let param: HashSha2256 = ctx.parameter_cursor().get()?;
+    let coin_state = host
+        .state()
+        .hashed_coins
+        .get(&param)
+        .ok_or(Error::CoinNotFound)?;
+    Ok(ViewCoinReturnData {
+        amount: coin_state.amount,
+        is_redeemed: coin_state.is_redeemed,
+    })
+}
+
+/// View function that returns the state of a Merkle batch.
+#[receive(
+    contract = "ccd_redeem",
+    name = "viewMerkleBatch",
+    parameter = "HashSha2256",
+    return_value = "MerkleBatch",
+    error = "Error"
+)]
+fn contract_view_merkle_batch<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> Result<MerkleBatch, Error> {
+    let param: HashSha2256 = ctx.parameter_cursor().get()?;
+    let batch = host
+        .state()
+        .merkle_batches
+        .get(&param)
+        .ok_or(Error::CoinNotFound)?;
+    Ok(*batch)
+}
+
+#[derive(Serialize, SchemaType)]
+pub struct ViewMerkleLeafParam {
+    pub root: HashSha2256,
+    pub leaf_index: u32,
+}
+
+/// View function that returns whether the leaf with the given index of a
+/// Merkle batch is redeemed.
+#[receive(
+    contract = "ccd_redeem",
+    name = "viewMerkleLeaf",
+    parameter = "ViewMerkleLeafParam",
+    return_value = "bool",
+    error = "Error"
+)]
+fn contract_view_merkle_leaf<S: HasStateApi>(
+    ctx: &impl HasReceiveContext,
+    host: &impl HasHost<State<S>, StateApiType = S>,
+) -> Result<bool, Error> {
+    let param: ViewMerkleLeafParam = ctx.parameter_cursor().get()?;
+    let state = host.state();
+    let batch = state
+        .merkle_batches
+        .get(&param.root)
+        .ok_or(Error::CoinNotFound)?;
+    ensure!(param.leaf_index < batch.leaf_count, Error::CoinNotFound);
+    Ok(state.is_merkle_leaf_redeemed(param.root, param.leaf_index))
+}
+
 #[derive(Serialize, SchemaType)]
 pub struct ViewReturnData {
     pub coins: Vec<(PublicKeyEd25519, CoinState)>,
+    /// Coins issued in the hashed mode, listed by their commitment.
+    pub hashed_coins: Vec<(HashSha2256, CoinState)>,
+    /// Batches of coins issued by a Merkle root, listed by their root.
+    pub merkle_batches: Vec<(HashSha2256, MerkleBatch)>,
     pub admin: AccountAddress,
 }
 
@@ -298,8 +662,22 @@ fn view<S: HasStateApi>(
 ) -> ReceiveResult<ViewReturnData> {
     let coins: Vec<(PublicKeyEd25519, CoinState)> =
         host.state().coins.iter().map(|x| (*x.0, *x.1)).collect();
+    let hashed_coins: Vec<(HashSha2256, CoinState)> = host
+        .state()
+        .hashed_coins
+        .iter()
+        .map(|x| (*x.0, *x.1))
+        .collect();
+    let merkle_batches: Vec<(HashSha2256, MerkleBatch)> = host
+        .state()
+        .merkle_batches
+        .iter()
+        .map(|x| (*x.0, *x.1))
+        .collect();
     Ok(ViewReturnData {
         coins,
+        hashed_coins,
+        merkle_batches,
         admin: host.state().admin,
     })
 }
@@ -348,6 +726,7 @@ pub struct PermitParamPartial {
     name = "viewMessageHash",
     parameter = "PermitParam",
     return_value = "[u8;32]",
+    error = "Error",
     crypto_primitives,
     mutable
 )]
@@ -407,10 +786,16 @@ fn contract_view_message_hash<S: HasStateApi>(
 /// - The `redeem` action can fail if:
 ///     - the coin is not found in the state
 ///     - the coin was already redeemed.
+/// - The `redeemMerkle` action can fail if:
+///     - the batch is not found in the state
+///     - the Merkle proof is invalid
+///     - the coin was already redeemed
+///     - the batch does not hold enough funds for the coin.
 #[receive(
     contract = "ccd_redeem",
     name = "permit",
     parameter = "PermitParam",
+    error = "Error",
     crypto_primitives,
     mutable
 )]
@@ -458,6 +843,17 @@ fn contract_permit<S: HasStateApi>(
 
         // Redeem the coin after verifiying the signature.
         verify_signature_and_redeem(host, crypto_primitives, redeem_params)?;
+    } else if message.entry_point.as_entrypoint_name()
+        == EntrypointName::new_unchecked("redeemMerkle")
+    {
+        // Parse the parameter.
+        let redeem_params: RedeemMerkleParam = from_bytes(&message.payload)?;
+
+        // Check that the sponsoree is the same as the account to redeem the coin to.
+        ensure_eq!(param.signer, redeem_params.account, Error::NotAuthorized);
+
+        // Redeem the coin after verifiying the signature and the proof.
+        verify_proof_and_redeem_merkle(host, crypto_primitives, redeem_params)?;
     } else {
         bail!(Error::WrongEntryPoint)
     }
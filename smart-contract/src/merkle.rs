@@ -0,0 +1,140 @@
+//! # Merkle trees over coin leaves
+//!
+//! Helpers for the Merkle-root based bulk issuance of coins. A batch of coins
+//! is committed to by the root of a binary Merkle tree whose leaves are the
+//! `(public_key, amount)` pairs of the coins, in order.
+//!
+//! Leaves are hashed as `SHA-256(0x00 || public_key || amount)` where the
+//! amount is the little-endian encoding of the microCCD value, and inner nodes
+//! as `SHA-256(0x01 || left || right)`. The prefixes make sure that an inner
+//! node can never be passed off as a leaf. If a level of the tree has an odd
+//! number of nodes, the last node is paired with itself.
+//!
+//! The hash function is supplied by the caller, so the same code is used by
+//! the contract (via the crypto primitives of the host) and by tests and
+//! tooling (via any off-chain SHA-256 implementation).
+use concordium_std::*;
+
+/// Prefix of the data hashed for a leaf.
+const LEAF_PREFIX: u8 = 0;
+/// Prefix of the data hashed for an inner node.
+const NODE_PREFIX: u8 = 1;
+
+/// The hash of the leaf corresponding to a coin.
+pub fn leaf_hash(
+    hash: &impl Fn(&[u8]) -> HashSha2256,
+    key: &PublicKeyEd25519,
+    amount: Amount,
+) -> HashSha2256 {
+    let mut data = [0u8; 1 + 32 + 8];
+    data[0] = LEAF_PREFIX;
+    data[1..33].copy_from_slice(&key.0);
+    data[33..41].copy_from_slice(&amount.micro_ccd.to_le_bytes());
+    hash(&data)
+}
+
+/// The hash of an inner node with the given children.
+pub fn node_hash(
+    hash: &impl Fn(&[u8]) -> HashSha2256,
+    left: &HashSha2256,
+    right: &HashSha2256,
+) -> HashSha2256 {
+    let mut data = [0u8; 1 + 32 + 32];
+    data[0] = NODE_PREFIX;
+    data[1..33].copy_from_slice(&left.0);
+    data[33..65].copy_from_slice(&right.0);
+    hash(&data)
+}
+
+/// The number of siblings in a proof for a tree with `leaf_count` leaves.
+pub fn tree_depth(leaf_count: u32) -> usize {
+    let mut depth = 0;
+    let mut width = leaf_count;
+    while width > 1 {
+        width = width.div_ceil(2);
+        depth += 1;
+    }
+    depth
+}
+
+/// Compute the root of a tree from a leaf, its index and the siblings on the
+/// path from the leaf to the root (bottom-up).
+pub fn root_from_proof(
+    hash: &impl Fn(&[u8]) -> HashSha2256,
+    leaf: HashSha2256,
+    leaf_index: u32,
+    proof: &[HashSha2256],
+) -> HashSha2256 {
+    let mut index = leaf_index;
+    let mut node = leaf;
+    for sibling in proof {
+        node = if index & 1 == 0 {
+            node_hash(hash, &node, sibling)
+        } else {
+            node_hash(hash, sibling, &node)
+        };
+        index /= 2;
+    }
+    node
+}
+
+/// A Merkle tree over a list of coins, used to compute the root that is
+/// committed to in the contract and the proofs needed to redeem the coins.
+pub struct MerkleTree {
+    /// The levels of the tree, starting with the leaves and ending with the
+    /// root.
+    levels: Vec<Vec<HashSha2256>>,
+}
+
+impl MerkleTree {
+    /// Build the tree over the given coins. Returns `None` if there are no
+    /// coins or more than `u32::MAX` of them.
+    pub fn new(
+        hash: &impl Fn(&[u8]) -> HashSha2256,
+        coins: &[(PublicKeyEd25519, Amount)],
+    ) -> Option<Self> {
+        if coins.is_empty() || u32::try_from(coins.len()).is_err() {
+            return None;
+        }
+        let leaves: Vec<HashSha2256> = coins
+            .iter()
+            .map(|(key, amount)| leaf_hash(hash, key, *amount))
+            .collect();
+        let mut levels = vec![leaves];
+        while let Some(level) = levels.last().filter(|level| level.len() > 1) {
+            let next = level
+                .chunks(2)
+                .map(|pair| node_hash(hash, &pair[0], pair.get(1).unwrap_or(&pair[0])))
+                .collect();
+            levels.push(next);
+        }
+        Some(MerkleTree { levels })
+    }
+
+    /// The root of the tree.
+    pub fn root(&self) -> HashSha2256 {
+        self.levels[self.levels.len() - 1][0]
+    }
+
+    /// The number of leaves of the tree.
+    pub fn leaf_count(&self) -> u32 {
+        self.levels[0].len() as u32
+    }
+
+    /// The proof for the leaf at the given index, i.e., the siblings on the
+    /// path from the leaf to the root. Returns `None` if the index is out of
+    /// bounds.
+    pub fn proof(&self, leaf_index: u32) -> Option<Vec<HashSha2256>> {
+        if leaf_index >= self.leaf_count() {
+            return None;
+        }
+        let mut index = leaf_index as usize;
+        let mut proof = Vec::with_capacity(self.levels.len() - 1);
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = level.get(index ^ 1).unwrap_or(&level[index]);
+            proof.push(*sibling);
+            index /= 2;
+        }
+        Some(proof)
+    }
+}
@@ -1,9 +1,16 @@
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
+use ccd_redeem::merkle::MerkleTree;
 use ccd_redeem::*;
 use concordium_smart_contract_testing::*;
-use concordium_std::{PublicKeyEd25519, SignatureEd25519};
+use concordium_std::HashSha2256;
+use concordium_std::{
+    AccountSignatures, CredentialSignatures, PublicKeyEd25519, Signature, SignatureEd25519,
+};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer as _};
 use hex::FromHex;
+use sha2::{Digest, Sha256};
 
 const AMOUNT: Amount = Amount::from_ccd(10);
 
@@ -295,3 +302,657 @@ fn test_issue() {
         "Coin data does not match"
     );
 }
+
+/// Compute the commitment of a public key used in the hashed coin mode.
+fn commitment(key: &PublicKeyEd25519) -> HashSha2256 {
+    HashSha2256(Sha256::digest(key.0).into())
+}
+
+fn issue_hashed(
+    chain: &mut Chain,
+    contract_address: ContractAddress,
+    coins: Vec<(HashSha2256, Amount)>,
+    amount: Amount,
+) -> Result<ContractInvokeSuccess, ContractInvokeError> {
+    let param = OwnedParameter::from_serial(&IssueHashedParam { coins })
+        .expect("Parameters should be serialized successfully");
+
+    chain.contract_update(
+        Signer::with_one_key(),
+        ACCOUNT_0,
+        Address::Account(ACCOUNT_0),
+        Energy::from(10000),
+        UpdateContractPayload {
+            amount,
+            address: contract_address,
+            receive_name: OwnedReceiveName::new_unchecked("ccd_redeem.issueHashed".to_string()),
+            message: param,
+        },
+    )
+}
+
+fn redeem(
+    chain: &mut Chain,
+    contract_address: ContractAddress,
+) -> Result<ContractInvokeSuccess, ContractInvokeError> {
+    let param = OwnedParameter::from_serial(&RedeemParam {
+        public_key: PUBLIC_KEY,
+        account: ACCOUNT_1,
+        signature: SIGNATURE,
+    })
+    .expect("Parameters should be serialized successfully");
+
+    chain.contract_update(
+        Signer::with_one_key(),
+        ACCOUNT_1,
+        Address::Account(ACCOUNT_1),
+        Energy::from(10000),
+        UpdateContractPayload {
+            amount: Amount::zero(),
+            address: contract_address,
+            receive_name: OwnedReceiveName::new_unchecked("ccd_redeem.redeem".to_string()),
+            message: param,
+        },
+    )
+}
+
+fn view_coin_hashed(
+    chain: &Chain,
+    contract_address: ContractAddress,
+    commitment: HashSha2256,
+) -> ViewCoinReturnData {
+    let res = chain
+        .contract_invoke(
+            ACCOUNT_1,
+            Address::Account(ACCOUNT_1),
+            Energy::from(10000),
+            UpdateContractPayload {
+                amount: Amount::zero(),
+                address: contract_address,
+                receive_name: OwnedReceiveName::new_unchecked(
+                    "ccd_redeem.viewCoinHashed".to_string(),
+                ),
+                message: OwnedParameter::from_serial(&commitment)
+                    .expect("Parameters should be serialized successfully"),
+            },
+        )
+        .expect("Contract view call succeeds");
+    from_bytes::<ViewCoinReturnData>(res.return_value.as_slice())
+        .expect("Data deserialized successfully")
+}
+
+#[test]
+/// Test that a coin issued by the commitment of its public key can be
+/// redeemed by revealing the public key, exactly like a plain coin.
+fn test_redeem_hashed() {
+    let mut chain = Chain::new();
+
+    let deployment = prepare(&mut chain);
+
+    let init_info = initialize(&mut chain, &deployment, Vec::new(), Amount::zero())
+        .expect("Initialization should always succeed");
+
+    issue_hashed(
+        &mut chain,
+        init_info.contract_address,
+        vec![(commitment(&PUBLIC_KEY), AMOUNT)],
+        AMOUNT,
+    )
+    .expect("Issuing hashed coins succeeds");
+
+    let result = view_coin_hashed(&chain, init_info.contract_address, commitment(&PUBLIC_KEY));
+    assert_eq!(result.amount, AMOUNT, "Coin amount does not match");
+    assert!(
+        !result.is_redeemed,
+        "The coin is expected not to be redeemed"
+    );
+
+    let balance_before = chain
+        .account_balance_available(ACCOUNT_1)
+        .expect("Account exists");
+
+    let update = redeem(&mut chain, init_info.contract_address).expect("Contract call succeeds");
+
+    assert_eq!(
+        chain
+            .account_balance_available(ACCOUNT_1)
+            .expect("Account exists"),
+        balance_before + AMOUNT - update.transaction_fee,
+        "The coin amount is expected to be transferred to the account"
+    );
+
+    let result = view_coin_hashed(&chain, init_info.contract_address, commitment(&PUBLIC_KEY));
+    assert!(result.is_redeemed, "The coin is expected to be redeemed");
+}
+
+#[test]
+/// Test that a coin issued in the hashed mode cannot be redeemed twice.
+fn test_redeem_hashed_twice_fails() {
+    let mut chain = Chain::new();
+
+    let deployment = prepare(&mut chain);
+
+    let init_info = initialize(&mut chain, &deployment, Vec::new(), Amount::zero())
+        .expect("Initialization should always succeed");
+
+    issue_hashed(
+        &mut chain,
+        init_info.contract_address,
+        vec![(commitment(&PUBLIC_KEY), AMOUNT)],
+        AMOUNT,
+    )
+    .expect("Issuing hashed coins succeeds");
+
+    redeem(&mut chain, init_info.contract_address).expect("Contract call succeeds");
+
+    let err = redeem(&mut chain, init_info.contract_address)
+        .expect_err("Redeeming the coin a second time is expected to fail");
+    let return_value = err.return_value().expect("The error is returned");
+    assert_eq!(
+        from_bytes::<Error>(return_value).expect("Error deserialized successfully"),
+        Error::CoinAlreadyRedeemed,
+        "The coin is expected to be already redeemed"
+    );
+}
+
+#[test]
+/// Test that the view function only exposes the commitments of hashed coins.
+fn test_view_hashed() {
+    let mut chain = Chain::new();
+
+    let deployment = prepare(&mut chain);
+
+    let init_info = initialize(&mut chain, &deployment, Vec::new(), Amount::zero())
+        .expect("Initialization should always succeed");
+
+    issue_hashed(
+        &mut chain,
+        init_info.contract_address,
+        vec![(commitment(&PUBLIC_KEY), AMOUNT)],
+        AMOUNT,
+    )
+    .expect("Issuing hashed coins succeeds");
+
+    assert!(
+        issue_hashed(
+            &mut chain,
+            init_info.contract_address,
+            vec![(commitment(&PUBLIC_KEY), AMOUNT)],
+            AMOUNT,
+        )
+        .is_err(),
+        "Issuing the same commitment twice is expected to fail"
+    );
+
+    let res = chain
+        .contract_invoke(
+            ACCOUNT_1,
+            Address::Account(ACCOUNT_1),
+            Energy::from(10000),
+            UpdateContractPayload {
+                amount: Amount::zero(),
+                address: init_info.contract_address,
+                receive_name: OwnedReceiveName::new_unchecked("ccd_redeem.view".to_string()),
+                message: OwnedParameter::empty(),
+            },
+        )
+        .expect("Contract view call succeeds");
+    let result = from_bytes::<ViewReturnData>(res.return_value.as_slice())
+        .expect("Data deserialized successfully");
+    assert!(result.coins.is_empty(), "No plain coins are expected");
+    assert_eq!(
+        result.hashed_coins,
+        vec![(commitment(&PUBLIC_KEY), CoinState::from_amount(AMOUNT))],
+        "Hashed coin data does not match"
+    );
+}
+
+fn sha256(data: &[u8]) -> HashSha2256 {
+    HashSha2256(Sha256::digest(data).into())
+}
+
+/// The test coin and a few other coins, where the test coin is the last leaf,
+/// such that its proof contains a node paired with itself.
+fn merkle_coins() -> Vec<(PublicKeyEd25519, Amount)> {
+    vec![
+        (PublicKeyEd25519([0u8; 32]), Amount::from_ccd(1)),
+        (PublicKeyEd25519([1u8; 32]), Amount::from_ccd(2)),
+        (PublicKeyEd25519([2u8; 32]), Amount::from_ccd(3)),
+        (PublicKeyEd25519([3u8; 32]), Amount::from_ccd(4)),
+        (PUBLIC_KEY, AMOUNT),
+    ]
+}
+
+fn merkle_tree() -> MerkleTree {
+    MerkleTree::new(&sha256, &merkle_coins()).expect("Tree over coins exists")
+}
+
+fn issue_merkle(
+    chain: &mut Chain,
+    contract_address: ContractAddress,
+    sender: AccountAddress,
+    tree: &MerkleTree,
+    amount: Amount,
+) -> Result<ContractInvokeSuccess, ContractInvokeError> {
+    let param = OwnedParameter::from_serial(&IssueMerkleParam {
+        root: tree.root(),
+        leaf_count: tree.leaf_count(),
+    })
+    .expect("Parameters should be serialized successfully");
+
+    chain.contract_update(
+        Signer::with_one_key(),
+        sender,
+        Address::Account(sender),
+        Energy::from(10000),
+        UpdateContractPayload {
+            amount,
+            address: contract_address,
+            receive_name: OwnedReceiveName::new_unchecked("ccd_redeem.issueMerkle".to_string()),
+            message: param,
+        },
+    )
+}
+
+fn redeem_merkle(
+    chain: &mut Chain,
+    contract_address: ContractAddress,
+    tree: &MerkleTree,
+    leaf_index: u32,
+    proof: Vec<HashSha2256>,
+) -> Result<ContractInvokeSuccess, ContractInvokeError> {
+    let param = OwnedParameter::from_serial(&RedeemMerkleParam {
+        public_key: PUBLIC_KEY,
+        signature: SIGNATURE,
+        account: ACCOUNT_1,
+        amount: AMOUNT,
+        root: tree.root(),
+        leaf_index,
+        proof,
+    })
+    .expect("Parameters should be serialized successfully");
+
+    chain.contract_update(
+        Signer::with_one_key(),
+        ACCOUNT_1,
+        Address::Account(ACCOUNT_1),
+        Energy::from(10000),
+        UpdateContractPayload {
+            amount: Amount::zero(),
+            address: contract_address,
+            receive_name: OwnedReceiveName::new_unchecked("ccd_redeem.redeemMerkle".to_string()),
+            message: param,
+        },
+    )
+}
+
+fn view_merkle_leaf(
+    chain: &Chain,
+    contract_address: ContractAddress,
+    root: HashSha2256,
+    leaf_index: u32,
+) -> bool {
+    let res = chain
+        .contract_invoke(
+            ACCOUNT_1,
+            Address::Account(ACCOUNT_1),
+            Energy::from(10000),
+            UpdateContractPayload {
+                amount: Amount::zero(),
+                address: contract_address,
+                receive_name: OwnedReceiveName::new_unchecked(
+                    "ccd_redeem.viewMerkleLeaf".to_string(),
+                ),
+                message: OwnedParameter::from_serial(&ViewMerkleLeafParam { root, leaf_index })
+                    .expect("Parameters should be serialized successfully"),
+            },
+        )
+        .expect("Contract view call succeeds");
+    from_bytes::<bool>(res.return_value.as_slice()).expect("Data deserialized successfully")
+}
+
+#[test]
+/// Test that proofs built by the helper module verify against the root.
+fn test_merkle_tree_proofs() {
+    let tree = merkle_tree();
+    for (leaf_index, (key, amount)) in (0..).zip(merkle_coins()) {
+        let proof = tree.proof(leaf_index).expect("Proof exists");
+        assert_eq!(proof.len(), merkle::tree_depth(tree.leaf_count()));
+        let leaf = merkle::leaf_hash(&sha256, &key, amount);
+        assert_eq!(
+            merkle::root_from_proof(&sha256, leaf, leaf_index, &proof),
+            tree.root(),
+            "Proof does not verify"
+        );
+    }
+    assert!(tree.proof(tree.leaf_count()).is_none());
+    assert!(MerkleTree::new(&sha256, &[]).is_none());
+}
+
+#[test]
+/// Test redeeming a coin issued in a Merkle batch.
+fn test_redeem_merkle() {
+    let mut chain = Chain::new();
+
+    let deployment = prepare(&mut chain);
+
+    let init_info = initialize(&mut chain, &deployment, Vec::new(), Amount::zero())
+        .expect("Initialization should always succeed");
+
+    let tree = merkle_tree();
+    let total = merkle_coins()
+        .iter()
+        .fold(Amount::zero(), |acc, x| acc + x.1);
+
+    assert!(
+        issue_merkle(
+            &mut chain,
+            init_info.contract_address,
+            ACCOUNT_1,
+            &tree,
+            total
+        )
+        .is_err(),
+        "Issuing by a non-admin is expected to fail"
+    );
+    issue_merkle(
+        &mut chain,
+        init_info.contract_address,
+        ACCOUNT_0,
+        &tree,
+        total,
+    )
+    .expect("Issuing a Merkle batch succeeds");
+
+    assert!(!view_merkle_leaf(
+        &chain,
+        init_info.contract_address,
+        tree.root(),
+        4
+    ));
+
+    let proof = tree.proof(4).expect("Proof exists");
+
+    assert!(
+        redeem_merkle(
+            &mut chain,
+            init_info.contract_address,
+            &tree,
+            3,
+            proof.clone()
+        )
+        .is_err(),
+        "Redeeming with a proof for a different leaf is expected to fail"
+    );
+
+    let balance_before = chain
+        .account_balance_available(ACCOUNT_1)
+        .expect("Account exists");
+
+    let update = redeem_merkle(
+        &mut chain,
+        init_info.contract_address,
+        &tree,
+        4,
+        proof.clone(),
+    )
+    .expect("Contract call succeeds");
+
+    assert_eq!(
+        chain
+            .account_balance_available(ACCOUNT_1)
+            .expect("Account exists"),
+        balance_before + AMOUNT - update.transaction_fee,
+        "The coin amount is expected to be transferred to the account"
+    );
+    assert!(view_merkle_leaf(
+        &chain,
+        init_info.contract_address,
+        tree.root(),
+        4
+    ));
+    assert!(!view_merkle_leaf(
+        &chain,
+        init_info.contract_address,
+        tree.root(),
+        3
+    ));
+
+    assert!(
+        redeem_merkle(&mut chain, init_info.contract_address, &tree, 4, proof).is_err(),
+        "Redeeming the coin a second time is expected to fail"
+    );
+}
+
+#[test]
+/// Test that a Merkle batch without leaves cannot be issued, since its funds
+/// could never be redeemed.
+fn test_issue_merkle_empty_fails() {
+    let mut chain = Chain::new();
+
+    let deployment = prepare(&mut chain);
+
+    let init_info = initialize(&mut chain, &deployment, Vec::new(), Amount::zero())
+        .expect("Initialization should always succeed");
+
+    let param = OwnedParameter::from_serial(&IssueMerkleParam {
+        root: sha256(&[]),
+        leaf_count: 0,
+    })
+    .expect("Parameters should be serialized successfully");
+
+    let err = chain
+        .contract_update(
+            Signer::with_one_key(),
+            ACCOUNT_0,
+            Address::Account(ACCOUNT_0),
+            Energy::from(10000),
+            UpdateContractPayload {
+                amount: AMOUNT,
+                address: init_info.contract_address,
+                receive_name: OwnedReceiveName::new_unchecked("ccd_redeem.issueMerkle".to_string()),
+                message: param,
+            },
+        )
+        .expect_err("Issuing an empty Merkle batch is expected to fail");
+    let return_value = err.return_value().expect("The error is returned");
+    assert_eq!(
+        from_bytes::<Error>(return_value).expect("Error deserialized successfully"),
+        Error::EmptyMerkleBatch,
+        "The batch is expected to be empty"
+    );
+}
+
+#[test]
+/// Test that redeeming a coin fails if its Merkle batch does not hold enough
+/// funds for it.
+fn test_redeem_merkle_batch_exhausted() {
+    let mut chain = Chain::new();
+
+    let deployment = prepare(&mut chain);
+
+    let init_info = initialize(&mut chain, &deployment, Vec::new(), Amount::zero())
+        .expect("Initialization should always succeed");
+
+    let tree = merkle_tree();
+
+    // The batch is funded with less than the amount of the test coin.
+    issue_merkle(
+        &mut chain,
+        init_info.contract_address,
+        ACCOUNT_0,
+        &tree,
+        Amount::from_ccd(9),
+    )
+    .expect("Issuing a Merkle batch succeeds");
+
+    let proof = tree.proof(4).expect("Proof exists");
+    let err = redeem_merkle(&mut chain, init_info.contract_address, &tree, 4, proof)
+        .expect_err("Redeeming more than the funds of the batch is expected to fail");
+    let return_value = err.return_value().expect("The error is returned");
+    assert_eq!(
+        from_bytes::<Error>(return_value).expect("Error deserialized successfully"),
+        Error::MerkleBatchExhausted,
+        "The batch is expected to be exhausted"
+    );
+    assert!(!view_merkle_leaf(
+        &chain,
+        init_info.contract_address,
+        tree.root(),
+        4
+    ));
+}
+
+/// The key of `ACCOUNT_1` signing permit messages.
+fn signer_key() -> Keypair {
+    let secret = SecretKey::from_bytes(&[7u8; 32]).expect("Secret key is valid");
+    let public = PublicKey::from(&secret);
+    Keypair { secret, public }
+}
+
+/// Call `permit` with the message signed by `ACCOUNT_1`, sponsored by
+/// `ACCOUNT_0`.
+fn permit(
+    chain: &mut Chain,
+    contract_address: ContractAddress,
+    entry_point: &str,
+    payload: Vec<u8>,
+) -> Result<ContractInvokeSuccess, ContractInvokeError> {
+    let mut param = PermitParam {
+        signature: AccountSignatures {
+            sigs: BTreeMap::new(),
+        },
+        signer: ACCOUNT_1,
+        message: PermitMessage {
+            contract_address,
+            nonce: 0,
+            timestamp: Timestamp::from_timestamp_millis(10_000_000_000),
+            entry_point: OwnedEntrypointName::new_unchecked(entry_point.to_string()),
+            payload,
+        },
+    };
+
+    let res = chain
+        .contract_invoke(
+            ACCOUNT_0,
+            Address::Account(ACCOUNT_0),
+            Energy::from(10000),
+            UpdateContractPayload {
+                amount: Amount::zero(),
+                address: contract_address,
+                receive_name: OwnedReceiveName::new_unchecked(
+                    "ccd_redeem.viewMessageHash".to_string(),
+                ),
+                message: OwnedParameter::from_serial(&param)
+                    .expect("Parameters should be serialized successfully"),
+            },
+        )
+        .expect("Contract view call succeeds");
+    let message_hash = from_bytes::<[u8; 32]>(res.return_value.as_slice())
+        .expect("Data deserialized successfully");
+
+    let signature = signer_key().sign(&message_hash);
+    param.signature.sigs.insert(
+        0,
+        CredentialSignatures {
+            sigs: BTreeMap::from([(
+                0,
+                Signature::Ed25519(SignatureEd25519(signature.to_bytes())),
+            )]),
+        },
+    );
+
+    chain.contract_update(
+        Signer::with_one_key(),
+        ACCOUNT_0,
+        Address::Account(ACCOUNT_0),
+        Energy::from(10000),
+        UpdateContractPayload {
+            amount: Amount::zero(),
+            address: contract_address,
+            receive_name: OwnedReceiveName::new_unchecked("ccd_redeem.permit".to_string()),
+            message: OwnedParameter::from_serial(&param)
+                .expect("Parameters should be serialized successfully"),
+        },
+    )
+}
+
+#[test]
+/// Test redeeming a coin issued in a Merkle batch through `permit`, with the
+/// transaction sent by a sponsor.
+fn test_permit_redeem_merkle() {
+    let mut chain = Chain::new();
+
+    let deployment = prepare(&mut chain);
+
+    // Give `ACCOUNT_1` a key, so it can sign the permit message.
+    chain.create_account(Account::new_with_keys(
+        ACCOUNT_1,
+        AccountBalance::new(ACC_INITIAL_BALANCE_1, Amount::zero(), Amount::zero())
+            .expect("Balance is valid"),
+        AccountAccessStructure::singleton(signer_key().public),
+    ));
+
+    let init_info = initialize(&mut chain, &deployment, Vec::new(), Amount::zero())
+        .expect("Initialization should always succeed");
+
+    let tree = merkle_tree();
+    let total = merkle_coins()
+        .iter()
+        .fold(Amount::zero(), |acc, x| acc + x.1);
+    issue_merkle(
+        &mut chain,
+        init_info.contract_address,
+        ACCOUNT_0,
+        &tree,
+        total,
+    )
+    .expect("Issuing a Merkle batch succeeds");
+
+    let payload = to_bytes(&RedeemMerkleParam {
+        public_key: PUBLIC_KEY,
+        signature: SIGNATURE,
+        account: ACCOUNT_1,
+        amount: AMOUNT,
+        root: tree.root(),
+        leaf_index: 4,
+        proof: tree.proof(4).expect("Proof exists"),
+    });
+
+    permit(
+        &mut chain,
+        init_info.contract_address,
+        "redeemMerkle",
+        payload.clone(),
+    )
+    .expect("Contract call succeeds");
+
+    // The sponsor pays the fee, so the account receives the full amount.
+    assert_eq!(
+        chain
+            .account_balance_available(ACCOUNT_1)
+            .expect("Account exists"),
+        ACC_INITIAL_BALANCE_1 + AMOUNT,
+        "The coin amount is expected to be transferred to the account"
+    );
+    assert!(view_merkle_leaf(
+        &chain,
+        init_info.contract_address,
+        tree.root(),
+        4
+    ));
+
+    let err = permit(
+        &mut chain,
+        init_info.contract_address,
+        "redeemMerkle",
+        payload,
+    )
+    .expect_err("Redeeming the coin a second time is expected to fail");
+    let return_value = err.return_value().expect("The error is returned");
+    assert_eq!(
+        from_bytes::<Error>(return_value).expect("Error deserialized successfully"),
+        Error::CoinAlreadyRedeemed,
+        "The coin is expected to be already redeemed"
+    );
+}